@@ -0,0 +1,79 @@
+use gtk::prelude::*;
+use gtk::{glib, Entry, Grid, Label, MenuButton, Popover};
+use std::rc::Rc;
+use std::sync::Arc;
+use crate::config::GenParams;
+use crate::context::Context;
+use tracing::debug;
+
+fn parse<T: std::str::FromStr>(e: &Entry) -> Option<T> {
+    let t = e.text();
+    let t = t.trim();
+    if t.is_empty() { None } else { t.parse().ok() }
+}
+
+// "Advanced" popover overriding the sampling options from the config,
+// empty fields keep the configured values
+pub fn advanced_button(ctx: Arc<Context>) -> MenuButton {
+    let grid = Grid::builder()
+        .row_spacing(5)
+        .column_spacing(5)
+        .build();
+
+    let fields = ["Temperature", "Top p", "Max tokens", "Stop (comma separated)", "Seed", "Context (Ollama)"]
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let label = Label::builder()
+                .label(*name)
+                .xalign(0.0)
+                .build();
+            let entry = Entry::builder()
+                .placeholder_text("default")
+                .width_chars(10)
+                .build();
+            grid.attach(&label, 0, i as i32, 1, 1);
+            grid.attach(&entry, 1, i as i32, 1, 1);
+            entry
+        })
+        .collect::<Vec<_>>();
+    let fields = Rc::new(fields);
+
+    let update = {
+        let fields = fields.clone();
+        move || {
+            let f = &fields;
+            let params = GenParams {
+                temperature: parse(&f[0]),
+                top_p: parse(&f[1]),
+                max_tokens: parse(&f[2]),
+                stop: f[3].text()
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
+                seed: parse(&f[4]),
+                num_ctx: parse(&f[5]),
+            };
+            debug!("Advanced params: {:?}", params);
+            let ctx = ctx.clone();
+            glib::spawn_future_local(async move {
+                *ctx.params.lock().await = params;
+            });
+        }
+    };
+    let update = Rc::new(update);
+    for e in fields.iter() {
+        let u = update.clone();
+        e.connect_changed(move |_| u());
+    }
+
+    let popover = Popover::builder()
+        .child(&grid)
+        .build();
+    MenuButton::builder()
+        .label("Advanced")
+        .popover(&popover)
+        .margin_start(5)
+        .build()
+}
@@ -0,0 +1,104 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use anyhow::{Result, anyhow};
+use futures::{Stream, StreamExt};
+use serde_json::Value;
+use crate::errors::HttpError;
+use tracing::debug;
+
+// Parsed `data:` events of an OpenAI compatible streaming response
+pub type EventStream = Pin<Box<dyn Stream<Item = Result<Value>> + Send>>;
+
+struct Sse<S> {
+    body: S,
+    // Raw bytes, a chunk can end in the middle of a character
+    buffer: Vec<u8>,
+    events: VecDeque<Value>,
+    done: bool,
+}
+
+impl<S> Sse<S> {
+    // Moves the complete lines of the buffer to the events
+    fn parse(&mut self) -> Result<()> {
+        while let Some(i) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line = String::from_utf8_lossy(&self.buffer[..i]).trim().to_string();
+            self.buffer.drain(..=i);
+            let data = match line.strip_prefix("data:") {
+                Some(d) => d.trim(),
+                None => continue,
+            };
+            if data == "[DONE]" {
+                self.done = true;
+                break;
+            }
+            self.events.push_back(serde_json::from_str(data)?);
+        }
+        Ok(())
+    }
+}
+
+// POST {url}/chat/completions with "stream": true
+pub async fn chat_stream(url: &str, key: &str, body: &Value) -> Result<EventStream> {
+    let url = format!("{}/chat/completions", url.trim_end_matches('/'));
+    debug!("POST {}", url);
+    let res = reqwest::Client::new()
+        .post(url)
+        .bearer_auth(key)
+        .json(body)
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        return Err(HttpError::from_response(res).await.into());
+    }
+
+    let state = Sse {
+        body: Box::pin(res.bytes_stream()),
+        buffer: vec![],
+        events: VecDeque::new(),
+        done: false,
+    };
+    let s = futures::stream::unfold(state, |mut st| async move {
+        loop {
+            if let Some(e) = st.events.pop_front() {
+                return Some((Ok(e), st));
+            }
+            if st.done {
+                return None;
+            }
+            match st.body.next().await {
+                Some(Ok(chunk)) => {
+                    st.buffer.extend_from_slice(&chunk);
+                    if let Err(e) = st.parse() {
+                        st.done = true;
+                        return Some((Err(e), st));
+                    }
+                }
+                Some(Err(e)) => {
+                    st.done = true;
+                    return Some((Err(e.into()), st));
+                }
+                None => st.done = true,
+            }
+        }
+    });
+    Ok(Box::pin(s))
+}
+
+// Non streaming request, returns the text of the first choice
+pub async fn complete(url: &str, key: &str, body: &Value) -> Result<String> {
+    let url = format!("{}/chat/completions", url.trim_end_matches('/'));
+    debug!("POST {}", url);
+    let res = reqwest::Client::new()
+        .post(url)
+        .bearer_auth(key)
+        .json(body)
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        return Err(HttpError::from_response(res).await.into());
+    }
+    let v: Value = res.json().await?;
+    v["choices"][0]["message"]["content"].as_str()
+        .map(|s| s.to_string())
+        .ok_or(anyhow!("No content in the response"))
+}
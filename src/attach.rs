@@ -0,0 +1,258 @@
+use gtk::prelude::*;
+use gtk::{gdk, gio, glib, Box, Button, Image, Label, Orientation, Overlay, Picture, TextView};
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use crate::documents::{self, Document};
+use crate::errors::AppError;
+use crate::images;
+use crate::notify::Notifier;
+use tracing::debug;
+
+const THUMB: i32 = 64;
+
+// Attachments waiting for the next question, shown above the prompt
+#[derive(Clone)]
+pub struct AttachBar {
+    root: Box,
+    items: Box,
+    tokens: Label,
+    images: Rc<RefCell<Vec<PathBuf>>>,
+    documents: Rc<RefCell<Vec<Document>>>,
+    notifier: Notifier,
+}
+
+pub fn thumbnail(path: &PathBuf) -> Picture {
+    Picture::builder()
+        .file(&gio::File::for_path(path))
+        .content_fit(gtk::ContentFit::Cover)
+        .width_request(THUMB)
+        .height_request(THUMB)
+        .can_shrink(true)
+        .tooltip_text(path.display().to_string())
+        .build()
+}
+
+impl AttachBar {
+    pub fn new(notifier: Notifier) -> Self {
+        let items = Box::builder()
+            .orientation(Orientation::Horizontal)
+            .spacing(5)
+            .build();
+        let tokens = Label::builder()
+            .valign(gtk::Align::Center)
+            .margin_start(10)
+            .build();
+        let root = Box::builder()
+            .orientation(Orientation::Horizontal)
+            .margin_start(5)
+            .visible(false)
+            .build();
+        root.append(&items);
+        root.append(&tokens);
+        Self {
+            root,
+            items,
+            tokens,
+            images: Rc::new(RefCell::new(vec![])),
+            documents: Rc::new(RefCell::new(vec![])),
+            notifier,
+        }
+    }
+
+    pub fn widget(&self) -> &Box {
+        &self.root
+    }
+
+    // Shows the bar only with something attached, documents count towards the estimate
+    fn update(&self) {
+        let docs = self.documents.borrow();
+        let tokens = docs.iter().map(|d| d.tokens).sum::<usize>();
+        self.tokens.set_visible(!docs.is_empty());
+        self.tokens.set_label(format!("≈ {} tokens", tokens).as_str());
+        self.root.set_visible(!docs.is_empty() || !self.images.borrow().is_empty());
+    }
+
+    pub fn add_image(&self, path: PathBuf) {
+        debug!("Attaching {}", path.display());
+        let pic = thumbnail(&path);
+        let idc_remove = Button::builder()
+            .icon_name("window-close-symbolic")
+            .halign(gtk::Align::End)
+            .valign(gtk::Align::Start)
+            .build();
+        let item = Overlay::builder()
+            .child(&pic)
+            .build();
+        item.add_overlay(&idc_remove);
+
+        let bar = self.clone();
+        let p = path.clone();
+        idc_remove.connect_clicked(glib::clone!(
+            #[weak]
+            item,
+            move |_| {
+                bar.images.borrow_mut().retain(|i| *i != p);
+                bar.items.remove(&item);
+                bar.update();
+            }
+        ));
+
+        self.images.borrow_mut().push(path);
+        self.items.append(&item);
+        self.update();
+    }
+
+    pub fn add_document(&self, doc: Document) {
+        debug!("Attaching {} ({} tokens)", doc.name, doc.tokens);
+        let item = Box::builder()
+            .orientation(Orientation::Horizontal)
+            .spacing(3)
+            .valign(gtk::Align::Center)
+            .tooltip_text(doc.path.display().to_string())
+            .build();
+        item.append(&Image::from_icon_name("text-x-generic-symbolic"));
+        item.append(&Label::new(Some(format!("{} ({})", doc.name, doc.tokens).as_str())));
+        let idc_remove = Button::builder()
+            .icon_name("window-close-symbolic")
+            .has_frame(false)
+            .build();
+        item.append(&idc_remove);
+
+        let bar = self.clone();
+        let p = doc.path.clone();
+        idc_remove.connect_clicked(glib::clone!(
+            #[weak]
+            item,
+            move |_| {
+                bar.documents.borrow_mut().retain(|d| d.path != p);
+                bar.items.remove(&item);
+                bar.update();
+            }
+        ));
+
+        self.documents.borrow_mut().push(doc);
+        self.items.append(&item);
+        self.update();
+    }
+
+    // Images are shown as thumbnails, other files are read as documents
+    pub fn add_file(&self, path: &Path) {
+        if images::is_image(path) {
+            match images::store_file(path) {
+                Ok(p) => self.add_image(p),
+                Err(e) => self.notifier.report(AppError::Attachment(format!("{}: {:#}", path.display(), e))),
+            }
+            return;
+        }
+        // PDFs can take a while
+        let bar = self.clone();
+        let p = path.to_path_buf();
+        glib::spawn_future_local(async move {
+            let path = p.clone();
+            match tokio::task::spawn_blocking(move || documents::load(path.as_path())).await {
+                Ok(Ok(doc)) => bar.add_document(doc),
+                Ok(Err(e)) => bar.notifier.report(AppError::Attachment(format!("{}: {:#}", p.display(), e))),
+                Err(e) => bar.notifier.report(AppError::Attachment(e.to_string())),
+            }
+        });
+    }
+
+    // Takes the pending images and documents and clears the bar
+    pub fn take(&self) -> (Vec<PathBuf>, Vec<Document>) {
+        while let Some(c) = self.items.first_child() {
+            self.items.remove(&c);
+        }
+        let res = (self.images.take(), self.documents.take());
+        self.update();
+        res
+    }
+
+    // Image from the clipboard, if there is one
+    pub async fn paste(&self, clipboard: &gdk::Clipboard) {
+        if !clipboard.formats().contains_type(gdk::Texture::static_type()) {
+            return;
+        }
+        match clipboard.read_texture_future().await {
+            Ok(Some(t)) => match images::store_bytes(&t.save_to_png_bytes(), "png") {
+                Ok(p) => self.add_image(p),
+                Err(e) => self.notifier.report(AppError::Attachment(format!("Cannot store the pasted image: {:#}", e))),
+            },
+            Ok(None) => {}
+            Err(e) => self.notifier.report(AppError::Attachment(format!("Cannot read the clipboard image: {}", e))),
+        }
+    }
+
+    // Drag and drop of image files and paste from the clipboard on the prompt
+    pub fn connect_prompt(&self, text_view: &TextView) {
+        let drop = gtk::DropTarget::new(gdk::FileList::static_type(), gdk::DragAction::COPY);
+        let bar = self.clone();
+        drop.connect_drop(move |_, value, _, _| {
+            match value.get::<gdk::FileList>() {
+                Ok(files) => {
+                    for f in files.files() {
+                        if let Some(p) = f.path() {
+                            bar.add_file(p.as_path());
+                        }
+                    }
+                    true
+                }
+                Err(_) => false,
+            }
+        });
+        text_view.add_controller(drop);
+
+        let bar = self.clone();
+        text_view.connect_paste_clipboard(move |tv| {
+            let bar = bar.clone();
+            let clipboard = tv.clipboard();
+            glib::spawn_future_local(async move {
+                bar.paste(&clipboard).await;
+            });
+        });
+    }
+
+    // Button opening a file chooser
+    pub fn button(&self) -> Button {
+        let idc_attach = Button::builder()
+            .icon_name("mail-attachment-symbolic")
+            .tooltip_text("Attach images or documents")
+            .margin_start(5)
+            .build();
+        let bar = self.clone();
+        idc_attach.connect_clicked(move |b| {
+            let bar = bar.clone();
+            let window = b.root().and_downcast::<gtk::Window>();
+            glib::spawn_future_local(async move {
+                let all = gtk::FileFilter::new();
+                all.set_name(Some("All files"));
+                all.add_pattern("*");
+                let filter = gtk::FileFilter::new();
+                filter.set_name(Some("Images"));
+                filter.add_pixbuf_formats();
+                let docs = gtk::FileFilter::new();
+                docs.set_name(Some("Documents"));
+                for p in ["*.pdf", "*.docx", "*.txt", "*.md"] {
+                    docs.add_pattern(p);
+                }
+                docs.add_mime_type("text/*");
+                let filters = gio::ListStore::new::<gtk::FileFilter>();
+                filters.append(&all);
+                filters.append(&filter);
+                filters.append(&docs);
+                let dialog = gtk::FileDialog::builder()
+                    .title("Attach files")
+                    .filters(&filters)
+                    .build();
+                if let Ok(files) = dialog.open_multiple_future(window.as_ref()).await {
+                    for f in files.iter::<gio::File>().flatten() {
+                        if let Some(p) = f.path() {
+                            bar.add_file(p.as_path());
+                        }
+                    }
+                }
+            });
+        });
+        idc_attach
+    }
+}
@@ -0,0 +1,67 @@
+use gtk::prelude::*;
+use gtk::{Align, Box, Button, Label, Orientation};
+
+// Shown above the prompt when a question could not be answered
+#[derive(Clone)]
+pub struct ErrorBanner {
+    root: Box,
+    label: Label,
+    retry: Button,
+}
+
+impl ErrorBanner {
+    pub fn new() -> Self {
+        let label = Label::builder()
+            .halign(Align::Start)
+            .hexpand(true)
+            .wrap(true)
+            .selectable(true)
+            .css_classes(["error"])
+            .build();
+        let retry = Button::builder()
+            .label("Retry")
+            .valign(Align::Center)
+            .build();
+        let idc_close = Button::builder()
+            .icon_name("window-close-symbolic")
+            .has_frame(false)
+            .valign(Align::Center)
+            .build();
+        let root = Box::builder()
+            .orientation(Orientation::Horizontal)
+            .spacing(5)
+            .margin_start(5)
+            .margin_end(5)
+            .visible(false)
+            .build();
+        root.append(&label);
+        root.append(&retry);
+        root.append(&idc_close);
+        let r = root.clone();
+        idc_close.connect_clicked(move |_| r.set_visible(false));
+        Self { root, label, retry }
+    }
+
+    pub fn widget(&self) -> &Box {
+        &self.root
+    }
+
+    // `detail` goes to the tooltip, the label keeps to the hint
+    pub fn show(&self, text: &str, detail: &str) {
+        self.label.set_text(text);
+        self.label.set_tooltip_text(Some(detail));
+        self.root.set_visible(true);
+    }
+
+    pub fn hide(&self) {
+        self.root.set_visible(false);
+    }
+
+    pub fn connect_retry<F: Fn() + 'static>(&self, f: F) {
+        let r = self.root.clone();
+        self.retry.connect_clicked(move |_| {
+            r.set_visible(false);
+            f();
+        });
+    }
+}
@@ -0,0 +1,221 @@
+use anyhow::{Result, anyhow};
+
+// Parentheses, signs, powers and function calls nested deeper than this are refused,
+// the expression comes from the model and must not overflow the stack
+const MAX_DEPTH: usize = 100;
+
+// Arithmetic for the calculator tool: + - * / % ^, parentheses,
+// pi, e and the usual functions
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    // Every recursion goes through here
+    fn nested(&mut self, f: impl FnOnce(&mut Self) -> Result<f64>) -> Result<f64> {
+        if self.depth >= MAX_DEPTH {
+            return Err(anyhow!("Nested more than {} levels deep", MAX_DEPTH));
+        }
+        self.depth += 1;
+        let v = f(self);
+        self.depth -= 1;
+        v
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.get(self.pos).map(|c| c.is_whitespace()).unwrap_or(false) {
+            self.pos += 1;
+        }
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expr(&mut self) -> Result<f64> {
+        let mut v = self.term()?;
+        loop {
+            if self.eat('+') {
+                v += self.term()?;
+            } else if self.eat('-') {
+                v -= self.term()?;
+            } else {
+                return Ok(v);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<f64> {
+        let mut v = self.unary()?;
+        loop {
+            if self.eat('*') {
+                v *= self.unary()?;
+            } else if self.eat('/') {
+                v /= nonzero(self.unary()?)?;
+            } else if self.eat('%') {
+                v %= nonzero(self.unary()?)?;
+            } else {
+                return Ok(v);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<f64> {
+        if self.eat('-') {
+            return self.nested(|p| p.unary()).map(|v| -v);
+        }
+        if self.eat('+') {
+            return self.nested(|p| p.unary());
+        }
+        self.power()
+    }
+
+    // Right associative, binds tighter than unary minus on the left
+    fn power(&mut self) -> Result<f64> {
+        let base = self.atom()?;
+        if self.eat('^') {
+            return Ok(base.powf(self.nested(|p| p.unary())?));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<f64> {
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let v = self.nested(|p| p.expr())?;
+                if !self.eat(')') {
+                    return Err(anyhow!("Missing ')'"));
+                }
+                Ok(v)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let start = self.pos;
+                while self.chars.get(self.pos).map(|c| c.is_ascii_digit() || *c == '.').unwrap_or(false) {
+                    self.pos += 1;
+                }
+                // Exponent, e.g. 1e-3
+                if self.chars.get(self.pos).map(|c| *c == 'e' || *c == 'E').unwrap_or(false)
+                    && self.chars.get(self.pos + 1).map(|c| c.is_ascii_digit() || *c == '-' || *c == '+').unwrap_or(false) {
+                    self.pos += 2;
+                    while self.chars.get(self.pos).map(|c| c.is_ascii_digit()).unwrap_or(false) {
+                        self.pos += 1;
+                    }
+                }
+                let s = self.chars[start..self.pos].iter().collect::<String>();
+                s.parse().map_err(|_| anyhow!("Invalid number: {}", s))
+            }
+            Some(c) if c.is_alphabetic() => {
+                let start = self.pos;
+                while self.chars.get(self.pos).map(|c| c.is_alphanumeric()).unwrap_or(false) {
+                    self.pos += 1;
+                }
+                let name = self.chars[start..self.pos].iter().collect::<String>().to_lowercase();
+                match name.as_str() {
+                    "pi" => return Ok(std::f64::consts::PI),
+                    "e" => return Ok(std::f64::consts::E),
+                    _ => {}
+                }
+                let arg = self.nested(|p| p.atom())?;
+                Ok(match name.as_str() {
+                    "sqrt" => arg.sqrt(),
+                    "abs" => arg.abs(),
+                    "ln" => arg.ln(),
+                    "log" => arg.log10(),
+                    "exp" => arg.exp(),
+                    "sin" => arg.sin(),
+                    "cos" => arg.cos(),
+                    "tan" => arg.tan(),
+                    "asin" => arg.asin(),
+                    "acos" => arg.acos(),
+                    "atan" => arg.atan(),
+                    "round" => arg.round(),
+                    "floor" => arg.floor(),
+                    "ceil" => arg.ceil(),
+                    _ => return Err(anyhow!("Unknown function: {}", name)),
+                })
+            }
+            Some(c) => Err(anyhow!("Unexpected '{}'", c)),
+            None => Err(anyhow!("Unexpected end of the expression")),
+        }
+    }
+}
+
+fn nonzero(v: f64) -> Result<f64> {
+    if v == 0.0 {
+        return Err(anyhow!("Division by zero"));
+    }
+    Ok(v)
+}
+
+pub fn eval(expr: &str) -> Result<f64> {
+    let mut p = Parser { chars: expr.chars().collect(), pos: 0, depth: 0 };
+    let v = p.expr()?;
+    match p.peek() {
+        None => Ok(v),
+        Some(c) => Err(anyhow!("Unexpected '{}'", c)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::eval;
+
+    fn close(expr: &str, expected: f64) {
+        let v = eval(expr).unwrap();
+        assert!((v - expected).abs() < 1e-9, "{} = {}, expected {}", expr, v, expected);
+    }
+
+    #[test]
+    fn precedence() {
+        close("1 + 2 * 3", 7.0);
+        close("(1 + 2) * 3", 9.0);
+        close("10 - 4 - 3", 3.0);
+        close("2 ^ 3 ^ 2", 512.0);
+        close("7 % 4 * 2", 6.0);
+        close("sqrt 16 + 1", 5.0);
+        close("2 * pi", 2.0 * std::f64::consts::PI);
+        close("1e-3 * 1000", 1.0);
+    }
+
+    #[test]
+    fn unary_minus() {
+        close("-3 + 5", 2.0);
+        close("--2", 2.0);
+        close("2 * -3", -6.0);
+        close("-2 ^ 2", -4.0);
+        close("2 ^ -1", 0.5);
+        close("-(1 + 1)", -2.0);
+    }
+
+    #[test]
+    fn division_by_zero() {
+        assert!(eval("1 / 0").is_err());
+        assert!(eval("5 % (2 - 2)").is_err());
+        close("0 / 5", 0.0);
+    }
+
+    #[test]
+    fn bad_input() {
+        for expr in ["", "1 +", "(1 + 2", "1 + 2)", "2 $ 3", "foo 3", "1..2", "3 4"] {
+            assert!(eval(expr).is_err(), "{} should fail", expr);
+        }
+    }
+
+    #[test]
+    fn nesting_limit() {
+        let deep = format!("{}1{}", "(".repeat(100_000), ")".repeat(100_000));
+        assert!(eval(deep.as_str()).is_err());
+        assert!(eval("-".repeat(100_000).as_str()).is_err());
+        assert!(eval("sqrt ".repeat(100_000).as_str()).is_err());
+        close(format!("{}1{}", "(".repeat(20), ")".repeat(20)).as_str(), 1.0);
+    }
+}
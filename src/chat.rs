@@ -1,114 +1,403 @@
+use std::pin::Pin;
 use std::sync::Arc;
-use anyhow::Result;
-use gtk::{prelude::TextBufferExt, glib};
-use openai::{
-    chat::{ChatCompletion, ChatCompletionMessage, ChatCompletionMessageRole}, Credentials
-};
-use tokio::sync::mpsc::error::TryRecvError;
+use anyhow::{Result, anyhow};
+use gtk::prelude::TextBufferExt;
+use serde_json::{json, Value};
 use crate::context::Context;
+use crate::conversation::{Message, Role, Summary};
+use crate::{api, errors, history, images, rag, tools};
+use crate::tools::{ToolSpec, ToolUse};
+use crate::config::GenParams;
+use crate::errors::AppError;
+use crate::documents::estimate_tokens;
+use crate::history::Strategy;
 use tracing::{info, debug, error};
-use ollama_rs::generation::completion::request::GenerationRequest;
-use tokio_stream::StreamExt;
-use crate::helper::convert_text;
+use ollama_rs::generation::chat::{ChatMessage, request::ChatMessageRequest};
+use ollama_rs::generation::images::Image;
+use ollama_rs::generation::tools::{ToolCall, ToolCallFunction};
+use ollama_rs::models::ModelOptions;
+use tokio_stream::{Stream, StreamExt};
 use async_channel::Sender;
-//use std::io::Write;
-
-pub async fn ask_chat(ctx: Arc<Context>, sx: Sender<String>) -> Result<()> {
-    let ai_chat = ctx.ai_chat.lock().await;
-    let aic = ai_chat.clone();
-    let wait = ctx.conf.chat_msg_wait;
-    drop(ai_chat);
-    let ai = match aic {
-        Some(crate::AiChat::Grok) | Some(crate::AiChat::ChatGPT) | Some(crate::AiChat::Deepseek) => aic.unwrap(),
-        _ => {
-            error!("Invalid AI configuration");
-            return Err(anyhow::anyhow!("Invalid AI configuration"))
+
+// Spoken text is sent once a sentence ends and enough of it is gathered
+fn sentence_end(chunk: &str, gathered: usize) -> bool {
+    (chunk.contains('.') || chunk.contains('。')) && gathered > 10
+}
+
+// Asks the selected chat, the prompt is the last user message in the conversation.
+// When the request fails the fallback chats are asked, the last error is shown in the banner
+pub async fn ask(ctx: Arc<Context>, sx: Sender<String>) {
+    let selected = match *ctx.ai_chat.lock().await {
+        Some(a) => a,
+        None => {
+            error!("No chat selected");
+            return;
         }
     };
+    ctx.ui.lock().await.banner.hide();
+    let available = ctx.conf.providers();
+    let mut chain = vec![selected];
+    for f in ctx.conf.retry.fallback() {
+        if !chain.contains(&f) && available.contains(&f) {
+            chain.push(f);
+        }
+    }
+    let mut failures = vec![];
+    let mut last = None;
+    for ai in chain {
+        if last.is_some() {
+            info!("Falling back to {}", ai);
+        }
+        let res = match ai {
+            crate::AiChat::Ollama => ask_ollama(ctx.clone(), sx.clone()).await,
+            _ => ask_chat(ctx.clone(), ai, sx.clone()).await,
+        };
+        match res {
+            Ok(_) => return,
+            Err(e) => {
+                let kind = errors::classify(&e);
+                error!("{} failed ({}): {:#}", ai, kind, e);
+                failures.push(format!("{}: {:#}", ai, e));
+                last = Some((ai, kind));
+            }
+        }
+    }
+    if let Some((ai, kind)) = last {
+        ctx.ui.lock().await.banner.show(format!("{} failed. {}", ai, kind.hint()).as_str(), failures.join("\n").as_str());
+    }
+}
 
-    let ai_conf = match ai {
-        crate::AiChat::Grok => ctx.conf.grok.clone().unwrap(),
-        crate::AiChat::ChatGPT => ctx.conf.gpt.clone().unwrap(),
-        crate::AiChat::Deepseek => ctx.conf.deepseek.clone().unwrap(),
+// Assistant message with the calls of one round, followed by the results
+fn openai_tool_round(uses: &[ToolUse]) -> Vec<Value> {
+    let calls = uses.iter()
+        .map(|u| json!({
+            "id": u.id,
+            "type": "function",
+            "function": { "name": u.name, "arguments": u.arguments.to_string() },
+        }))
+        .collect::<Vec<_>>();
+    let mut res = vec![json!({ "role": "assistant", "content": null, "tool_calls": calls })];
+    res.extend(uses.iter().map(|u| json!({ "role": "tool", "tool_call_id": u.id, "content": u.result })));
+    res
+}
+
+// Tool rounds of an answer go before its text
+fn openai_messages(m: &Message) -> Vec<Value> {
+    let mut res = m.tools.chunk_by(|a, b| a.round == b.round)
+        .flat_map(openai_tool_round)
+        .collect::<Vec<_>>();
+    res.push(openai_message(m));
+    res
+}
+
+// User messages with images use content parts
+fn openai_message(m: &Message) -> Value {
+    let role = match m.role {
+        Role::User => "user",
+        Role::Assistant => "assistant",
+    };
+    if m.images.is_empty() {
+        return json!({ "role": role, "content": m.content() });
+    }
+    let mut parts = vec![json!({ "type": "text", "text": m.content() })];
+    for p in m.images.iter() {
+        match images::data_url(p) {
+            Ok(url) => parts.push(json!({ "type": "image_url", "image_url": { "url": url } })),
+            Err(e) => error!("Cannot read image {}: {}", p.display(), e.to_string()),
+        }
+    }
+    json!({ "role": role, "content": parts })
+}
+
+fn ollama_tool_round(uses: &[ToolUse]) -> Vec<ChatMessage> {
+    let mut call = ChatMessage::assistant(String::new());
+    call.tool_calls = uses.iter()
+        .map(|u| ToolCall { function: ToolCallFunction { name: u.name.clone(), arguments: u.arguments.clone() } })
+        .collect();
+    let mut res = vec![call];
+    res.extend(uses.iter().map(|u| ChatMessage::tool(u.result.clone())));
+    res
+}
+
+fn ollama_messages(m: &Message) -> Vec<ChatMessage> {
+    let mut res = m.tools.chunk_by(|a, b| a.round == b.round)
+        .flat_map(ollama_tool_round)
+        .collect::<Vec<_>>();
+    res.push(ollama_message(m));
+    res
+}
+
+fn ollama_message(m: &Message) -> ChatMessage {
+    match m.role {
+        Role::User => {
+            let images = m.images.iter()
+                .filter_map(|p| images::base64(p).ok())
+                .map(Image::from_base64)
+                .collect::<Vec<_>>();
+            if images.is_empty() {
+                ChatMessage::user(m.content())
+            } else {
+                ChatMessage::user(m.content()).with_images(images)
+            }
+        }
+        Role::Assistant => ChatMessage::assistant(m.text.clone()),
+    }
+}
+
+// Request history after fitting it into the context of the model
+struct Fitted {
+    summary: Option<String>,
+    messages: Vec<Message>,
+    used: usize,
+    limit: usize,
+    dropped: usize,
+}
+
+// Single non streaming answer, used for the summaries
+async fn complete(ctx: &Context, ai: crate::AiChat, model: &str, prompt: String) -> Result<String> {
+    match ai {
+        crate::AiChat::Ollama => {
+            let ollama = ollama_rs::Ollama::new(ctx.conf.ollama_url.as_str(), ctx.conf.ollama_port);
+            let request = ChatMessageRequest::new(model.to_string(), vec![ChatMessage::user(prompt)]);
+            Ok(ollama.send_chat_messages(request).await?.message.content)
+        }
         _ => {
-            error!("Invalid AI configuration, it shouldn'e even be here");
-            return Err(anyhow::anyhow!("Invalid AI configuration!!!"))
+            let api = ctx.conf.api(ai).ok_or(anyhow!("{} is not configured", ai))?;
+            let body = json!({
+                "model": model,
+                "messages": [{ "role": "user", "content": prompt }],
+            });
+            api::complete(api.url.as_str(), api.key.as_str(), &body).await
         }
+    }
+}
+
+// Summary of the dropped messages, the stored one is extended when more messages drop out
+async fn summary_of(ctx: &Context, ai: crate::AiChat, model: &str, dropped: &[Message]) -> Option<String> {
+    let covers = dropped.iter().map(|m| m.id).collect::<Vec<_>>();
+    if covers.is_empty() {
+        return None;
+    }
+    let prev = ctx.conv.lock().await.summary.clone();
+    if let Some(s) = prev.as_ref().filter(|s| s.covers == covers) {
+        return Some(s.text.clone());
+    }
+    // Extended only when it covers the start of these very messages
+    let (earlier, msgs) = match prev.filter(|s| !s.covers.is_empty() && covers.starts_with(s.covers.as_slice())) {
+        Some(s) => (
+            format!("Summary of the messages before:\n{}\n\n", s.text),
+            dropped[s.covers.len()..].to_vec(),
+        ),
+        None => (String::new(), dropped.to_vec()),
     };
+    let prompt = format!("Summarize the conversation below in one short paragraph. \
+        Keep names, facts, numbers and decisions, the summary replaces the messages.\n\n{}{}",
+        earlier, history::transcript(&msgs));
+    let model = ctx.conf.context.summary_model.clone().unwrap_or(model.to_string());
+    info!("Summarizing {} messages with {}", msgs.len(), model);
+    match complete(ctx, ai, model.as_str(), prompt).await {
+        Ok(text) => {
+            ctx.conv.lock().await.summary = Some(Summary { covers, text: text.clone() });
+            ctx.save().await;
+            Some(text)
+        }
+        Err(e) => {
+            error!("Cannot summarize the conversation: {}", e.to_string());
+            None
+        }
+    }
+}
+
+// Drops or summarizes the oldest messages so the request fits the context
+// `fixed` are the tokens of the system messages
+async fn fit_history(ctx: &Context, ai: crate::AiChat, model: &str, params: &GenParams, fixed: usize) -> Fitted {
+    let cc = &ctx.conf.context;
+    let num_ctx = if ai == crate::AiChat::Ollama { params.num_ctx } else { None };
+    let limit = cc.limit_for(model, num_ctx);
+    let reserve = params.max_tokens.map(|m| m as usize).unwrap_or(cc.reserve);
+    let budget = limit.saturating_sub(reserve + fixed);
+    let all = ctx.conv.lock().await.history().cloned().collect::<Vec<_>>();
+
+    let strategy = cc.strategy();
+    let mut w = history::select(&all, budget, strategy, cc.pin);
+    let mut summary = None;
+    if strategy == Strategy::Summarize && !w.dropped.is_empty() {
+        w = history::select(&all, budget.saturating_sub(cc.summary_tokens), strategy, cc.pin);
+        summary = summary_of(ctx, ai, model, &w.dropped).await;
+    }
+    if !w.dropped.is_empty() {
+        info!("{} of {} messages don't fit into {} tokens", w.dropped.len(), all.len(), limit);
+    }
+    let used = w.tokens + fixed + summary.as_deref().map(estimate_tokens).unwrap_or(0);
+    let f = Fitted { summary, messages: w.keep, used, limit, dropped: w.dropped.len() };
+    show_usage(ctx, &f, "").await;
+    f
+}
+
+async fn show_usage(ctx: &Context, f: &Fitted, answer: &str) {
+    ctx.ui.lock().await.meter.set(f.used + estimate_tokens(answer), f.limit, f.dropped, f.summary.is_some());
+}
+
+// Tool call streamed in parts, the arguments come as JSON text fragments
+#[derive(Default)]
+struct PendingCall {
+    id: String,
+    name: String,
+    arguments: String,
+}
+
+fn collect_tool_calls(calls: &mut Vec<PendingCall>, delta: &Value) {
+    for c in delta["tool_calls"].as_array().into_iter().flatten() {
+        let i = c["index"].as_u64().unwrap_or(0) as usize;
+        while calls.len() <= i {
+            calls.push(PendingCall::default());
+        }
+        let p = &mut calls[i];
+        if let Some(id) = c["id"].as_str().filter(|id| !id.is_empty()) {
+            p.id = id.to_string();
+        }
+        if let Some(n) = c["function"]["name"].as_str().filter(|_| p.name.is_empty()) {
+            p.name = n.to_string();
+        }
+        if let Some(a) = c["function"]["arguments"].as_str() {
+            p.arguments.push_str(a);
+        }
+    }
+}
+
+// Runs the calls of one round and shows them in the answer's bubble
+async fn run_tools(ctx: &Context, specs: &[ToolSpec], id: u64, round: usize, calls: Vec<(String, String, Value)>) -> Vec<ToolUse> {
+    let mut uses = vec![];
+    for (i, (call_id, name, arguments)) in calls.into_iter().enumerate() {
+        let mut u = ToolUse {
+            id: if call_id.is_empty() { format!("call_{}_{}", round, i) } else { call_id },
+            name,
+            arguments,
+            result: String::new(),
+            round,
+        };
+        // The row is shown while the tool runs, output can be streamed into it
+        ctx.add_tool_use(id, u.clone()).await;
+        u.result = tools::call(ctx, specs, id, &u).await;
+        ctx.set_tool_result(id, u.id.as_str(), u.result.as_str()).await;
+        uses.push(u);
+    }
+    uses
+}
+
+// Errors of the request are returned, the ones after the answer started are logged
+pub async fn ask_chat(ctx: Arc<Context>, ai: crate::AiChat, sx: Sender<String>) -> Result<()> {
+    let ai_conf = ctx.conf.api(ai).cloned().ok_or(anyhow!("{} is not configured", ai))?;
 
     info!("Config AI: {}", ai);
     let url = ai_conf.url;
     info!("Config URL: {}", url);
     let api_key = ai_conf.key;
-    let model = ai_conf.model;
-    let text_buffer = ctx.text_buffer().await;
+    let persona = ctx.persona().await;
+    let model = ctx.model_for(ai, ai_conf.model.as_str()).await;
 
-    let prompt = crate::get_text!(text_buffer).to_string();
+    let params = ctx.gen_params(&ai_conf.params).await;
+    debug!("Params: {:?}", params);
+    let system = persona.map(|p| p.system_prompt).unwrap_or_default();
+    let retrieved = rag::retrieve(&ctx).await;
+    let excerpts = retrieved.as_ref().map(|r| r.prompt.clone()).unwrap_or_default();
+    let fixed = estimate_tokens(system.as_str()) + estimate_tokens(excerpts.as_str());
+    let fitted = fit_history(&ctx, ai, model.as_str(), &params, fixed).await;
 
-    debug!("Prompt: {}", prompt);
-    let c = Credentials::new(api_key, url);
-
-    let messages = vec![ChatCompletionMessage {
-        role: ChatCompletionMessageRole::User,
-        content: Some(prompt),
-        name: None,
-        function_call: None,
-        tool_calls: None,
-        tool_call_id: None,
-    }];
+    let mut messages = vec![];
+    if !system.is_empty() {
+        messages.push(json!({ "role": "system", "content": system }));
+    }
+    if !excerpts.is_empty() {
+        messages.push(json!({ "role": "system", "content": excerpts }));
+    }
+    if let Some(s) = &fitted.summary {
+        messages.push(json!({ "role": "system", "content": format!("Summary of the earlier conversation:\n{}", s) }));
+    }
+    messages.extend(fitted.messages.iter().flat_map(openai_messages));
 
     debug!("Created messages");
-    let mut cc = ChatCompletion::builder(model.as_str(), messages.clone())
-        .credentials(c.clone())
-        .stream(true)
-        .create_stream()
-        .await?;
+    let mut body = openai_body(model.as_str(), messages, &params);
+    let specs = tools::registry(&ctx).await;
+    if !specs.is_empty() {
+        body["tools"] = tools::openai_tools(&specs);
+    }
+    let mut cc = errors::retry(&ctx.conf.retry, ai.as_str(), || api::chat_stream(url.as_str(), api_key.as_str(), &body)).await?;
 
     debug!("Completions ready");
 
-    let mut d = true;
-    let dur = std::time::Duration::from_millis(wait);
-    let result_buffer = ctx.result_buffer().await;
-    let mut end_iter = result_buffer.end_iter();
+    let (id, result_buffer) = ctx.push_message(Role::Assistant, model.as_str(), "").await;
+    ctx.set_provider(id, ai).await;
+    if let Some(r) = retrieved {
+        ctx.set_sources(id, r.sources).await;
+    }
     let mut vc = vec![];
-    let play = ctx.with_sound.lock().await;
-
-    while d {
-        let r = cc.try_recv();
-        match r {
-            Ok(r) => {
-                let choice = &r.choices[0];
-                if let Some(content) = &choice.delta.content {
-                    debug!("Received content: {}", content);
-                    result_buffer.insert(&mut end_iter, content.as_str());
-                    let has_dot = content.contains(".") || content.contains("。");
-                    if *play {
-                        vc.push(content.clone());
-                        if has_dot && vc.len() > 10 {
-                            match sx.send(vc.join(" ")).await {
-                                Ok(_) => vc.clear(),
-                                Err(e) => error!("Error sending: {}", e.to_string()),
+    // Copied so that Play can be switched while the answer streams
+    let play = *ctx.with_sound.lock().await;
+
+    // The tool results are sent back until the model answers without calls
+    let mut round = 0;
+    let mut broken = false;
+    loop {
+        let mut calls = vec![];
+        while let Some(r) = cc.next().await {
+            match r {
+                Ok(r) => {
+                    let delta = &r["choices"][0]["delta"];
+                    collect_tool_calls(&mut calls, delta);
+                    if let Some(content) = delta["content"].as_str() {
+                        debug!("Received content: {}", content);
+                        let mut end_iter = result_buffer.end_iter();
+                        result_buffer.insert(&mut end_iter, content);
+                        if play {
+                            vc.push(content.to_string());
+                            if sentence_end(content, vc.len()) {
+                                match sx.send(vc.join(" ")).await {
+                                    Ok(_) => vc.clear(),
+                                    Err(e) => error!("Error sending: {}", e.to_string()),
+                                }
                             }
                         }
+                    } else if delta["tool_calls"].is_null() {
+                        debug!("I don't know what to do with it");
                     }
-                    
-                } else {
-                    debug!("I don't know what to do with it");
+                }
+                Err(e) => {
+                    if round == 0 && result_buffer.char_count() == 0 {
+                        // Nothing is shown yet, the fallback chats can answer instead
+                        ctx.remove_message(id).await;
+                        return Err(e);
+                    }
+                    // Part of the answer is already shown, the rest is lost
+                    ctx.notifier.report(AppError::request(&e));
+                    broken = true;
+                    break;
                 }
             }
-            Err(TryRecvError::Empty) => {
-                debug!("Empty stream");
-                glib::timeout_future(dur).await;
-                debug!("Empty stream: awake");
-            }
-            Err(TryRecvError::Disconnected) => {
-                debug!("** DC **");
-                vc.push(String::from("~END~"));
-                d = false;
-            }
         }
+        if broken || calls.is_empty() {
+            break;
+        }
+        if round >= ctx.conf.tools.max_rounds {
+            error!("No answer after {} tool call rounds", round);
+            break;
+        }
+        let calls = calls.into_iter()
+            .map(|c| (c.id, c.name, serde_json::from_str(c.arguments.as_str()).unwrap_or(json!({}))))
+            .collect();
+        let uses = run_tools(&ctx, &specs, id, round, calls).await;
+        if let Some(m) = body["messages"].as_array_mut() {
+            m.extend(openai_tool_round(&uses));
+        }
+        round += 1;
+        cc = match errors::retry(&ctx.conf.retry, ai.as_str(), || api::chat_stream(url.as_str(), api_key.as_str(), &body)).await {
+            Ok(s) => s,
+            Err(e) => {
+                ctx.notifier.report(AppError::request(&e));
+                break;
+            }
+        };
     }
-
     if vc.len() > 0 {
         match sx.send(vc.join(" ")).await {
             Ok(_) => vc.clear(),
@@ -119,54 +408,246 @@ pub async fn ask_chat(ctx: Arc<Context>, sx: Sender<String>) -> Result<()> {
 
     info!("Stream finished");
 
-    let result_buffer = result_buffer.clone();
-
     let text = crate::get_text!(result_buffer);
-    let c_text = convert_text(text.as_str());
-    result_buffer.set_text("");
-    let mut end_iter = result_buffer.end_iter();
-    result_buffer.insert_markup(&mut end_iter, c_text.as_str());
-    drop(cc);
+    ctx.finish_message(id, text.as_str()).await;
+    show_usage(&ctx, &fitted, text.as_str()).await;
     info!("Ending chat");
     Ok(())
 }
 
-pub async fn ask_ollama(app_state: Arc<Context>, sx: Sender<String>) {
-    let result_buffer = app_state.result_buffer().await;
-    let text_buffer = app_state.text_buffer().await;
-    let prompt = crate::get_text!(text_buffer);
-    let model = app_state.conf.ollama_model.clone();
+// ollama-rs ends its stream with a bare Err(()) when reading the response fails
+// and prints the cause itself, this is all that is known
+fn ollama_stream_error() -> anyhow::Error {
+    anyhow!("Reading the Ollama stream failed: connection closed before the answer was complete")
+}
+
+// Text parts of an answer
+pub type TextStream = Pin<Box<dyn Stream<Item = Result<String>> + Send>>;
+
+// Plain answer of `ai` to the prompt after the current history, without tools
+// or documents, returns the model and the stream. Used by the compare window
+pub async fn answer_stream(ctx: &Context, ai: crate::AiChat, prompt: &str) -> Result<(String, TextStream)> {
+    let (configured, base) = match ai {
+        crate::AiChat::Ollama => (ctx.conf.ollama_model.clone(), ctx.conf.ollama_params.clone()),
+        _ => {
+            let api = ctx.conf.api(ai).ok_or(anyhow!("{} is not configured", ai))?;
+            (api.model.clone(), api.params.clone())
+        }
+    };
+    let model = ctx.model_for(ai, configured.as_str()).await;
+    let params = ctx.gen_params(&base).await;
+    let system = ctx.persona().await.map(|p| p.system_prompt).unwrap_or_default();
+
+    let cc = &ctx.conf.context;
+    let num_ctx = if ai == crate::AiChat::Ollama { params.num_ctx } else { None };
+    let reserve = params.max_tokens.map(|m| m as usize).unwrap_or(cc.reserve);
+    let fixed = estimate_tokens(system.as_str()) + estimate_tokens(prompt);
+    let budget = cc.limit_for(model.as_str(), num_ctx).saturating_sub(reserve + fixed);
+    let all = ctx.conv.lock().await.history().cloned().collect::<Vec<_>>();
+    let history = history::select(&all, budget, Strategy::Sliding, 0).keep;
+
+    let stream: TextStream = match ai {
+        crate::AiChat::Ollama => {
+            let mut messages = vec![];
+            if !system.is_empty() {
+                messages.push(ChatMessage::system(system));
+            }
+            messages.extend(history.iter().map(ollama_message));
+            messages.push(ChatMessage::user(prompt.to_string()));
+            let request = ChatMessageRequest::new(model.clone(), messages)
+                .options(model_options(&params)?);
+            let ollama = ollama_rs::Ollama::new(ctx.conf.ollama_url.as_str(), ctx.conf.ollama_port);
+            Box::pin(ollama.send_chat_messages_stream(request).await?
+                .map(|r| r.map(|r| r.message.content).map_err(|_| ollama_stream_error())))
+        }
+        _ => {
+            let api = ctx.conf.api(ai).ok_or(anyhow!("{} is not configured", ai))?;
+            let mut messages = vec![];
+            if !system.is_empty() {
+                messages.push(json!({ "role": "system", "content": system }));
+            }
+            messages.extend(history.iter().map(openai_message));
+            messages.push(json!({ "role": "user", "content": prompt }));
+            let body = openai_body(model.as_str(), messages, &params);
+            Box::pin(api::chat_stream(api.url.as_str(), api.key.as_str(), &body).await?
+                .map(|r| r.map(|r| r["choices"][0]["delta"]["content"].as_str().unwrap_or("").to_string())))
+        }
+    };
+    Ok((model, stream))
+}
+
+// Streamed request of the OpenAI compatible chats, the counterpart of `model_options`
+fn openai_body(model: &str, messages: Vec<Value>, params: &GenParams) -> Value {
+    let mut body = json!({
+        "model": model,
+        "messages": messages,
+        "stream": true,
+    });
+    if let Some(t) = params.temperature {
+        body["temperature"] = json!(t);
+    }
+    if let Some(p) = params.top_p {
+        body["top_p"] = json!(p);
+    }
+    if let Some(m) = params.max_tokens {
+        body["max_tokens"] = json!(m);
+    }
+    if !params.stop.is_empty() {
+        body["stop"] = json!(params.stop);
+    }
+    if let Some(s) = params.seed {
+        body["seed"] = json!(s);
+    }
+    body
+}
+
+// Ollama takes 32 bit values, larger ones are refused rather than wrapped
+// into another seed or length
+fn model_options(params: &GenParams) -> Result<ModelOptions, AppError> {
+    let int = |name: &str, v: i64| i32::try_from(v)
+        .map_err(|_| AppError::Config(format!("{} = {} is too large for Ollama, the limit is {}", name, v, i32::MAX)));
+    let mut o = ModelOptions::default();
+    if let Some(t) = params.temperature {
+        o = o.temperature(t);
+    }
+    if let Some(p) = params.top_p {
+        o = o.top_p(p);
+    }
+    if let Some(m) = params.max_tokens {
+        o = o.num_predict(int("max_tokens", m as i64)?);
+    }
+    if !params.stop.is_empty() {
+        o = o.stop(params.stop.clone());
+    }
+    if let Some(s) = params.seed {
+        o = o.seed(int("seed", s)?);
+    }
+    if let Some(n) = params.num_ctx {
+        o = o.num_ctx(n);
+    }
+    Ok(o)
+}
+
+pub async fn ask_ollama(app_state: Arc<Context>, sx: Sender<String>) -> Result<()> {
+    let persona = app_state.persona().await;
+    let model = app_state.model_for(crate::AiChat::Ollama, app_state.conf.ollama_model.as_str()).await;
     let ollama = ollama_rs::Ollama::new(app_state.conf.ollama_url.as_str(), app_state.conf.ollama_port);
 
-    let request = GenerationRequest::new(model, prompt.as_str());
+    let params = app_state.gen_params(&app_state.conf.ollama_params).await;
+    debug!("Params: {:?}", params);
+    let options = model_options(&params)?;
+    let system = persona.map(|p| p.system_prompt).unwrap_or_default();
+    let retrieved = rag::retrieve(&app_state).await;
+    let excerpts = retrieved.as_ref().map(|r| r.prompt.clone()).unwrap_or_default();
+    let fixed = estimate_tokens(system.as_str()) + estimate_tokens(excerpts.as_str());
+    let fitted = fit_history(&app_state, crate::AiChat::Ollama, model.as_str(), &params, fixed).await;
 
-    let mut stream = ollama.generate_stream(request).await.unwrap();
-    let play = app_state.with_sound.lock().await;
+    let mut messages = vec![];
+    if !system.is_empty() {
+        messages.push(ChatMessage::system(system));
+    }
+    if !excerpts.is_empty() {
+        messages.push(ChatMessage::system(excerpts));
+    }
+    if let Some(s) = &fitted.summary {
+        messages.push(ChatMessage::system(format!("Summary of the earlier conversation:\n{}", s)));
+    }
+    messages.extend(fitted.messages.iter().flat_map(ollama_messages));
+    let mut specs = tools::registry(&app_state).await;
+    let request = |messages: Vec<ChatMessage>, specs: &[ToolSpec]| ChatMessageRequest::new(model.clone(), messages)
+        .options(options.clone())
+        .tools(tools::ollama_tools(specs));
+
+    let send = |messages: Vec<ChatMessage>, specs: &[ToolSpec]| {
+        let r = request(messages, specs);
+        let ollama = &ollama;
+        errors::retry(&app_state.conf.retry, "Ollama", move || {
+            let r = r.clone();
+            async move { Ok(ollama.send_chat_messages_stream(r).await?) }
+        })
+    };
+    let mut stream = match send(messages.clone(), &specs).await {
+        Ok(s) => s,
+        // Not every model can call tools, ask again without them
+        Err(e) if !specs.is_empty() && e.to_string().contains("does not support tools") => {
+            info!("{} does not support tools", model);
+            specs.clear();
+            send(messages.clone(), &specs).await?
+        }
+        Err(e) => {
+            if errors::classify(&e) == errors::ErrorKind::Network || crate::model_manager::is_pulled(&ollama, model.as_str()).await {
+                return Err(e);
+            }
+            // Offer pulling the missing model instead of failing
+            let manager = app_state.ui.lock().await.manager.clone();
+            if !manager.offer_pull(model.as_str()).await {
+                return Err(e);
+            }
+            send(messages.clone(), &specs).await?
+        }
+    };
+    let (id, result_buffer) = app_state.push_message(Role::Assistant, model.as_str(), "").await;
+    app_state.set_provider(id, crate::AiChat::Ollama).await;
+    if let Some(r) = retrieved {
+        app_state.set_sources(id, r.sources).await;
+    }
+    let play = *app_state.with_sound.lock().await;
     let mut vc = vec![];
 
-    while let Some(res) = stream.next().await {
-        match res {
-            Ok(responses) => {
-                let mut end_iter = result_buffer.end_iter();
-                if *play {
-                    for r in responses {
-                        let content = &r.response;
-                        let has_dot = content.contains(".") || content.contains("。");
-                        result_buffer.insert(&mut end_iter, content);
+    let mut round = 0;
+    let mut broken = false;
+    loop {
+        let mut calls = vec![];
+        while let Some(res) = stream.next().await {
+            match res {
+                Ok(r) => {
+                    calls.extend(r.message.tool_calls.into_iter()
+                        .map(|c| (String::new(), c.function.name, c.function.arguments)));
+                    let mut end_iter = result_buffer.end_iter();
+                    let content = &r.message.content;
+                    result_buffer.insert(&mut end_iter, content);
+                    if play {
                         vc.push(content.clone());
-                        if has_dot || vc.len() > 10 {
+                        if sentence_end(content, vc.len()) {
                             match sx.send(vc.join(" ")).await {
                                 Ok(_) => vc.clear(),
                                 Err(e) => error!("Error sending: {}", e.to_string()),
                             }
                         }
-
                     }
+                },
+                Err(_) => {
+                    let e = ollama_stream_error();
+                    if round == 0 && result_buffer.char_count() == 0 {
+                        // Nothing is shown yet, the fallback chats can answer instead
+                        app_state.remove_message(id).await;
+                        return Err(e);
+                    }
+                    // Part of the answer is already shown, the rest is lost
+                    app_state.notifier.report(AppError::request(&e));
+                    broken = true;
+                    break;
                 }
-            },
-            Err(e) => eprintln!("Error: {}", e),
+            }
+        }
+        if broken || calls.is_empty() {
+            break;
         }
-    } 
+        if round >= app_state.conf.tools.max_rounds {
+            error!("No answer after {} tool call rounds", round);
+            break;
+        }
+        let uses = run_tools(&app_state, &specs, id, round, calls).await;
+        messages.extend(ollama_tool_round(&uses));
+        round += 1;
+        stream = match send(messages.clone(), &specs).await {
+            Ok(s) => s,
+            Err(e) => {
+                app_state.notifier.report(AppError::request(&e));
+                break;
+            }
+        };
+    }
 
     info!("Stream finished"); 
 
@@ -177,13 +658,10 @@ pub async fn ask_ollama(app_state: Arc<Context>, sx: Sender<String>) {
         }
     }
 
-
     let text = crate::get_text!(result_buffer);
-    let c_text = convert_text(text.as_str());
-    result_buffer.set_text("");
-    let mut end_iter = result_buffer.end_iter();
-    result_buffer.insert_markup(&mut end_iter, c_text.as_str());
+    app_state.finish_message(id, text.as_str()).await;
+    show_usage(&app_state, &fitted, text.as_str()).await;
 
     info!("Ending chat");
-
+    Ok(())
 }
@@ -0,0 +1,236 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
+use gtk::prelude::*;
+use gtk::{glib, Align, Box, Button, CheckButton, Label, Orientation, ScrolledWindow, TextBuffer, TextView, Window};
+use tokio_stream::StreamExt;
+use crate::context::Context;
+use crate::conversation::Role;
+use crate::documents::estimate_tokens;
+use crate::{chat, AiChat};
+use tracing::{debug, error, info};
+
+// One answer of the compare window
+#[derive(Clone)]
+struct Column {
+    root: Box,
+    header: Label,
+    buffer: TextBuffer,
+    stats: Label,
+    keep: Button,
+}
+
+impl Column {
+    fn new(ai: AiChat) -> Self {
+        let header = Label::builder()
+            .halign(Align::Start)
+            .build();
+        header.set_markup(format!("<b>{}</b>", ai).as_str());
+        let view = TextView::builder()
+            .editable(false)
+            .cursor_visible(false)
+            .wrap_mode(gtk::WrapMode::Word)
+            .build();
+        let scroll = ScrolledWindow::builder()
+            .child(&view)
+            .vexpand(true)
+            .build();
+        let stats = Label::builder()
+            .label("waiting")
+            .halign(Align::Start)
+            .wrap(true)
+            .build();
+        let keep = Button::builder()
+            .label("Keep this answer")
+            .sensitive(false)
+            .build();
+        let root = Box::builder()
+            .orientation(Orientation::Vertical)
+            .spacing(5)
+            .hexpand(true)
+            .build();
+        root.append(&header);
+        root.append(&scroll);
+        root.append(&stats);
+        root.append(&keep);
+        Self { root, header, buffer: view.buffer(), stats, keep }
+    }
+}
+
+// Streams of the columns, stopped when an answer is kept, the chats are asked
+// again or the window is closed
+type Tasks = Rc<RefCell<Vec<glib::JoinHandle<()>>>>;
+
+fn stop(tasks: &Tasks) {
+    for t in tasks.borrow_mut().drain(..) {
+        t.abort();
+    }
+}
+
+// The question and the picked answer go to the conversation
+async fn keep(ctx: Arc<Context>, ai: AiChat, prompt: &str, model: &str, text: &str) {
+    info!("Keeping the answer of {}", model);
+    ctx.push_message(Role::User, "", prompt).await;
+    let (id, _) = ctx.push_message(Role::Assistant, model, "").await;
+    ctx.set_provider(id, ai).await;
+    ctx.finish_message(id, text).await;
+    ctx.ui.lock().await.clear_text();
+}
+
+// Streams the answer of `ai` into the column, the stats are shown at the end
+async fn run(ctx: Arc<Context>, window: Window, tasks: Tasks, ai: AiChat, prompt: String, col: Column) {
+    let start = Instant::now();
+    let (model, mut stream) = match chat::answer_stream(&ctx, ai, prompt.as_str()).await {
+        Ok(r) => r,
+        Err(e) => {
+            error!("Compare {} failed: {}", ai, e.to_string());
+            col.stats.set_text(format!("Error: {}", e).as_str());
+            return;
+        }
+    };
+    col.header.set_markup(format!("<b>{}</b>  <small>{}</small>", ai, glib::markup_escape_text(model.as_str())).as_str());
+    col.stats.set_text("answering");
+    let mut first = None;
+    let mut failed = None;
+    while let Some(r) = stream.next().await {
+        match r {
+            Ok(t) => {
+                if first.is_none() && !t.is_empty() {
+                    first = Some(start.elapsed());
+                }
+                let mut end_iter = col.buffer.end_iter();
+                col.buffer.insert(&mut end_iter, t.as_str());
+            }
+            Err(e) => {
+                error!("Compare {} stream error: {}", ai, e.to_string());
+                failed = Some(e.to_string());
+                break;
+            }
+        }
+    }
+    let total = start.elapsed().as_secs_f64();
+    let buffer = &col.buffer;
+    let text = crate::get_text!(buffer).to_string();
+    let tokens = estimate_tokens(text.as_str());
+    let mut stats = format!("First token {:.1} s, total {:.1} s, {} words, ≈ {} tokens, {:.0} tokens/s",
+        first.map(|f| f.as_secs_f64()).unwrap_or(total), total,
+        text.split_whitespace().count(), tokens, tokens as f64 / total.max(0.001));
+    if let Some(e) = failed {
+        stats.push_str(format!("\nStopped: {}", e).as_str());
+    }
+    debug!("{} {}: {}", ai, model, stats);
+    col.stats.set_text(stats.as_str());
+    if text.trim().is_empty() {
+        return;
+    }
+    col.keep.set_sensitive(true);
+    col.keep.connect_clicked(move |_| {
+        let ctx = ctx.clone();
+        let (prompt, model, text) = (prompt.clone(), model.clone(), text.clone());
+        stop(&tasks);
+        window.close();
+        glib::spawn_future_local(async move {
+            keep(ctx, ai, prompt.as_str(), model.as_str(), text.as_str()).await;
+        });
+    });
+}
+
+fn present(ctx: Arc<Context>, parent: Option<Window>, prompt: String) {
+    let question = Label::builder()
+        .label(prompt.as_str())
+        .halign(Align::Start)
+        .wrap(true)
+        .selectable(true)
+        .lines(4)
+        .ellipsize(gtk::pango::EllipsizeMode::End)
+        .build();
+    let chats = Box::builder()
+        .orientation(Orientation::Horizontal)
+        .spacing(5)
+        .build();
+    let checks = ctx.conf.providers().into_iter()
+        .map(|ai| {
+            let c = CheckButton::builder()
+                .label(ai.to_string())
+                .active(true)
+                .build();
+            chats.append(&c);
+            (ai, c)
+        })
+        .collect::<Vec<_>>();
+    let idc_send = Button::builder()
+        .label("Send")
+        .margin_start(10)
+        .build();
+    chats.append(&idc_send);
+    let columns = Box::builder()
+        .orientation(Orientation::Horizontal)
+        .homogeneous(true)
+        .spacing(10)
+        .vexpand(true)
+        .build();
+    let root = Box::builder()
+        .orientation(Orientation::Vertical)
+        .spacing(5)
+        .margin_top(5)
+        .margin_start(5)
+        .margin_end(5)
+        .margin_bottom(5)
+        .build();
+    root.append(&question);
+    root.append(&chats);
+    root.append(&columns);
+
+    let window = Window::builder()
+        .title("Compare answers")
+        .default_width(1100)
+        .default_height(650)
+        .child(&root)
+        .build();
+    window.set_transient_for(parent.as_ref());
+
+    let tasks: Tasks = Rc::new(RefCell::new(vec![]));
+    let t = tasks.clone();
+    window.connect_close_request(move |_| {
+        stop(&t);
+        glib::Propagation::Proceed
+    });
+    let w = window.clone();
+    idc_send.connect_clicked(move |_| {
+        stop(&tasks);
+        while let Some(c) = columns.first_child() {
+            columns.remove(&c);
+        }
+        // All chats are asked at once, each streams into its column
+        for (ai, c) in checks.iter().filter(|(_, c)| c.is_active()) {
+            let col = Column::new(*ai);
+            columns.append(&col.root);
+            let task = glib::spawn_future_local(run(ctx.clone(), w.clone(), tasks.clone(), *ai, prompt.clone(), col));
+            tasks.borrow_mut().push(task);
+        }
+    });
+    window.present();
+}
+
+// Opens the compare window for the text of the prompt
+pub fn compare_button(ctx: Arc<Context>) -> Button {
+    let b = Button::builder()
+        .label("Compare")
+        .tooltip_text("Send the prompt to several chats and keep the best answer")
+        .margin_start(5)
+        .build();
+    b.connect_clicked(move |b| {
+        let ctx = ctx.clone();
+        let parent = b.root().and_downcast::<Window>();
+        glib::spawn_future_local(async move {
+            let buffer = ctx.text_buffer().await;
+            let prompt = crate::get_text!(buffer).trim().to_string();
+            if prompt.is_empty() {
+                return;
+            }
+            present(ctx, parent, prompt);
+        });
+    });
+    b
+}
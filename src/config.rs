@@ -1,10 +1,263 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env::current_exe;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use toml_edit::{DocumentMut, Item, Table, Value};
+use crate::errors::AppError;
+
+// Old location, still read when there is no config in the XDG dir
+const CONF: &str = "app.toml";
+const APP_DIR: &str = "gchatter";
+const CONFIG_FILE: &str = "config.toml";
+
+// Sampling options, unset ones are left to the backend
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq)]
+pub struct GenParams {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    pub seed: Option<i64>,
+    // Context length, Ollama only
+    pub num_ctx: Option<u64>,
+}
+
+impl GenParams {
+    // Values set in `over` take precedence
+    pub fn merge(&self, over: &GenParams) -> GenParams {
+        GenParams {
+            temperature: over.temperature.or(self.temperature),
+            top_p: over.top_p.or(self.top_p),
+            max_tokens: over.max_tokens.or(self.max_tokens),
+            stop: if over.stop.is_empty() { self.stop.clone() } else { over.stop.clone() },
+            seed: over.seed.or(self.seed),
+            num_ctx: over.num_ctx.or(self.num_ctx),
+        }
+    }
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct AiApi {
+    // Better left out of the config, see key_env, key_cmd and secrets.toml
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub key: String,
+    // Environment variable with the key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_env: Option<String>,
+    // Command printing the key, e.g. "pass show openai"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_cmd: Option<String>,
     pub url: String,
     pub model: String,
+    #[serde(default, flatten)]
+    pub params: GenParams,
+}
+
+// Named assistant setup, selected next to the chat drop down
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct Persona {
+    pub name: String,
+    #[serde(default)]
+    pub system_prompt: String,
+    // AiChat name, e.g. "Ollama"
+    pub provider: Option<String>,
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    // ElevenLabs voice id
+    pub voice: Option<String>,
+    // Language name, e.g. "PL"
+    pub language: Option<String>,
+    // Names of the MCP servers used with this persona, the enabled ones when not set
+    pub mcp: Option<Vec<String>>,
+}
+
+impl Persona {
+    pub fn provider(&self) -> Option<crate::AiChat> {
+        self.provider.as_ref()
+            .and_then(|p| crate::AiChat::ALL.iter().find(|a| a.as_str() == p))
+            .copied()
+    }
+
+    pub fn language(&self) -> Option<crate::Language> {
+        self.language.as_ref()
+            .and_then(|l| crate::Language::ALL.iter().find(|a| a.as_str() == l))
+            .copied()
+    }
+
+    pub fn uses_mcp(&self, server: &McpServer) -> bool {
+        match &self.mcp {
+            Some(names) => names.contains(&server.name),
+            None => server.enabled,
+        }
+    }
+
+    // The persona's model is used only with its preferred provider
+    pub fn model_for(&self, ai: crate::AiChat) -> Option<String> {
+        if self.provider() == Some(ai) { self.model.clone() } else { None }
+    }
+}
+
+// Prompt with {placeholders}, inserted from the Templates menu
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct Template {
+    pub name: String,
+    pub text: String,
+    // GTK accelerator, e.g. "<Control><Alt>t"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shortcut: Option<String>,
+}
+
+// How the history is fitted into the context of the model
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContextConf {
+    // "Sliding", "Pin" or "Summarize"
+    pub strategy: String,
+    // Messages from the start kept by "Pin"
+    pub pin: usize,
+    // Tokens of models missing from `limits`
+    pub limit: usize,
+    pub limits: HashMap<String, usize>,
+    // Left for the answer when max_tokens is not set
+    pub reserve: usize,
+    // Cheaper model of the same provider for "Summarize", the chat model when not set
+    pub summary_model: Option<String>,
+    pub summary_tokens: usize,
+}
+
+impl Default for ContextConf {
+    fn default() -> Self {
+        Self {
+            strategy: String::from("Sliding"),
+            pin: 2,
+            limit: 8192,
+            limits: HashMap::new(),
+            reserve: 1024,
+            summary_model: None,
+            summary_tokens: 512,
+        }
+    }
+}
+
+impl ContextConf {
+    pub fn strategy(&self) -> crate::history::Strategy {
+        crate::history::Strategy::ALL.iter()
+            .find(|s| s.as_str() == self.strategy)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // Configured limit of the model, then Ollama's num_ctx, then the default
+    pub fn limit_for(&self, model: &str, num_ctx: Option<u64>) -> usize {
+        self.limits.get(model)
+            .copied()
+            .or(num_ctx.map(|n| n as usize))
+            .unwrap_or(self.limit)
+    }
+}
+
+// Answers over a local folder, disabled without a folder
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RagConf {
+    pub folder: Option<String>,
+    // Ollama embedding model
+    pub embed_model: String,
+    pub chunk_tokens: usize,
+    pub overlap_tokens: usize,
+    // Chunks added to each question
+    pub top_k: usize,
+}
+
+impl Default for RagConf {
+    fn default() -> Self {
+        Self {
+            folder: None,
+            embed_model: String::from("nomic-embed-text"),
+            chunk_tokens: 400,
+            overlap_tokens: 50,
+            top_k: 4,
+        }
+    }
+}
+
+fn enabled() -> bool {
+    true
+}
+
+// Model Context Protocol server started over stdio
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct McpServer {
+    pub name: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    // Used without a persona, or when the persona doesn't list its servers
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    // Tools which can run without confirmation, besides the ones the server marks read only
+    #[serde(default)]
+    pub read_only: Vec<String>,
+}
+
+// Local tools the models can call
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ToolsConf {
+    pub enabled: bool,
+    // Folders the read_file tool can read from, the tool is off without any
+    pub read_folders: Vec<String>,
+    // Tool call rounds of a single answer
+    pub max_rounds: usize,
+    // Shell commands, each one is confirmed before it runs
+    pub shell: bool,
+    // Seconds before a command is killed
+    pub shell_timeout: u64,
+}
+
+impl Default for ToolsConf {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            read_folders: vec![],
+            max_rounds: 5,
+            shell: false,
+            shell_timeout: 30,
+        }
+    }
+}
+
+// Failed chat requests, transient errors are tried again before the fallback chats
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryConf {
+    pub attempts: u32,
+    // Seconds before the second attempt, doubled for each next one
+    pub delay: f32,
+    // AiChat names asked in order when the selected chat fails
+    pub fallback: Vec<String>,
+}
+
+impl Default for RetryConf {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            delay: 1.0,
+            fallback: vec![],
+        }
+    }
+}
+
+impl RetryConf {
+    pub fn fallback(&self) -> Vec<crate::AiChat> {
+        self.fallback.iter()
+            .filter_map(|f| crate::AiChat::ALL.iter().find(|a| a.as_str() == f).copied())
+            .collect()
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
@@ -13,6 +266,8 @@ pub struct Config {
     pub ollama_url: String,
     pub ollama_port: u16,
     pub ollama_model: String,
+    #[serde(default)]
+    pub ollama_params: GenParams,
 
     pub font_size: f32,
     pub w: f32,
@@ -26,6 +281,388 @@ pub struct Config {
 
     pub whisper_model: String,
     pub chat_msg_wait: u64,
+
+    #[serde(default)]
+    pub personas: Vec<Persona>,
+
+    #[serde(default)]
+    pub templates: Vec<Template>,
+
+    // Tesseract data dir, system default when not set
+    pub tessdata: Option<String>,
+    // PaddleOCR-json executable
+    pub paddleocr_exe: Option<String>,
+
+    // Attached documents are cut to fit the context of the model, and to this many tokens when set
+    pub max_document_tokens: Option<usize>,
+
+    #[serde(default)]
+    pub context: ContextConf,
+
+    #[serde(default)]
+    pub rag: RagConf,
+
+    #[serde(default)]
+    pub tools: ToolsConf,
+
+    #[serde(default)]
+    pub mcp: Vec<McpServer>,
+
+    #[serde(default)]
+    pub retry: RetryConf,
+}
+
+
+
+impl Config {
+    // Settings of the online chats, Ollama has its own fields
+    pub fn api(&self, ai: crate::AiChat) -> Option<&AiApi> {
+        match ai {
+            crate::AiChat::ChatGPT => self.gpt.as_ref(),
+            crate::AiChat::Deepseek => self.deepseek.as_ref(),
+            crate::AiChat::Grok => self.grok.as_ref(),
+            crate::AiChat::Ollama => None,
+        }
+    }
+
+    // Chats which can be asked, the online ones need their section
+    pub fn providers(&self) -> Vec<crate::AiChat> {
+        crate::AiChat::ALL.iter()
+            .filter(|a| **a == crate::AiChat::Ollama || self.api(**a).is_some())
+            .copied()
+            .collect()
+    }
+
+    // Written by the setup when there is no config, Ollama only
+    pub fn initial() -> Config {
+        Config {
+            ollama_url: String::from("http://localhost"),
+            ollama_port: 11434,
+            ollama_model: String::from("llama3.2"),
+            font_size: 17.0,
+            w: 850.0,
+            h: 800.0,
+            chat_msg_wait: 500,
+            ..Default::default()
+        }
+    }
+}
+
+// Set from --config
+static OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn set_path(path: PathBuf) {
+    let _ = OVERRIDE.set(path);
+}
+
+fn xdg_path() -> PathBuf {
+    let base = std::env::var("XDG_CONFIG_HOME")
+        .ok()
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            let home = std::env::var("HOME").unwrap_or(String::from("."));
+            PathBuf::from(home).join(".config")
+        });
+    base.join(APP_DIR).join(CONFIG_FILE)
+}
+
+// --config, then $XDG_CONFIG_HOME/gchatter/config.toml, then the old app.toml next to
+// the executable or in the working directory. A new config goes to the XDG location
+pub fn path() -> PathBuf {
+    if let Some(p) = OVERRIDE.get() {
+        return p.clone();
+    }
+    let xdg = xdg_path();
+    let exe = current_exe().ok()
+        .and_then(|e| e.parent().map(|p| p.join(CONF)));
+    [Some(xdg.clone()), exe, Some(PathBuf::from(CONF))].into_iter()
+        .flatten()
+        .find(|p| p.exists())
+        .unwrap_or(xdg)
+}
+
+pub fn exists() -> bool {
+    path().exists()
+}
+
+pub fn load() -> Result<Config, AppError> {
+    load_from(&path())
+}
+
+fn load_from(path: &Path) -> Result<Config, AppError> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| AppError::Config(format!("Cannot read {}: {}", path.display(), e)))?;
+    toml::from_str(text.as_str())
+        .map_err(|e| AppError::Config(format!("{} is not valid: {}", path.display(), e)))
+}
+
+// The settings are f32 and go through f64 on the way out, 0.7 would be written as
+// 0.699999988079071. The shortest f32 repr is put back
+fn shorten(v: &mut Value) {
+    match v {
+        Value::Float(f) => {
+            let short = (*f.value() as f32).to_string().parse::<f64>().unwrap_or(*f.value());
+            *v = Value::from(short);
+        }
+        Value::Array(a) => a.iter_mut().for_each(shorten),
+        Value::InlineTable(t) => t.iter_mut().for_each(|(_, v)| shorten(v)),
+        _ => {}
+    }
+}
+
+fn shorten_table(t: &mut Table) {
+    for (_, item) in t.iter_mut() {
+        match item {
+            Item::Value(v) => shorten(v),
+            Item::Table(t) => shorten_table(t),
+            Item::ArrayOfTables(a) => a.iter_mut().for_each(shorten_table),
+            Item::None => {}
+        }
+    }
+}
+
+// The config as TOML, before it is merged into the file
+fn to_document(conf: &Config) -> Result<DocumentMut, AppError> {
+    let text = toml::to_string_pretty(conf)
+        .map_err(|e| AppError::Config(e.to_string()))?;
+    let mut doc = text.parse::<DocumentMut>()
+        .map_err(|e| AppError::Config(e.to_string()))?;
+    shorten_table(doc.as_table_mut());
+    Ok(doc)
+}
+
+// Equal values are left as the user wrote them, e.g. 0.70 or 1e3
+fn same(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Float(x), Value::Float(y)) => return *x.value() as f32 == *y.value() as f32,
+        (Value::Integer(x), Value::Integer(y)) => return x.value() == y.value(),
+        _ => {}
+    }
+    let (mut a, mut b) = (a.clone(), b.clone());
+    a.decor_mut().clear();
+    b.decor_mut().clear();
+    a.to_string() == b.to_string()
 }
 
+// Place of a key in the document
+#[derive(Clone)]
+enum Step {
+    Key(String),
+    Index(usize),
+}
+
+// Changed values are put in place keeping the comments around them. Keys which
+// the new config does not have are collected in `gone`, see `prune`
+fn merge(old: &mut Table, new: &Table, at: &mut Vec<Step>, gone: &mut Vec<Vec<Step>>) {
+    for (k, _) in old.iter().filter(|(k, _)| !new.contains_key(k)) {
+        let mut path = at.clone();
+        path.push(Step::Key(k.to_string()));
+        gone.push(path);
+    }
+    for (k, n) in new.iter() {
+        at.push(Step::Key(k.to_string()));
+        match (old.get_mut(k), n) {
+            (Some(Item::Table(o)), Item::Table(n)) => merge(o, n, at, gone),
+            (Some(Item::Value(o)), Item::Value(n)) => {
+                if !same(o, n) {
+                    let decor = o.decor().clone();
+                    *o = n.clone();
+                    *o.decor_mut() = decor;
+                }
+            }
+            (Some(Item::ArrayOfTables(o)), Item::ArrayOfTables(n)) => {
+                while o.len() > n.len() {
+                    o.remove(o.len() - 1);
+                }
+                for (i, t) in n.iter().enumerate() {
+                    match o.get_mut(i) {
+                        Some(ot) => {
+                            at.push(Step::Index(i));
+                            merge(ot, t, at, gone);
+                            at.pop();
+                        }
+                        None => o.push(t.clone()),
+                    }
+                }
+            }
+            (Some(o), n) => *o = n.clone(),
+            (None, n) => {
+                old.insert(k, n.clone());
+            }
+        }
+        at.pop();
+    }
+}
+
+fn remove_at(t: &mut Table, path: &[Step]) {
+    match path {
+        [Step::Key(k)] => {
+            t.remove(k.as_str());
+        }
+        [Step::Key(k), rest @ ..] => match (t.get_mut(k.as_str()), rest) {
+            (Some(Item::Table(t)), _) => remove_at(t, rest),
+            (Some(Item::ArrayOfTables(a)), [Step::Index(i), rest @ ..]) => {
+                if let Some(t) = a.get_mut(*i) {
+                    remove_at(t, rest);
+                }
+            }
+            _ => {}
+        },
+        _ => {}
+    }
+}
+
+// The config as read from the document, None when it is not valid
+fn parsed(doc: &DocumentMut) -> Option<String> {
+    toml::from_str::<Config>(doc.to_string().as_str()).ok()
+        .and_then(|c| toml::to_string(&c).ok())
+}
+
+// Of the keys the new config does not have only the ones which the config reads are
+// removed, e.g. an option which is unset now. Other keys are the user's, or belong to
+// a feature left out of this build or to a newer version, they stay
+fn prune(doc: &mut DocumentMut, gone: Vec<Vec<Step>>) {
+    for path in gone {
+        let mut probe = doc.clone();
+        remove_at(probe.as_table_mut(), path.as_slice());
+        if parsed(&probe) != parsed(doc) {
+            *doc = probe;
+        }
+    }
+}
+
+// The new config written over the document of the existing file
+fn update(doc: &mut DocumentMut, new: &DocumentMut) {
+    let mut gone = vec![];
+    merge(doc.as_table_mut(), new.as_table(), &mut vec![], &mut gone);
+    prune(doc, gone);
+}
+
+pub fn save(conf: &Config) -> Result<PathBuf, AppError> {
+    let path = path();
+    save_to(&path, conf)?;
+    Ok(path)
+}
 
+// Written over the existing file so that the user's comments stay,
+// a file which is not TOML at all is kept with a .bak extension
+fn save_to(path: &Path, conf: &Config) -> Result<(), AppError> {
+    let new = to_document(conf)?;
+    let doc = match std::fs::read_to_string(path).map(|t| t.parse::<DocumentMut>()) {
+        Ok(Ok(mut doc)) => {
+            update(&mut doc, &new);
+            doc
+        }
+        Ok(Err(_)) => {
+            std::fs::rename(path, path.with_extension("toml.bak"))
+                .map_err(|e| AppError::Config(format!("Cannot back up {}: {}", path.display(), e)))?;
+            new
+        }
+        Err(_) => new,
+    };
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| AppError::Config(format!("Cannot create {}: {}", dir.display(), e)))?;
+    }
+    std::fs::write(path, doc.to_string())
+        .map_err(|e| AppError::Config(format!("Cannot write {}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = r#"# Chat settings
+ollama_url = "http://localhost" # local
+ollama_port = 11434
+ollama_model = "llama3.2"
+font_size = 17.0
+w = 850.0
+h = 800.0
+whisper_model = ""
+chat_msg_wait = 500
+tessdata = "/usr/share/tessdata"
+my_extra = 1
+
+[ollama_params]
+# Lower is more focused
+temperature = 0.7
+top_p = 0.90
+mirostat = 2
+
+[retry]
+attempts = 3
+delay = 0.5 # seconds
+
+[future]
+flag = true
+"#;
+
+    fn merged(conf: &Config) -> String {
+        let mut doc = FILE.parse::<DocumentMut>().unwrap();
+        update(&mut doc, &to_document(conf).unwrap());
+        doc.to_string()
+    }
+
+    #[test]
+    fn floats_are_written_short() {
+        let mut conf = Config::initial();
+        conf.ollama_params.temperature = Some(0.7);
+        conf.retry.delay = 0.1;
+        let text = to_document(&conf).unwrap().to_string();
+        assert!(text.contains("temperature = 0.7\n"), "{}", text);
+        assert!(text.contains("delay = 0.1\n"), "{}", text);
+    }
+
+    #[test]
+    fn merge_keeps_comments_and_unchanged_values() {
+        let mut conf: Config = toml::from_str(FILE).unwrap();
+        conf.ollama_model = String::from("qwen3");
+        let text = merged(&conf);
+        for kept in ["# Chat settings", "\"http://localhost\" # local", "# Lower is more focused",
+            "temperature = 0.7\n", "top_p = 0.90\n", "delay = 0.5 # seconds"] {
+            assert!(text.contains(kept), "{} is missing from\n{}", kept, text);
+        }
+        assert!(text.contains("ollama_model = \"qwen3\""), "{}", text);
+        let back: Config = toml::from_str(text.as_str()).unwrap();
+        assert_eq!(back.ollama_params.temperature, Some(0.7));
+        assert_eq!(back.ollama_model, "qwen3");
+    }
+
+    #[test]
+    fn merge_changes_and_removes_keys() {
+        let mut conf: Config = toml::from_str(FILE).unwrap();
+        conf.ollama_params.temperature = Some(0.2);
+        conf.tessdata = None;
+        let text = merged(&conf);
+        assert!(text.contains("# Lower is more focused\ntemperature = 0.2\n"), "{}", text);
+        assert!(!text.contains("tessdata"), "{}", text);
+        assert!(text.contains("[context]"), "{}", text);
+    }
+
+    #[test]
+    fn merge_keeps_unknown_keys() {
+        let mut conf: Config = toml::from_str(FILE).unwrap();
+        conf.tessdata = None;
+        let text = merged(&conf);
+        for kept in ["my_extra = 1\n", "mirostat = 2\n", "[future]\nflag = true\n"] {
+            assert!(text.contains(kept), "{} is missing from\n{}", kept, text);
+        }
+        assert!(!text.contains("tessdata"), "{}", text);
+    }
+
+    #[test]
+    fn save_then_load() {
+        let dir = std::env::temp_dir().join(format!("gchatter-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join(CONFIG_FILE);
+        std::fs::write(&file, FILE).unwrap();
+        let conf = load_from(&file).unwrap();
+        save_to(&file, &conf).unwrap();
+        let text = std::fs::read_to_string(&file).unwrap();
+        assert!(text.contains("# Lower is more focused\ntemperature = 0.7\n"), "{}", text);
+        assert_eq!(load_from(&file).unwrap().ollama_params.temperature, Some(0.7));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
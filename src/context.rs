@@ -3,21 +3,37 @@ use tokio::sync::Mutex;
 use gtk::TextBuffer;
 use gtk::prelude::*;
 use crate::Language;
+use crate::conversation::{Conversation, Role};
+use crate::view::ChatView;
+use crate::sidebar::Sidebar;
+use crate::model_manager::ModelManager;
+use crate::attach::AttachBar;
+use crate::history::ContextMeter;
+use crate::banner::ErrorBanner;
+use crate::errors::AppError;
+use crate::notify::Notifier;
+use crate::store;
 use std::thread::JoinHandle;
-use crate::config::Config;
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::mcp::McpClient;
+use crate::config::{Config, GenParams, Persona};
 use anyhow::{Result, anyhow};
 use tracing::{info, debug, error};
-use std::env::current_exe;
-
-const CONF: &str = "app.toml";
 
 pub struct UiContext {
     text_buffer: TextBuffer,
-    pub result_buffer: TextBuffer,
+    pub chat: ChatView,
+    pub sidebar: Sidebar,
+    pub manager: ModelManager,
+    pub attach: AttachBar,
+    pub meter: ContextMeter,
+    pub banner: ErrorBanner,
 }
 
 pub struct RecContext {
-    recorder: PvRecorder,
+    // None when no capture device could be opened
+    recorder: Option<PvRecorder>,
     pub audio_data: Vec<i16>,
     pub rec_c: bool,
     pub handle: Option<std::thread::JoinHandle<()>>,
@@ -30,14 +46,30 @@ pub struct Context {
     pub conf: Config,
     pub ai_chat: Mutex<Option<crate::AiChat>>,
     pub with_sound: Mutex<bool>,
+    pub conv: Mutex<Conversation>,
+    pub last_transcript: Mutex<Option<String>>,
+    // Index into conf.personas
+    pub persona: Mutex<Option<usize>>,
+    // Overrides from the advanced popover
+    pub params: Mutex<GenParams>,
+    // Model picked in the model drop down, None keeps the configured one
+    pub model: Mutex<Option<String>>,
+    // Local documents index, loaded on first use
+    pub rag: Mutex<Option<crate::rag::Index>>,
+    pub use_rag: Mutex<bool>,
+    pub use_tools: Mutex<bool>,
+    // MCP servers by name, None for the ones which failed to start
+    pub mcp: Mutex<HashMap<String, Option<Arc<McpClient>>>>,
+    // Failures shown to the user
+    pub notifier: Notifier,
 }
 
 unsafe impl Send for Context {}
 unsafe impl Send for UiContext {}
 
 impl UiContext {
-    pub fn new(tv: &TextBuffer, rv: &TextBuffer) -> Self {
-        Self { text_buffer: tv.clone(), result_buffer: rv.clone() }
+    pub fn new(tv: &TextBuffer, chat: ChatView, sidebar: Sidebar, manager: ModelManager, attach: AttachBar, meter: ContextMeter, banner: ErrorBanner) -> Self {
+        Self { text_buffer: tv.clone(), chat, sidebar, manager, attach, meter, banner }
     }
 
     pub fn append_text(&mut self, s: &str) {
@@ -45,11 +77,6 @@ impl UiContext {
         self.text_buffer.insert(&mut end_iter, s);
     }
 
-    pub fn append_result(&mut self, s: &str) {
-        let mut end_iter = self.result_buffer.end_iter();
-        self.result_buffer.insert(&mut end_iter, s);
-    }
-
     pub fn clear_text(&mut self) {
         let mut start = self.text_buffer.start_iter();
         let mut end = self.text_buffer.end_iter();
@@ -57,17 +84,18 @@ impl UiContext {
     }
 
     pub fn clear_result(&mut self) {
-        let mut start = self.result_buffer.start_iter();
-        let mut end = self.result_buffer.end_iter();
-        self.result_buffer.delete(&mut start, &mut end);
+        self.chat.clear();
     }
 
 }
 
 impl RecContext {
     pub fn new() -> Self {
+        let recorder = PvRecorderBuilder::new(512).device_index(0).init()
+            .map_err(|e| error!("Cannot open the record device, voice input is off: {}", e.to_string()))
+            .ok();
         Self {
-            recorder: PvRecorderBuilder::new(512).device_index(0).init().expect("Recorder init error"),
+            recorder,
             audio_data: vec![],
             rec_c: false,
             handle: None,
@@ -78,10 +106,22 @@ impl RecContext {
         self.audio_data = vec![];
     }
 
+    pub fn available(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    fn recorder(&self) -> Result<&PvRecorder> {
+        self.recorder.as_ref().ok_or(anyhow!("No record device"))
+    }
+
     pub fn set_rec_device(&mut self, di: i32) -> Result<()> {
-        self.recorder.stop()?;
-        self.recorder = PvRecorderBuilder::new(512).device_index(di).init().expect("Recorder init error");
-        self.recorder.start()?;
+        if let Some(r) = self.recorder.take() {
+            r.stop()?;
+        }
+        let r = PvRecorderBuilder::new(512).device_index(di).init()
+            .map_err(|e| anyhow!(e.to_string()))?;
+        r.start()?;
+        self.recorder = Some(r);
         Ok(())
     }
 
@@ -97,10 +137,8 @@ impl RecContext {
         let v = !self.rec_c;
         self.rec_c = v;
         debug!("Toggle: {}", v);
-        if v {
-            let _ = self.recorder.start();
-        } else {
-            let _ = self.recorder.stop();
+        if let Some(r) = &self.recorder {
+            let _ = if v { r.start() } else { r.stop() };
         }
         self.rec_c
     }
@@ -108,15 +146,15 @@ impl RecContext {
     pub fn rec(&mut self, v: bool) -> Result<()> {
         self.rec_c = v;
         if v {
-            self.recorder.start()?;
+            self.recorder()?.start()?;
         }  else {
-            self.recorder.stop()?;
+            self.recorder()?.stop()?;
         }
         Ok(())
     }
 
     pub fn read(&mut self) -> Result<()> {
-        let r = self.recorder.read();
+        let r = self.recorder()?.read();
         match r {
             Ok(z) => {
                 self.audio_data.extend_from_slice(&z);
@@ -133,26 +171,33 @@ impl RecContext {
 }
 
 impl Context {
-    pub fn new(tv: &TextBuffer, rv: &TextBuffer) -> Self {
+    pub fn new(conf: Config, tv: &TextBuffer, chat: ChatView, sidebar: Sidebar, manager: ModelManager, attach: AttachBar, meter: ContextMeter, banner: ErrorBanner, notifier: Notifier) -> Self {
         info!("Initializing Context");
-        let exe =  current_exe().unwrap();
-        let ce = exe.parent().unwrap();
-        let config_path = ce.join(CONF);
-
+        let use_tools = conf.tools.enabled;
         Self {
-            ui: Mutex::new(UiContext::new(tv,rv)),
+            ui: Mutex::new(UiContext::new(tv,chat,sidebar,manager,attach,meter,banner)),
             re: Mutex::new(RecContext::new()),
             language: Mutex::new(Some(Language::EN)),
-            conf: toml::from_str(
-                std::fs::read_to_string( 
-                    config_path.to_str().unwrap_or("./app.toml") 
-                    ).unwrap_or(std::fs::read_to_string("./app.toml").unwrap()).as_str() 
-                ).unwrap(),
+            conf,
             ai_chat: Mutex::new(Some(crate::AiChat::ChatGPT)),
             with_sound: Mutex::new(false),
+            conv: Mutex::new(Conversation::new()),
+            last_transcript: Mutex::new(None),
+            persona: Mutex::new(None),
+            params: Mutex::new(GenParams::default()),
+            model: Mutex::new(None),
+            rag: Mutex::new(None),
+            use_rag: Mutex::new(false),
+            use_tools: Mutex::new(use_tools),
+            mcp: Mutex::new(HashMap::new()),
+            notifier,
         }
     }
 
+    pub async fn has_recorder(&self) -> bool {
+        self.re.lock().await.available()
+    }
+
     pub async fn set_rec_device(&self, di: i32) -> Result<()> {
         debug!("Setting record device: {}", di);
         let mut h = self.re.lock().await;
@@ -178,8 +223,298 @@ impl Context {
         self.ui.lock().await.text_buffer.clone()
     }
 
-    pub async fn result_buffer(&self) -> TextBuffer {
-        self.ui.lock().await.result_buffer.clone()
+    // Adds the message to the conversation and the chat view, returns its id and the bubble buffer
+    pub async fn push_message(&self, role: Role, model: &str, text: &str) -> (u64, TextBuffer) {
+        let mut conv = self.conv.lock().await;
+        let id = conv.push(role, model, text);
+        let msg = conv.get(id).cloned().unwrap();
+        let buffer = self.ui.lock().await.chat.push(&msg, conv.variants(id));
+        drop(conv);
+        if role == Role::User {
+            self.save().await;
+        }
+        (id, buffer)
+    }
+
+    // Adds the user's question, the pending transcription and attachments go with it
+    pub async fn push_question(&self, text: &str) -> u64 {
+        let transcript = self.last_transcript.lock().await.take();
+        let (images, mut documents) = self.ui.lock().await.attach.take();
+        if !documents.is_empty() {
+            // Shared equally between the documents
+            let each = self.document_budget().await / documents.len();
+            for d in documents.iter_mut() {
+                let tokens = d.tokens;
+                if crate::documents::fit(d, each) {
+                    self.notifier.report(AppError::Truncated(
+                        format!("{} has about {} tokens, only {} fit into the context", d.name, tokens, each)));
+                }
+            }
+        }
+        let mut conv = self.conv.lock().await;
+        let id = conv.push(Role::User, "", text);
+        let msg = match conv.get_mut(id) {
+            Some(m) => {
+                m.transcript = transcript;
+                m.images = images;
+                m.documents = documents;
+                m.clone()
+            }
+            None => return id,
+        };
+        self.ui.lock().await.chat.push(&msg, conv.variants(id));
+        drop(conv);
+        self.save().await;
+        id
+    }
+
+    // Stores the final text of the message and renders it
+    pub async fn finish_message(&self, id: u64, text: &str) {
+        let mut conv = self.conv.lock().await;
+        if let Some(m) = conv.get_mut(id) {
+            m.text = text.to_string();
+            self.ui.lock().await.chat.set_text(id, m.role, text);
+        }
+        drop(conv);
+        self.save().await;
+    }
+
+    pub async fn set_provider(&self, id: u64, ai: crate::AiChat) {
+        if let Some(m) = self.conv.lock().await.get_mut(id) {
+            m.provider = ai.to_string();
+        }
+    }
+
+    pub async fn set_sources(&self, id: u64, sources: Vec<String>) {
+        let mut conv = self.conv.lock().await;
+        if let Some(m) = conv.get_mut(id) {
+            self.ui.lock().await.chat.set_sources(id, sources.as_slice());
+            m.sources = sources;
+        }
+    }
+
+    pub async fn add_tool_use(&self, id: u64, t: crate::tools::ToolUse) {
+        let mut conv = self.conv.lock().await;
+        if let Some(m) = conv.get_mut(id) {
+            self.ui.lock().await.chat.add_tool_use(id, &t);
+            m.tools.push(t);
+        }
+    }
+
+    // Partial output of a running tool, only shown
+    pub async fn tool_output(&self, id: u64, call_id: &str, text: &str) {
+        self.ui.lock().await.chat.append_tool_output(id, call_id, text);
+    }
+
+    pub async fn set_tool_result(&self, id: u64, call_id: &str, result: &str) {
+        let mut conv = self.conv.lock().await;
+        if let Some(t) = conv.get_mut(id).and_then(|m| m.tools.iter_mut().find(|t| t.id == call_id)) {
+            t.result = result.to_string();
+        }
+        drop(conv);
+        self.ui.lock().await.chat.set_tool_result(id, call_id, result);
+    }
+
+    // Replies of the message are kept, the variant counts can change so the view is rebuilt
+    pub async fn remove_message(&self, id: u64) {
+        if self.conv.lock().await.remove(id).is_none() {
+            return;
+        }
+        self.render_path().await;
+        self.save().await;
+    }
+
+    // Rebuilds the chat view from the shown branch
+    async fn render_path(&self) {
+        let conv = self.conv.lock().await;
+        let mut ui = self.ui.lock().await;
+        ui.chat.clear();
+        for m in conv.path() {
+            ui.chat.push(m, conv.variants(m.id));
+        }
+    }
+
+    // Continues the conversation after the message, the later ones stay as another branch
+    pub async fn branch_from(&self, id: Option<u64>) {
+        self.conv.lock().await.set_current(id);
+        self.render_path().await;
+        self.save().await;
+    }
+
+    // Shows the previous or next variant of the message
+    pub async fn switch_variant(&self, id: u64, delta: i64) {
+        if !self.conv.lock().await.switch(id, delta) {
+            return;
+        }
+        self.ui.lock().await.meter.clear();
+        self.render_path().await;
+        self.save().await;
+    }
+
+    // Switches to the newest branch with the message if it is not shown
+    pub async fn reveal(&self, id: u64) {
+        let mut conv = self.conv.lock().await;
+        if conv.get(id).is_none() || conv.path().iter().any(|m| m.id == id) {
+            return;
+        }
+        let leaf = conv.leaf(id);
+        conv.set_current(Some(leaf));
+        drop(conv);
+        self.render_path().await;
+    }
+
+    pub async fn parent_of(&self, id: u64) -> Option<u64> {
+        self.conv.lock().await.get(id).and_then(|m| m.parent)
+    }
+
+    // Writes the current conversation to the data dir, empty ones are not kept.
+    // The provider and model are the ones of the last answer, the selection before any
+    pub async fn save(&self) {
+        let selected = self.ai_chat.lock().await.map(|a| a.to_string()).unwrap_or_default();
+        let language = self.language.lock().await.map(|l| l.to_string()).unwrap_or_default();
+        let mut conv = self.conv.lock().await;
+        if conv.is_empty() {
+            return;
+        }
+        let answer = conv.path().iter().rev()
+            .find(|m| m.role == Role::Assistant)
+            .map(|m| (m.provider.clone(), m.model.clone()));
+        conv.provider = match &answer {
+            Some((p, _)) if !p.is_empty() => p.clone(),
+            _ => selected,
+        };
+        if let Some((_, m)) = answer {
+            conv.model = m;
+        }
+        conv.language = language;
+        conv.updated = crate::helper::now();
+        if let Err(e) = store::save(&conv) {
+            self.notifier.report(AppError::Storage(format!("{:#}", e)));
+        }
+        let summary = store::ConvSummary::from(&*conv);
+        drop(conv);
+        // Only the row of this conversation changes, the others are not read again
+        self.ui.lock().await.sidebar.update(&summary);
+    }
+
+    pub async fn refresh_sidebar(&self) {
+        let current = self.conv.lock().await.id.clone();
+        match store::list() {
+            Ok(items) => self.ui.lock().await.sidebar.refresh(&items, current.as_str()),
+            Err(e) => error!("Cannot list conversations: {}", e.to_string()),
+        }
+    }
+
+    // Replaces the current conversation and rebuilds the chat view
+    async fn show_conversation(&self, c: Conversation) {
+        let id = c.id.clone();
+        *self.conv.lock().await = c;
+        self.ui.lock().await.meter.clear();
+        self.render_path().await;
+        self.ui.lock().await.sidebar.select(id.as_str());
+    }
+
+    pub async fn new_conversation(&self) {
+        self.show_conversation(Conversation::new()).await;
+    }
+
+    // Reopens a stored conversation so it can be continued, returns the stored provider and language
+    pub async fn open_conversation(&self, id: &str) -> Result<(Option<crate::AiChat>, Option<Language>)> {
+        let mut c = store::load(id)?;
+        // An edit which was not sent leaves the branch point as the end, show the newest branch after it
+        if let Some(last) = c.current.or(c.messages.iter().rev().find(|m| m.parent.is_none()).map(|m| m.id)) {
+            let leaf = c.leaf(last);
+            c.set_current(Some(leaf));
+        }
+        let ai = crate::AiChat::ALL.iter().find(|a| a.as_str() == c.provider).copied();
+        let lang = Language::ALL.iter().find(|l| l.as_str() == c.language).copied();
+        if ai.is_some() {
+            *self.ai_chat.lock().await = ai;
+        }
+        if lang.is_some() {
+            *self.language.lock().await = lang;
+        }
+        self.show_conversation(c).await;
+        Ok((ai, lang))
+    }
+
+    pub async fn delete_conversation(&self, id: &str) -> Result<()> {
+        store::delete(id)?;
+        let current = self.conv.lock().await.id == id;
+        if current {
+            self.new_conversation().await;
+        }
+        self.refresh_sidebar().await;
+        Ok(())
+    }
+
+    pub async fn rename_conversation(&self, id: &str, title: &str) -> Result<()> {
+        let mut conv = self.conv.lock().await;
+        if conv.id == id && !conv.is_empty() {
+            conv.title = title.to_string();
+            drop(conv);
+            self.save().await;
+        } else {
+            drop(conv);
+            store::rename(id, title)?;
+            self.refresh_sidebar().await;
+        }
+        Ok(())
+    }
+
+    pub async fn message_text(&self, id: u64) -> Option<String> {
+        self.conv.lock().await.get(id).map(|m| m.text.clone())
+    }
+
+    pub async fn persona(&self) -> Option<Persona> {
+        let p = *self.persona.lock().await;
+        p.and_then(|i| self.conf.personas.get(i).cloned())
+    }
+
+    // Model drop down, then the persona, then the config.
+    // The drop down lists the models of the selected chat only
+    pub async fn model_for(&self, ai: crate::AiChat, configured: &str) -> String {
+        let selected = *self.ai_chat.lock().await == Some(ai);
+        if let Some(m) = self.model.lock().await.clone().filter(|_| selected) {
+            return m;
+        }
+        self.persona().await
+            .and_then(|p| p.model_for(ai))
+            .unwrap_or(configured.to_string())
+    }
+
+    // Tokens left for attached documents: the context of the selected model without the
+    // answer reserve and the history. The history can be dropped to make room, so the
+    // documents get at least a quarter of the context. max_document_tokens caps it
+    pub async fn document_budget(&self) -> usize {
+        let ai = self.ai_chat.lock().await.unwrap_or_default();
+        let (configured, base) = match ai {
+            crate::AiChat::Ollama => (self.conf.ollama_model.clone(), self.conf.ollama_params.clone()),
+            _ => match self.conf.api(ai) {
+                Some(api) => (api.model.clone(), api.params.clone()),
+                None => (String::new(), GenParams::default()),
+            },
+        };
+        let model = self.model_for(ai, configured.as_str()).await;
+        let params = self.gen_params(&base).await;
+        let cc = &self.conf.context;
+        let num_ctx = if ai == crate::AiChat::Ollama { params.num_ctx } else { None };
+        let reserve = params.max_tokens.map(|m| m as usize).unwrap_or(cc.reserve);
+        let room = cc.limit_for(model.as_str(), num_ctx).saturating_sub(reserve);
+        let history = self.conv.lock().await.history()
+            .map(|m| crate::documents::estimate_tokens(m.content().as_str()))
+            .sum::<usize>();
+        let budget = room.saturating_sub(history).max(room / 4);
+        self.conf.max_document_tokens.map_or(budget, |m| budget.min(m))
+    }
+
+    // Provider config, then the persona, then the advanced popover
+    pub async fn gen_params(&self, base: &GenParams) -> GenParams {
+        let mut p = base.clone();
+        if let Some(t) = self.persona().await.and_then(|p| p.temperature) {
+            p.temperature = Some(t);
+        }
+        p.merge(&*self.params.lock().await)
     }
 
     pub async fn au_buffer_len(&self) -> usize {
@@ -197,7 +532,9 @@ impl Context {
 
     pub async fn dispose(&self) -> Result<()> {
         let mut rlock = self.re.lock().await;
-        crate::report_err!(rlock.recorder.stop());
+        if let Some(r) = &rlock.recorder {
+            crate::report_err!(r.stop());
+        }
         
         if let Some(h) = rlock.handle.take() {
             let _ = h.join();
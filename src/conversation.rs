@@ -0,0 +1,398 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use crate::documents::{self, Document};
+use crate::tools::ToolUse;
+
+#[derive(Clone, Debug, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Message {
+    pub id: u64,
+    // Message answered or continued, None for the first one of a branch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<u64>,
+    pub role: Role,
+    pub model: String,
+    // AiChat which answered, a fallback chat when the selected one failed
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub provider: String,
+    pub text: String,
+    pub time: i64,
+    // Raw Whisper output when the question was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transcript: Option<String>,
+    // Attached image files, copies kept in the data dir
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<PathBuf>,
+    // Text extracted from attached files
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub documents: Vec<Document>,
+    // Files of the local documents the answer was based on
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<String>,
+    // Tool calls made while answering
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<ToolUse>,
+}
+
+impl Message {
+    // Text sent to the model, attached documents go before the question
+    pub fn content(&self) -> String {
+        if self.documents.is_empty() {
+            return self.text.clone();
+        }
+        let mut parts = self.documents.iter()
+            .map(documents::delimited)
+            .collect::<Vec<_>>();
+        parts.push(self.text.clone());
+        parts.join("\n\n")
+    }
+}
+
+// Older messages replaced by a summary when they don't fit into the context
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Summary {
+    // Messages covered, the start of the shown branch. Empty in summaries stored
+    // before it was kept, those are not used
+    #[serde(default)]
+    pub covers: Vec<u64>,
+    pub text: String,
+}
+
+// Stored format, 0 is the flat list written before the messages had parents
+const TREE_VERSION: u32 = 1;
+
+// Single chat session. The messages form a tree: regenerated answers and edited
+// questions are siblings, `current` is the end of the branch which is shown
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Conversation {
+    pub id: String,
+    pub title: String,
+    pub provider: String,
+    pub model: String,
+    pub language: String,
+    pub created: i64,
+    pub updated: i64,
+    pub messages: Vec<Message>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<Summary>,
+    #[serde(default)]
+    pub current: Option<u64>,
+    #[serde(default)]
+    version: u32,
+    next_id: u64,
+}
+
+impl Conversation {
+    pub fn new() -> Self {
+        let millis = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        let now = crate::helper::now();
+        Self {
+            id: millis.to_string(),
+            created: now,
+            updated: now,
+            version: TREE_VERSION,
+            ..Default::default()
+        }
+    }
+
+    // Links the messages of a flat conversation into a single branch
+    pub fn upgrade(&mut self) {
+        if self.version < TREE_VERSION {
+            let mut parent = None;
+            for m in self.messages.iter_mut() {
+                m.parent = parent;
+                parent = Some(m.id);
+            }
+            self.current = parent;
+            self.version = TREE_VERSION;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    // Title from the first question if the user didn't set one
+    pub fn default_title(&self) -> String {
+        self.messages.iter()
+            .find(|m| m.role == Role::User)
+            .map(|m| m.text.lines().next().unwrap_or("").chars().take(40).collect::<String>())
+            .filter(|t| !t.trim().is_empty())
+            .unwrap_or(String::from("New chat"))
+    }
+
+    pub fn push(&mut self, role: Role, model: &str, text: &str) -> u64 {
+        self.push_at(role, model, text, crate::helper::now())
+    }
+
+    pub fn push_at(&mut self, role: Role, model: &str, text: &str, time: i64) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.messages.push(Message {
+            id,
+            parent: self.current,
+            role,
+            model: model.to_string(),
+            provider: String::new(),
+            text: text.to_string(),
+            time,
+            transcript: None,
+            images: vec![],
+            documents: vec![],
+            sources: vec![],
+            tools: vec![],
+        });
+        self.current = Some(id);
+        id
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Message> {
+        self.messages.iter().find(|m| m.id == id)
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut Message> {
+        self.messages.iter_mut().find(|m| m.id == id)
+    }
+
+    fn position(&self, id: u64) -> Option<usize> {
+        self.messages.iter().position(|m| m.id == id)
+    }
+
+    // Messages of the shown branch, from the first one
+    pub fn path(&self) -> Vec<&Message> {
+        let mut res = vec![];
+        let mut id = self.current;
+        while let Some(m) = id.and_then(|i| self.get(i)) {
+            res.push(m);
+            id = m.parent;
+        }
+        res.reverse();
+        res
+    }
+
+    // Messages with the same parent, the message itself included, oldest first
+    fn siblings(&self, id: u64) -> Vec<u64> {
+        let parent = match self.get(id) {
+            Some(m) => m.parent,
+            None => return vec![],
+        };
+        self.messages.iter()
+            .filter(|m| m.parent == parent)
+            .map(|m| m.id)
+            .collect()
+    }
+
+    // Position of the message among its variants (from 1) and their count
+    pub fn variants(&self, id: u64) -> (usize, usize) {
+        let s = self.siblings(id);
+        (s.iter().position(|i| *i == id).map(|i| i + 1).unwrap_or(1), s.len())
+    }
+
+    // End of the newest branch starting with the message
+    pub fn leaf(&self, id: u64) -> u64 {
+        let mut id = id;
+        while let Some(c) = self.messages.iter().rev().find(|m| m.parent == Some(id)) {
+            id = c.id;
+        }
+        id
+    }
+
+    // The summary is valid while the messages it covers start the shown branch
+    fn check_summary(&mut self) {
+        let covers = match &self.summary {
+            Some(s) => s.covers.clone(),
+            None => return,
+        };
+        let shown = self.history().map(|m| m.id).take(covers.len()).collect::<Vec<_>>();
+        if covers.is_empty() || shown != covers {
+            self.summary = None;
+        }
+    }
+
+    // Shows the branch ending with the message, the next one pushed becomes its child
+    pub fn set_current(&mut self, id: Option<u64>) {
+        self.current = id;
+        self.check_summary();
+    }
+
+    // Shows the previous (-1) or next (1) variant of the message with its newest branch
+    pub fn switch(&mut self, id: u64, delta: i64) -> bool {
+        let s = self.siblings(id);
+        let i = match s.iter().position(|i| *i == id) {
+            Some(i) => i as i64 + delta,
+            None => return false,
+        };
+        if i < 0 || i >= s.len() as i64 {
+            return false;
+        }
+        let leaf = self.leaf(s[i as usize]);
+        self.set_current(Some(leaf));
+        true
+    }
+
+    // Removes a single message, its replies move to its parent
+    pub fn remove(&mut self, id: u64) -> Option<Message> {
+        let m = self.position(id).map(|i| self.messages.remove(i))?;
+        for c in self.messages.iter_mut().filter(|c| c.parent == Some(id)) {
+            c.parent = m.parent;
+        }
+        if self.current == Some(id) {
+            self.current = m.parent;
+        }
+        self.check_summary();
+        Some(m)
+    }
+
+    // Messages of the shown branch which are not empty, used to build the request history
+    pub fn history(&self) -> impl Iterator<Item = &Message> {
+        self.path().into_iter().filter(|m| !m.text.is_empty())
+    }
+
+    pub fn clear(&mut self) {
+        self.summary = None;
+        self.current = None;
+        self.messages.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(c: &Conversation) -> Vec<u64> {
+        c.path().iter().map(|m| m.id).collect()
+    }
+
+    // q0 with two answers, the first one continued with q1
+    fn branched() -> (Conversation, [u64; 4]) {
+        let mut c = Conversation::new();
+        let q0 = c.push(Role::User, "m", "q0");
+        let a0 = c.push(Role::Assistant, "m", "a0");
+        let q1 = c.push(Role::User, "m", "q1");
+        c.set_current(Some(q0));
+        let a1 = c.push(Role::Assistant, "m", "a1");
+        (c, [q0, a0, q1, a1])
+    }
+
+    #[test]
+    fn regenerated_answers_are_variants() {
+        let (c, [q0, a0, _, a1]) = branched();
+        assert_eq!(ids(&c), vec![q0, a1]);
+        assert_eq!(c.variants(a0), (1, 2));
+        assert_eq!(c.variants(a1), (2, 2));
+        assert_eq!(c.variants(q0), (1, 1));
+    }
+
+    #[test]
+    fn leaf_follows_the_newest_branch() {
+        let (c, [q0, a0, q1, a1]) = branched();
+        assert_eq!(c.leaf(a0), q1);
+        assert_eq!(c.leaf(q0), a1);
+        assert_eq!(c.leaf(q1), q1);
+    }
+
+    #[test]
+    fn switch_between_variants() {
+        let (mut c, [q0, a0, q1, a1]) = branched();
+        assert!(c.switch(a1, -1));
+        assert_eq!(ids(&c), vec![q0, a0, q1]);
+        assert!(!c.switch(a0, -1));
+        assert!(c.switch(a0, 1));
+        assert_eq!(ids(&c), vec![q0, a1]);
+        assert!(!c.switch(a1, 1));
+        assert!(!c.switch(99, 1));
+    }
+
+    #[test]
+    fn remove_moves_replies_to_the_parent() {
+        let (mut c, [q0, a0, q1, a1]) = branched();
+        c.set_current(Some(q1));
+        let m = c.remove(a0).unwrap();
+        assert_eq!(m.id, a0);
+        assert_eq!(c.get(q1).unwrap().parent, Some(q0));
+        assert_eq!(ids(&c), vec![q0, q1]);
+        assert_eq!(c.variants(a1), (2, 2));
+        assert!(c.remove(a0).is_none());
+    }
+
+    #[test]
+    fn remove_current_shows_its_parent() {
+        let (mut c, [q0, _, _, a1]) = branched();
+        c.remove(a1);
+        assert_eq!(c.current, Some(q0));
+        assert_eq!(ids(&c), vec![q0]);
+    }
+
+    fn summary(covers: &[u64]) -> Option<Summary> {
+        Some(Summary { covers: covers.to_vec(), text: String::from("s") })
+    }
+
+    #[test]
+    fn summary_of_changed_messages_is_dropped() {
+        let (mut c, [q0, a0, q1, a1]) = branched();
+        c.set_current(Some(q1));
+        c.summary = summary(&[q0, a0]);
+        c.remove(q1);
+        assert!(c.summary.is_some());
+        c.switch(a0, 1);
+        assert_eq!(ids(&c), vec![q0, a1]);
+        assert!(c.summary.is_none());
+        c.summary = summary(&[q0, a1]);
+        c.remove(q0);
+        assert!(c.summary.is_none());
+    }
+
+    #[test]
+    fn summary_is_dropped_on_another_branch_after_it() {
+        let (mut c, [q0, a0, q1, _]) = branched();
+        c.set_current(Some(q1));
+        let a2 = c.push(Role::Assistant, "m", "a2");
+        c.summary = summary(&[q0, a0, q1, a2]);
+        // Another answer to q1, a2 is not on the branch anymore
+        c.set_current(Some(q1));
+        let a3 = c.push(Role::Assistant, "m", "a3");
+        c.set_current(Some(a3));
+        assert!(c.summary.is_none());
+        // A branch after the summarized messages keeps it
+        c.summary = summary(&[q0, a0]);
+        c.set_current(Some(q1));
+        c.push(Role::Assistant, "m", "a4");
+        c.set_current(Some(a2));
+        assert!(c.summary.is_some());
+        // So does a summary stored without the covered ids
+        c.summary = summary(&[]);
+        c.set_current(Some(a3));
+        assert!(c.summary.is_none());
+    }
+
+    #[test]
+    fn upgrade_links_flat_messages() {
+        let mut c = Conversation::default();
+        for t in ["q0", "a0", "q1"] {
+            c.push(Role::User, "m", t);
+        }
+        for m in c.messages.iter_mut() {
+            m.parent = None;
+        }
+        c.current = None;
+        c.upgrade();
+        assert_eq!(ids(&c), vec![0, 1, 2]);
+        assert_eq!(c.get(2).unwrap().parent, Some(1));
+    }
+
+    #[test]
+    fn history_skips_empty_messages() {
+        let mut c = Conversation::new();
+        c.push(Role::User, "m", "q0");
+        c.push(Role::Assistant, "m", "");
+        assert_eq!(c.history().count(), 1);
+    }
+}
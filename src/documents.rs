@@ -0,0 +1,107 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, anyhow};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+// Text of an attached file, sent along with the question
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Document {
+    pub name: String,
+    pub path: PathBuf,
+    pub text: String,
+    pub tokens: usize,
+    // Cut to fit the context of the model
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+// Rough estimate, about 4 characters per token for most models
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_default()
+}
+
+pub fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// Paragraphs of word/document.xml, formatting is dropped
+fn docx_text(path: &Path) -> Result<String> {
+    let mut zip = zip::ZipArchive::new(std::fs::File::open(path)?)?;
+    let mut xml = String::new();
+    zip.by_name("word/document.xml")?.read_to_string(&mut xml)?;
+    let xml = xml.replace("</w:p>", "\n")
+        .replace("<w:tab/>", "\t")
+        .replace("<w:br/>", "\n");
+    let tags = Regex::new(r"<[^>]+>")?;
+    Ok(unescape(tags.replace_all(xml.as_str(), "").as_ref()))
+}
+
+// PDF and DOCX are converted, anything else has to be UTF-8 text
+pub fn extract(path: &Path) -> Result<String> {
+    match extension(path).as_str() {
+        "pdf" => pdf_extract::extract_text(path).map_err(|e| anyhow!("Cannot read PDF: {}", e.to_string())),
+        "docx" => docx_text(path),
+        _ => {
+            let bytes = std::fs::read(path)?;
+            String::from_utf8(bytes).map_err(|_| anyhow!("Not a text file: {}", path.display()))
+        }
+    }
+}
+
+// Keeps the first `max_tokens` worth of characters
+fn truncate(text: &str, max_tokens: usize) -> Option<String> {
+    let max_chars = max_tokens * 4;
+    text.char_indices()
+        .nth(max_chars)
+        .map(|(i, _)| text[..i].to_string())
+}
+
+// The whole text, it is cut when the question is sent and the model is known
+pub fn load(path: &Path) -> Result<Document> {
+    let name = path.file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or(path.display().to_string());
+    let text = extract(path)?;
+    debug!("Loaded {} ({} chars)", name, text.len());
+    Ok(Document {
+        tokens: estimate_tokens(text.as_str()),
+        name,
+        path: path.to_path_buf(),
+        text,
+        truncated: false,
+    })
+}
+
+// Cuts the document to `max_tokens`, true when it was longer
+pub fn fit(doc: &mut Document, max_tokens: usize) -> bool {
+    match truncate(doc.text.as_str(), max_tokens) {
+        Some(t) => {
+            info!("{} has about {} tokens, truncated to {}", doc.name, doc.tokens, max_tokens);
+            doc.text = t;
+            doc.tokens = estimate_tokens(doc.text.as_str());
+            doc.truncated = true;
+            true
+        }
+        None => false,
+    }
+}
+
+// The document as it goes into the prompt
+pub fn delimited(doc: &Document) -> String {
+    let note = if doc.truncated { " (truncated)" } else { "" };
+    format!("----- BEGIN FILE: {}{} -----\n{}\n----- END FILE: {} -----",
+        doc.name, note, doc.text.trim_end(), doc.name)
+}
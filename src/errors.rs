@@ -0,0 +1,256 @@
+use std::future::Future;
+use std::time::Duration;
+use anyhow::Result;
+use crate::config::RetryConf;
+use tracing::warn;
+
+crate::make_enum!(ErrorKind, [Other, Auth, RateLimit, Network, ModelMissing, Server]);
+
+impl ErrorKind {
+    // Worth trying again after a while
+    pub fn transient(&self) -> bool {
+        matches!(self, ErrorKind::RateLimit | ErrorKind::Network | ErrorKind::Server)
+    }
+
+    // What the user can do about it
+    pub fn hint(&self) -> &'static str {
+        match self {
+            ErrorKind::Auth => "The API key was rejected, check the key in the preferences",
+            ErrorKind::RateLimit => "Rate limited, wait a moment or check the plan of the account",
+            ErrorKind::Network => "Cannot connect, check the network and the URL",
+            ErrorKind::ModelMissing => "The model is not available, pick another one or pull it",
+            ErrorKind::Server => "The service had an error, try again later",
+            ErrorKind::Other => "The request failed",
+        }
+    }
+}
+
+// Response of a chat API which was not a success
+#[derive(Debug)]
+pub struct HttpError {
+    pub status: u16,
+    pub body: String,
+    // Seconds from the Retry-After header
+    pub retry_after: Option<u64>,
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HTTP {}: {}", self.status, self.body)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl HttpError {
+    pub async fn from_response(res: reqwest::Response) -> Self {
+        let retry_after = res.headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse().ok());
+        Self {
+            status: res.status().as_u16(),
+            body: res.text().await.unwrap_or_default(),
+            retry_after,
+        }
+    }
+}
+
+// Ollama reports most problems only as text, those are matched by words
+pub fn classify(e: &anyhow::Error) -> ErrorKind {
+    let text = format!("{:#}", e).to_lowercase();
+    if let Some(h) = e.downcast_ref::<HttpError>() {
+        match h.status {
+            401 | 403 => return ErrorKind::Auth,
+            429 => return ErrorKind::RateLimit,
+            404 if text.contains("model") => return ErrorKind::ModelMissing,
+            500..=599 => return ErrorKind::Server,
+            _ => {}
+        }
+    }
+    let network = e.chain()
+        .filter_map(|c| c.downcast_ref::<reqwest::Error>())
+        .any(|r| r.is_connect() || r.is_timeout() || r.is_request());
+    if network {
+        return ErrorKind::Network;
+    }
+    if text.contains("model") && (text.contains("not found") || text.contains("does not exist") || text.contains("try pulling")) {
+        ErrorKind::ModelMissing
+    } else if text.contains("api key") || text.contains("unauthorized") || text.contains("authentication") {
+        ErrorKind::Auth
+    } else if text.contains("rate limit") || text.contains("too many requests") {
+        ErrorKind::RateLimit
+    } else if text.contains("connection refused") || text.contains("connection closed") || text.contains("timed out") || text.contains("error sending request") {
+        ErrorKind::Network
+    } else if text.contains("overloaded") || text.contains("server error") {
+        ErrorKind::Server
+    } else {
+        ErrorKind::Other
+    }
+}
+
+// Runs the request again while it fails with a transient error,
+// waiting delay, 2 * delay... or what the server asked for
+pub async fn retry<T, F, Fut>(conf: &RetryConf, what: &str, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut delay = Duration::from_secs_f32(conf.delay.max(0.0));
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Ok(r) => return Ok(r),
+            Err(e) if attempt < conf.attempts && classify(&e).transient() => {
+                let wait = e.downcast_ref::<HttpError>()
+                    .and_then(|h| h.retry_after)
+                    .map(Duration::from_secs)
+                    .unwrap_or(delay);
+                warn!("{} failed ({:#}), attempt {} of {} in {:.1} s", what, e, attempt + 1, conf.attempts, wait.as_secs_f32());
+                tokio::time::sleep(wait).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Failure reported to the user, the text is the full detail which goes to the log
+#[derive(Debug, Clone)]
+pub enum AppError {
+    Config(String),
+    Audio(String),
+    Transcription(String),
+    Speech(String),
+    Ocr(String),
+    Attachment(String),
+    Tool(String),
+    // Not a failure, the document was cut to fit the context
+    Truncated(String),
+    Request(ErrorKind, String),
+    Storage(String),
+}
+
+impl AppError {
+    pub fn title(&self) -> &'static str {
+        match self {
+            AppError::Config(_) => "Configuration error",
+            AppError::Audio(_) => "Audio device error",
+            AppError::Transcription(_) => "Transcription failed",
+            AppError::Speech(_) => "Speech failed",
+            AppError::Ocr(_) => "Text recognition failed",
+            AppError::Attachment(_) => "Cannot attach",
+            AppError::Tool(_) => "Tool unavailable",
+            AppError::Truncated(_) => "Attachment shortened",
+            AppError::Request(_, _) => "Request failed",
+            AppError::Storage(_) => "Cannot save",
+        }
+    }
+
+    pub fn hint(&self) -> &'static str {
+        match self {
+            AppError::Config(_) => "Fix the file, or start with --config and another one",
+            AppError::Audio(_) => "Check that a microphone is connected and pick it in the device list",
+            AppError::Transcription(_) => "Check the Whisper model in the preferences and record again",
+            AppError::Speech(_) => "Check the ElevenLabs key and voice in the preferences",
+            AppError::Ocr(_) => "Check tessdata or paddleocr_exe in the config and the image",
+            AppError::Attachment(_) => "Images, PDF, DOCX and UTF-8 text files can be attached",
+            AppError::Tool(_) => "Check the command of the [[mcp]] server in the config",
+            AppError::Truncated(_) => "Only the start is sent, attach a smaller part or pick a model with a larger context",
+            AppError::Request(kind, _) => kind.hint(),
+            AppError::Storage(_) => "Check the free space and permissions of the data dir",
+        }
+    }
+
+    pub fn detail(&self) -> &str {
+        match self {
+            AppError::Config(d) | AppError::Audio(d) | AppError::Transcription(d) | AppError::Speech(d)
+                | AppError::Ocr(d) | AppError::Attachment(d) | AppError::Tool(d) | AppError::Truncated(d)
+                | AppError::Request(_, d) | AppError::Storage(d) => d.as_str(),
+        }
+    }
+
+    // Classified error of a chat request
+    pub fn request(e: &anyhow::Error) -> Self {
+        AppError::Request(classify(e), format!("{:#}", e))
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.title(), self.detail())
+    }
+}
+
+impl std::error::Error for AppError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    fn http(status: u16, body: &str) -> anyhow::Error {
+        anyhow::Error::new(HttpError { status, body: body.to_string(), retry_after: None })
+    }
+
+    #[test]
+    fn classify_by_status() {
+        assert_eq!(classify(&http(401, "")), ErrorKind::Auth);
+        assert_eq!(classify(&http(403, "forbidden")), ErrorKind::Auth);
+        assert_eq!(classify(&http(429, "")), ErrorKind::RateLimit);
+        assert_eq!(classify(&http(404, "model 'x' not found")), ErrorKind::ModelMissing);
+        assert_eq!(classify(&http(404, "no such page")), ErrorKind::Other);
+        assert_eq!(classify(&http(503, "")), ErrorKind::Server);
+        assert_eq!(classify(&http(400, "bad request")), ErrorKind::Other);
+    }
+
+    #[test]
+    fn classify_by_text() {
+        assert_eq!(classify(&anyhow!("model \"llama9\" not found, try pulling it first")), ErrorKind::ModelMissing);
+        assert_eq!(classify(&anyhow!("Invalid API key provided")), ErrorKind::Auth);
+        assert_eq!(classify(&anyhow!("Too Many Requests")), ErrorKind::RateLimit);
+        assert_eq!(classify(&anyhow!("tcp connect error: Connection refused")), ErrorKind::Network);
+        assert_eq!(classify(&anyhow!("connection closed before message completed")), ErrorKind::Network);
+        assert_eq!(classify(&anyhow!("The engine is overloaded")), ErrorKind::Server);
+        assert_eq!(classify(&anyhow!("something else")), ErrorKind::Other);
+    }
+
+    #[test]
+    fn classify_looks_through_context() {
+        let e = http(429, "").context("Asking ChatGPT");
+        assert_eq!(classify(&e), ErrorKind::RateLimit);
+        let e = anyhow!("connection refused").context("Asking Ollama");
+        assert_eq!(classify(&e), ErrorKind::Network);
+    }
+
+    #[test]
+    fn only_transient_kinds_are_retried() {
+        assert!(ErrorKind::RateLimit.transient());
+        assert!(ErrorKind::Network.transient());
+        assert!(ErrorKind::Server.transient());
+        assert!(!ErrorKind::Auth.transient());
+        assert!(!ErrorKind::ModelMissing.transient());
+        assert!(!ErrorKind::Other.transient());
+    }
+
+    #[tokio::test]
+    async fn retry_stops_at_permanent_errors() {
+        let conf = RetryConf { attempts: 3, delay: 0.0, fallback: vec![] };
+        let mut calls = 0;
+        let res: Result<()> = retry(&conf, "test", || {
+            calls += 1;
+            async { Err(http(401, "")) }
+        }).await;
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+
+        let mut calls = 0;
+        let res = retry(&conf, "test", || {
+            calls += 1;
+            let n = calls;
+            async move { if n < 3 { Err(http(503, "")) } else { Ok(n) } }
+        }).await;
+        assert_eq!(res.unwrap(), 3);
+    }
+}
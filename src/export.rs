@@ -0,0 +1,163 @@
+use std::path::Path;
+use anyhow::Result;
+use pulldown_cmark::{Event, Parser, Options, html};
+use crate::conversation::{Conversation, Message, Role};
+use crate::helper::format_time;
+use crate::store;
+use tracing::info;
+
+crate::make_enum!(ExportFormat, [Markdown, Html, Json]);
+
+impl ExportFormat {
+    pub fn extension(&self) -> &str {
+        match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Html => "html",
+            ExportFormat::Json => "json",
+        }
+    }
+}
+
+const STYLE: &str = "
+body { font-family: sans-serif; max-width: 50em; margin: 2em auto; padding: 0 1em; color: #222; }
+.meta { color: #666; font-size: 0.9em; }
+.msg { border-radius: 8px; padding: 0.5em 1em; margin: 1em 0; }
+.user { background: #e8f0fe; margin-left: 4em; }
+.assistant { background: #f3f3f3; margin-right: 4em; }
+.who { font-weight: bold; }
+.time { color: #888; font-size: 0.8em; margin-left: 1em; }
+.transcript { color: #555; font-style: italic; }
+pre { background: #272822; color: #f8f8f2; padding: 0.7em; overflow-x: auto; border-radius: 4px; }
+code { font-family: monospace; }
+";
+
+fn title(c: &Conversation) -> String {
+    if c.title.is_empty() { c.default_title() } else { c.title.clone() }
+}
+
+fn who(m: &Message) -> &str {
+    match m.role {
+        Role::User => "You",
+        Role::Assistant => m.model.as_str(),
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Keeps the original model text, so the Markdown is what the model returned
+pub fn to_markdown(c: &Conversation) -> String {
+    let mut res = format!("# {}\n\n", title(c));
+    res.push_str(format!("- Provider: {}\n- Model: {}\n- Language: {}\n- Created: {}\n- Updated: {}\n\n",
+        c.provider, c.model, c.language, format_time(c.created), format_time(c.updated)).as_str());
+    // Only the shown branch, the other variants are left out
+    for m in c.path() {
+        res.push_str(format!("## {} ({})\n\n", who(m), format_time(m.time)).as_str());
+        if let Some(t) = &m.transcript {
+            res.push_str(format!("> Transcript: {}\n\n", t.trim()).as_str());
+        }
+        for p in m.images.iter() {
+            res.push_str(format!("![image]({})\n\n", p.display()).as_str());
+        }
+        for d in m.documents.iter() {
+            res.push_str(format!("> Attached: {} (~{} tokens)\n\n", d.name, d.tokens).as_str());
+        }
+        for t in m.tools.iter() {
+            res.push_str(format!("> Tool {}({}): {}\n\n", t.name, t.arguments, t.result.trim()).as_str());
+        }
+        res.push_str(m.text.trim_end());
+        res.push_str("\n\n");
+        if !m.sources.is_empty() {
+            res.push_str(format!("Sources: {}\n\n", m.sources.join(", ")).as_str());
+        }
+    }
+    res
+}
+
+pub fn to_html(c: &Conversation) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TABLES);
+
+    let t = escape(title(c).as_str());
+    let mut res = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n", t, STYLE);
+    res.push_str(format!("<h1>{}</h1>\n<p class=\"meta\">{} {} &middot; {} &middot; {}</p>\n",
+        t, escape(c.provider.as_str()), escape(c.model.as_str()), escape(c.language.as_str()), format_time(c.created)).as_str());
+    for m in c.path() {
+        let class = if m.role == Role::User { "user" } else { "assistant" };
+        res.push_str(format!("<div class=\"msg {}\">\n<div><span class=\"who\">{}</span><span class=\"time\">{}</span></div>\n",
+            class, escape(who(m)), format_time(m.time)).as_str());
+        if let Some(tr) = &m.transcript {
+            res.push_str(format!("<p class=\"transcript\">Transcript: {}</p>\n", escape(tr.trim())).as_str());
+        }
+        // Embedded, so the file stays standalone
+        for p in m.images.iter() {
+            if let Ok(url) = crate::images::data_url(p) {
+                res.push_str(format!("<img src=\"{}\" style=\"max-width: 100%\">\n", url).as_str());
+            }
+        }
+        for d in m.documents.iter() {
+            res.push_str(format!("<p class=\"transcript\">Attached: {}</p>\n", escape(d.name.as_str())).as_str());
+        }
+        for t in m.tools.iter() {
+            res.push_str(format!("<p class=\"transcript\">Tool {}({}): {}</p>\n",
+                escape(t.name.as_str()), escape(t.arguments.to_string().as_str()), escape(t.result.trim())).as_str());
+        }
+        match m.role {
+            Role::User => res.push_str(format!("<p>{}</p>\n", escape(m.text.as_str()).replace('\n', "<br>\n")).as_str()),
+            // Raw HTML of the answer is shown as text, a script in it must not run when the file is opened
+            Role::Assistant => html::push_html(&mut res, Parser::new_ext(m.text.as_str(), options)
+                .map(|e| match e {
+                    Event::Html(t) | Event::InlineHtml(t) => Event::Text(t),
+                    e => e,
+                })),
+        }
+        if !m.sources.is_empty() {
+            res.push_str(format!("<p class=\"meta\">Sources: {}</p>\n", escape(m.sources.join(", ").as_str())).as_str());
+        }
+        res.push_str("</div>\n");
+    }
+    res.push_str("</body>\n</html>\n");
+    res
+}
+
+// Same as the stored file, with all the metadata
+pub fn to_json(c: &Conversation) -> Result<String> {
+    Ok(serde_json::to_string_pretty(c)?)
+}
+
+pub fn render(c: &Conversation, fmt: ExportFormat) -> Result<String> {
+    match fmt {
+        ExportFormat::Markdown => Ok(to_markdown(c)),
+        ExportFormat::Html => Ok(to_html(c)),
+        ExportFormat::Json => to_json(c),
+    }
+}
+
+// File name from the title, safe for any file system
+pub fn file_name(c: &Conversation, fmt: ExportFormat) -> String {
+    let name = title(c).chars()
+        .map(|ch| if ch.is_alphanumeric() || ch == '-' || ch == '_' { ch } else { '_' })
+        .collect::<String>();
+    format!("{}-{}.{}", name.trim_matches('_'), c.id, fmt.extension())
+}
+
+pub fn export(c: &Conversation, fmt: ExportFormat, path: &Path) -> Result<()> {
+    std::fs::write(path, render(c, fmt)?)?;
+    info!("Exported {} to {}", c.id, path.display());
+    Ok(())
+}
+
+// Exports every stored conversation into the directory, returns the count
+pub fn export_all(dir: &Path, fmt: ExportFormat) -> Result<usize> {
+    std::fs::create_dir_all(dir)?;
+    let convs = store::load_all()?;
+    for c in convs.iter() {
+        export(c, fmt, dir.join(file_name(c, fmt)).as_path())?;
+    }
+    Ok(convs.len())
+}
@@ -1,14 +1,16 @@
-use gtk::{DropDown, StringList};
+use gtk::{glib, DropDown, StringList};
 use pv_recorder::PvRecorderBuilder;
 use pulldown_cmark::{Parser, Options, html};
 use regex::Regex;
+use crate::errors::AppError;
+use crate::notify::Notifier;
 
 // Gets the drop down with devices
-pub fn device_dd() -> DropDown {
+pub fn device_dd(notifier: &Notifier) -> DropDown {
     let devices = PvRecorderBuilder::new(512)
         .get_available_devices()
         .unwrap_or_else(|e| {
-            eprintln!("Cannot obtain the list of record devices!: {}", e.to_string());
+            notifier.report(AppError::Audio(format!("Cannot list the record devices: {}", e.to_string())));
             vec![]
         });
 
@@ -24,6 +26,20 @@ pub fn device_dd() -> DropDown {
         .build()
 }
 
+// Current local time as unix timestamp
+pub fn now() -> i64 {
+    glib::DateTime::now_local()
+        .map(|d| d.to_unix())
+        .unwrap_or(0)
+}
+
+pub fn format_time(t: i64) -> String {
+    glib::DateTime::from_unix_local(t)
+        .and_then(|d| d.format("%Y-%m-%d %H:%M"))
+        .map(|s| s.to_string())
+        .unwrap_or_default()
+}
+
 //--------------- Enums -------------
 
 #[macro_export]
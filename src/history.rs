@@ -0,0 +1,105 @@
+use gtk::prelude::*;
+use gtk::{Box, Label, LevelBar, Orientation};
+use crate::conversation::{Message, Role};
+use crate::documents::estimate_tokens;
+
+crate::make_enum!(Strategy, [Sliding, Pin, Summarize]);
+
+// Role and formatting overhead of a message
+const MESSAGE_OVERHEAD: usize = 4;
+// Rough cost of an attached image
+const IMAGE_TOKENS: usize = 512;
+
+pub fn message_tokens(m: &Message) -> usize {
+    estimate_tokens(m.content().as_str()) + m.images.len() * IMAGE_TOKENS + MESSAGE_OVERHEAD
+}
+
+// Messages sent to the model and the ones which didn't fit
+pub struct Window {
+    pub keep: Vec<Message>,
+    pub dropped: Vec<Message>,
+    pub tokens: usize,
+}
+
+// Newest messages first until the budget is used, the last question is always kept
+pub fn select(history: &[Message], budget: usize, strategy: Strategy, pin: usize) -> Window {
+    let pinned = if strategy == Strategy::Pin { pin.min(history.len()) } else { 0 };
+    let mut tokens = history[..pinned].iter().map(message_tokens).sum::<usize>();
+    let mut start = history.len();
+    while start > pinned {
+        let t = message_tokens(&history[start - 1]);
+        if tokens + t > budget && start < history.len() {
+            break;
+        }
+        tokens += t;
+        start -= 1;
+    }
+    let mut keep = history[..pinned].to_vec();
+    keep.extend_from_slice(&history[start..]);
+    Window {
+        keep,
+        dropped: history[pinned..start].to_vec(),
+        tokens,
+    }
+}
+
+// Plain text of the messages for the summary request
+pub fn transcript(msgs: &[Message]) -> String {
+    msgs.iter()
+        .map(|m| format!("{}: {}", if m.role == Role::User { "User" } else { "Assistant" }, m.content()))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+// How full the context of the last request was
+pub struct ContextMeter {
+    root: Box,
+    bar: LevelBar,
+    label: Label,
+}
+
+impl ContextMeter {
+    pub fn new() -> Self {
+        let bar = LevelBar::builder()
+            .min_value(0.0)
+            .max_value(1.0)
+            .width_request(80)
+            .valign(gtk::Align::Center)
+            .build();
+        bar.add_offset_value("context-high", 0.9);
+        let label = Label::builder()
+            .margin_start(5)
+            .build();
+        let root = Box::builder()
+            .orientation(Orientation::Horizontal)
+            .margin_start(5)
+            .build();
+        root.append(&bar);
+        root.append(&label);
+        let m = Self { root, bar, label };
+        m.clear();
+        m
+    }
+
+    pub fn widget(&self) -> &Box {
+        &self.root
+    }
+
+    pub fn set(&self, used: usize, limit: usize, dropped: usize, summarized: bool) {
+        let fill = if limit == 0 { 1.0 } else { (used as f64 / limit as f64).min(1.0) };
+        self.bar.set_value(fill);
+        self.label.set_text(format!("{:.0}%", fill * 100.0).as_str());
+        let mut tip = format!("Context: ≈ {} of {} tokens", used, limit);
+        if dropped > 0 {
+            let how = if summarized { "summarized" } else { "left out" };
+            tip.push_str(format!("\n{} older messages {}", dropped, how).as_str());
+        }
+        self.root.set_tooltip_text(Some(tip.as_str()));
+    }
+
+    pub fn clear(&self) {
+        self.bar.set_value(0.0);
+        self.label.set_text("0%");
+        self.root.set_tooltip_text(Some("Context usage of the last request"));
+    }
+}
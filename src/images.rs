@@ -0,0 +1,56 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use anyhow::{Result, anyhow};
+use gtk::glib;
+use crate::store::data_dir;
+
+const IMAGE_DIR: &str = "images";
+const EXTENSIONS: [&str; 5] = ["png", "jpg", "jpeg", "webp", "gif"];
+
+pub fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| EXTENSIONS.contains(&e.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+pub fn mime(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase()).as_deref() {
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("webp") => "image/webp",
+        Some("gif") => "image/gif",
+        _ => "image/png",
+    }
+}
+
+// Keeps a copy in the data dir so the saved conversation can still show it,
+// the name is the hash of the content
+pub fn store_bytes(bytes: &[u8], ext: &str) -> Result<PathBuf> {
+    let dir = data_dir().join(IMAGE_DIR);
+    std::fs::create_dir_all(&dir)?;
+    let mut h = DefaultHasher::new();
+    bytes.hash(&mut h);
+    let path = dir.join(format!("{:016x}.{}", h.finish(), ext));
+    if !path.exists() {
+        std::fs::write(&path, bytes)?;
+    }
+    Ok(path)
+}
+
+pub fn store_file(path: &Path) -> Result<PathBuf> {
+    if !is_image(path) {
+        return Err(anyhow!("Not a supported image: {}", path.display()));
+    }
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("png").to_lowercase();
+    store_bytes(std::fs::read(path)?.as_slice(), ext.as_str())
+}
+
+pub fn base64(path: &Path) -> Result<String> {
+    Ok(glib::base64_encode(std::fs::read(path)?.as_slice()).to_string())
+}
+
+// For the OpenAI image_url content part
+pub fn data_url(path: &Path) -> Result<String> {
+    Ok(format!("data:{};base64,{}", mime(path), base64(path)?))
+}
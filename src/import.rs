@@ -0,0 +1,328 @@
+use std::collections::HashSet;
+use std::path::Path;
+use anyhow::{Result, anyhow};
+use serde_json::Value;
+use crate::conversation::{Conversation, Role};
+use crate::store;
+use tracing::{info, debug};
+
+fn role(r: &str) -> Option<Role> {
+    match r {
+        "user" => Some(Role::User),
+        "assistant" => Some(Role::Assistant),
+        _ => None,
+    }
+}
+
+// Some exports use seconds, some milliseconds, some floats
+fn timestamp(v: &Value) -> Option<i64> {
+    let t = v.as_f64()?;
+    Some(if t > 1e12 { (t / 1000.0) as i64 } else { t as i64 })
+}
+
+// Store ids can't contain path characters
+fn conv_id(prefix: &str, id: Option<&str>) -> String {
+    let id = id.map(|i| i.to_string()).unwrap_or_else(|| Conversation::new().id);
+    let id = id.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect::<String>();
+    format!("{}-{}", prefix, id)
+}
+
+fn finish(mut c: Conversation) -> Conversation {
+    if let Some(first) = c.messages.first() {
+        c.created = first.time;
+    }
+    if let Some(last) = c.messages.last() {
+        c.updated = last.time;
+    }
+    if let Some(m) = c.messages.iter().rev().find(|m| m.role == Role::Assistant) {
+        c.model = m.model.clone();
+    }
+    c
+}
+
+// ChatGPT `conversations.json`, messages are a tree in `mapping`,
+// the shown branch ends with `current_node`
+fn from_chatgpt(v: &Value) -> Result<Conversation> {
+    let mapping = v["mapping"].as_object().ok_or(anyhow!("Missing mapping"))?;
+    let mut c = Conversation::new();
+    c.id = conv_id("chatgpt", v["id"].as_str().or(v["conversation_id"].as_str()));
+    c.title = v["title"].as_str().unwrap_or("").to_string();
+    c.provider = crate::AiChat::ChatGPT.to_string();
+    let default_model = v["default_model_slug"].as_str().unwrap_or("");
+    let created = timestamp(&v["create_time"]).unwrap_or(0);
+
+    // A broken export can link back to a node, each one is taken once
+    let mut path = vec![];
+    let mut seen = HashSet::new();
+    let mut node = v["current_node"].as_str();
+    while let Some(n) = node.filter(|n| seen.insert(*n)) {
+        let entry = match mapping.get(n) {
+            Some(e) => e,
+            None => break,
+        };
+        path.push(entry);
+        node = entry["parent"].as_str();
+    }
+
+    for entry in path.iter().rev() {
+        let msg = &entry["message"];
+        let r = match msg["author"]["role"].as_str().and_then(role) {
+            Some(r) => r,
+            None => continue,
+        };
+        let text = msg["content"]["parts"].as_array()
+            .map(|parts| parts.iter()
+                .filter_map(|p| p.as_str())
+                .collect::<Vec<_>>()
+                .join("\n"))
+            .or(msg["content"]["text"].as_str().map(|t| t.to_string()))
+            .unwrap_or_default();
+        if text.trim().is_empty() {
+            continue;
+        }
+        let model = msg["metadata"]["model_slug"].as_str().unwrap_or(default_model);
+        let time = timestamp(&msg["create_time"]).unwrap_or(created);
+        c.push_at(r, if r == Role::Assistant { model } else { "" }, text.as_str(), time);
+    }
+    Ok(finish(c))
+}
+
+// Open WebUI export, `chat.history` holds the tree, `chat.messages` the flat list
+fn from_open_webui(v: &Value) -> Result<Conversation> {
+    let chat = &v["chat"];
+    let mut c = Conversation::new();
+    c.id = conv_id("webui", v["id"].as_str().or(chat["id"].as_str()));
+    c.title = v["title"].as_str().or(chat["title"].as_str()).unwrap_or("").to_string();
+    c.provider = crate::AiChat::Ollama.to_string();
+    let default_model = chat["models"][0].as_str().unwrap_or("");
+    let created = timestamp(&v["created_at"]).or(timestamp(&chat["timestamp"])).unwrap_or(0);
+
+    let mut messages: Vec<&Value> = vec![];
+    if let Some(history) = chat["history"]["messages"].as_object() {
+        let mut seen = HashSet::new();
+        let mut node = chat["history"]["currentId"].as_str();
+        while let Some(n) = node.filter(|n| seen.insert(*n)) {
+            let m = match history.get(n) {
+                Some(m) => m,
+                None => break,
+            };
+            messages.push(m);
+            node = m["parentId"].as_str();
+        }
+        messages.reverse();
+    }
+    if messages.is_empty() {
+        messages = chat["messages"].as_array()
+            .map(|a| a.iter().collect())
+            .unwrap_or_default();
+    }
+
+    for m in messages {
+        let r = match m["role"].as_str().and_then(role) {
+            Some(r) => r,
+            None => continue,
+        };
+        let text = m["content"].as_str().unwrap_or("");
+        let model = m["model"].as_str().unwrap_or(default_model);
+        let time = timestamp(&m["timestamp"]).unwrap_or(created);
+        c.push_at(r, if r == Role::Assistant { model } else { "" }, text, time);
+    }
+    Ok(finish(c))
+}
+
+// Plain Ollama chat format: `{"model": ..., "messages": [{"role": ..., "content": ...}]}`
+fn from_ollama(v: &Value) -> Result<Conversation> {
+    let mut c = Conversation::new();
+    c.id = conv_id("ollama", v["id"].as_str());
+    c.title = v["title"].as_str().unwrap_or("").to_string();
+    c.provider = crate::AiChat::Ollama.to_string();
+    let model = v["model"].as_str().unwrap_or("");
+    let created = timestamp(&v["created_at"]).unwrap_or(crate::helper::now());
+    for m in v["messages"].as_array().ok_or(anyhow!("Missing messages"))? {
+        let r = match m["role"].as_str().and_then(role) {
+            Some(r) => r,
+            None => continue,
+        };
+        let time = timestamp(&m["timestamp"]).unwrap_or(created);
+        c.push_at(r, if r == Role::Assistant { model } else { "" }, m["content"].as_str().unwrap_or(""), time);
+    }
+    Ok(finish(c))
+}
+
+fn convert(v: &Value) -> Result<Conversation> {
+    if v.get("mapping").is_some() {
+        from_chatgpt(v)
+    } else if v.get("chat").is_some() {
+        from_open_webui(v)
+    } else if v.get("messages").is_some() {
+        from_ollama(v)
+    } else {
+        Err(anyhow!("Unknown conversation format"))
+    }
+}
+
+// Reads a ChatGPT, Open WebUI or Ollama export, one conversation or a list of them
+pub fn read(path: &Path) -> Result<Vec<Conversation>> {
+    let v: Value = serde_json::from_str(std::fs::read_to_string(path)?.as_str())?;
+    let items = match &v {
+        Value::Array(a) => a.iter().collect::<Vec<_>>(),
+        _ => vec![&v],
+    };
+    let mut res = vec![];
+    for item in items {
+        match convert(item) {
+            Ok(c) if !c.is_empty() => res.push(c),
+            Ok(_) => debug!("Skipping empty conversation"),
+            Err(e) => debug!("Skipping item: {}", e.to_string()),
+        }
+    }
+    if res.is_empty() {
+        return Err(anyhow!("No conversations found in {}", path.display()));
+    }
+    Ok(res)
+}
+
+// Imports into the conversation store, already imported ones get overwritten
+pub fn import(path: &Path) -> Result<usize> {
+    let convs = read(path)?;
+    for c in convs.iter() {
+        store::save(c)?;
+    }
+    info!("Imported {} conversations from {}", convs.len(), path.display());
+    Ok(convs.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn texts(c: &Conversation) -> Vec<(Role, String)> {
+        c.path().iter().map(|m| (m.role, m.text.clone())).collect()
+    }
+
+    #[test]
+    fn chatgpt_follows_current_node() {
+        let v = json!({
+            "id": "abc/1",
+            "title": "Trip",
+            "default_model_slug": "gpt-4o",
+            "create_time": 1700000000.5,
+            "current_node": "a2",
+            "mapping": {
+                "root": { "parent": null, "message": null },
+                "sys": { "parent": "root", "message": {
+                    "author": { "role": "system" }, "content": { "parts": ["You are helpful"] } } },
+                "q": { "parent": "sys", "message": {
+                    "author": { "role": "user" }, "create_time": 1700000001.0,
+                    "content": { "parts": ["Where", "to go?"] } } },
+                "a1": { "parent": "q", "message": {
+                    "author": { "role": "assistant" }, "content": { "parts": ["Old answer"] } } },
+                "a2": { "parent": "q", "message": {
+                    "author": { "role": "assistant" }, "create_time": 1700000002000.0,
+                    "metadata": { "model_slug": "o3" }, "content": { "parts": ["Rome"] } } }
+            }
+        });
+        let c = convert(&v).unwrap();
+        assert_eq!(c.id, "chatgpt-abc_1");
+        assert_eq!(c.title, "Trip");
+        assert_eq!(texts(&c), vec![
+            (Role::User, String::from("Where\nto go?")),
+            (Role::Assistant, String::from("Rome")),
+        ]);
+        assert_eq!(c.model, "o3");
+        assert_eq!(c.created, 1700000001);
+        assert_eq!(c.updated, 1700000002);
+    }
+
+    #[test]
+    fn open_webui_follows_current_id() {
+        let v = json!({
+            "id": "w1",
+            "title": "Rust",
+            "chat": {
+                "models": ["llama3.2"],
+                "history": {
+                    "currentId": "m3",
+                    "messages": {
+                        "m1": { "parentId": null, "role": "user", "content": "Hi", "timestamp": 10 },
+                        "m2": { "parentId": "m1", "role": "assistant", "content": "Hello", "timestamp": 11 },
+                        "m3": { "parentId": "m1", "role": "assistant", "content": "Hey", "model": "qwen3",
+                            "timestamp": 12 }
+                    }
+                },
+                "messages": [
+                    { "role": "user", "content": "Hi" },
+                    { "role": "assistant", "content": "Hello" }
+                ]
+            }
+        });
+        let c = convert(&v).unwrap();
+        assert_eq!(c.id, "webui-w1");
+        assert_eq!(texts(&c), vec![
+            (Role::User, String::from("Hi")),
+            (Role::Assistant, String::from("Hey")),
+        ]);
+        assert_eq!(c.model, "qwen3");
+    }
+
+    #[test]
+    fn parent_cycles_end() {
+        let v = json!({
+            "current_node": "b",
+            "mapping": {
+                "a": { "parent": "a", "message": {
+                    "author": { "role": "user" }, "content": { "parts": ["Hi"] } } },
+                "b": { "parent": "a", "message": {
+                    "author": { "role": "assistant" }, "content": { "parts": ["Hello"] } } }
+            }
+        });
+        assert_eq!(texts(&convert(&v).unwrap()).len(), 2);
+        let v = json!({
+            "chat": {
+                "history": {
+                    "currentId": "m2",
+                    "messages": {
+                        "m1": { "parentId": "m2", "role": "user", "content": "Hi" },
+                        "m2": { "parentId": "m1", "role": "assistant", "content": "Hello" }
+                    }
+                }
+            }
+        });
+        assert_eq!(texts(&convert(&v).unwrap()).len(), 2);
+    }
+
+    #[test]
+    fn open_webui_flat_messages() {
+        let v = json!({
+            "chat": {
+                "models": ["llama3.2"],
+                "messages": [
+                    { "role": "user", "content": "Hi", "timestamp": 10 },
+                    { "role": "assistant", "content": "Hello", "timestamp": 11 }
+                ]
+            }
+        });
+        let c = convert(&v).unwrap();
+        assert_eq!(c.path().len(), 2);
+        assert_eq!(c.model, "llama3.2");
+    }
+
+    #[test]
+    fn ollama_and_unknown() {
+        let v = json!({
+            "model": "llama3.2",
+            "messages": [
+                { "role": "user", "content": "2+2?" },
+                { "role": "tool", "content": "4" },
+                { "role": "assistant", "content": "4" }
+            ]
+        });
+        let c = convert(&v).unwrap();
+        assert_eq!(texts(&c).len(), 2);
+        assert_eq!(c.model, "llama3.2");
+        assert!(convert(&json!({ "foo": 1 })).is_err());
+    }
+}
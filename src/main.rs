@@ -1,8 +1,12 @@
 #![allow(dead_code)]
 use gtk::prelude::*;
-use gtk::{glib, Application, ApplicationWindow, Button, ScrolledWindow, TextView, Box, DropDown, Label, CheckButton};
+use gtk::{glib, Application, ApplicationWindow, Button, TextView, Box, DropDown, Label, CheckButton};
 use std::sync::Arc;
 use crate::context::Context;
+use crate::conversation::Role;
+use crate::view::{ChatView, MsgAction};
+use crate::sidebar::{Sidebar, SideAction};
+use crate::errors::AppError;
 use tracing::{debug, error, info};
 
 mod context;
@@ -10,6 +14,36 @@ mod helper;
 mod chat;
 mod config;
 mod transcribe;
+mod conversation;
+mod view;
+mod store;
+mod sidebar;
+mod search;
+mod search_view;
+mod export;
+mod import;
+mod advanced;
+mod models;
+mod model_manager;
+mod api;
+mod images;
+mod attach;
+mod documents;
+mod history;
+mod rag;
+mod calc;
+mod tools;
+mod mcp;
+mod compare;
+mod templates;
+mod errors;
+mod banner;
+mod notify;
+mod setup;
+mod prefs;
+mod secrets;
+#[cfg(any(feature = "leptess", feature = "paddleocr"))]
+mod ocr;
 
 make_enum!(AiChat, [ChatGPT, Grok, Deepseek, Ollama]);
 make_enum!(Language, [EN,PL,CN,DE,FR,ES,RU,TR,JP]);
@@ -21,15 +55,76 @@ use elevenlabs_rs::utils::play;
 const APP_NAME: &str = "gChatter 0.3.0";
 const APP_ID: &str = "org.gnome.gChatter.Devel";
 
+// Resolves the API keys in a blocking task, a key command may ask for a passphrase.
+// No window is open yet, so the app is held until it is built
+fn start(app: &Application, conf: config::Config) {
+    let guard = app.hold();
+    let app = app.clone();
+    glib::spawn_future_local(async move {
+        let resolved = tokio::task::spawn_blocking(move || {
+            let mut conf = conf;
+            let errors = secrets::resolve_keys(&mut conf);
+            (conf, errors)
+        }).await;
+        match resolved {
+            Ok((conf, errors)) => build_window(&app, conf, errors),
+            Err(e) => error!("Cannot resolve the API keys: {}", e.to_string()),
+        }
+        drop(guard);
+    });
+}
+
+// The setup is shown first when there is no config
 fn build_ui(app: &Application) {
+    if !config::exists() {
+        info!("No {}, starting the setup", config::path().display());
+        let a = app.clone();
+        setup::present(app, move |conf| start(&a, conf));
+        return;
+    }
+    match config::load() {
+        Ok(conf) => start(app, conf),
+        Err(e) => {
+            error!("{}", e);
+            let dialog = gtk::AlertDialog::builder()
+                .message(e.title())
+                .detail(format!("{}\n\n{}, or set it up again",
+                    e.detail(), e.hint()).as_str())
+                .buttons(["Quit", "Set up again"])
+                .cancel_button(0)
+                .default_button(1)
+                .build();
+            // Nothing is open yet, the app stays until the dialog is answered
+            let guard = app.hold();
+            let app = app.clone();
+            glib::spawn_future_local(async move {
+                if matches!(dialog.choose_future(None::<&gtk::Window>).await, Ok(1)) {
+                    let a = app.clone();
+                    setup::present(&app, move |conf| start(&a, conf));
+                }
+                drop(guard);
+            });
+        }
+    }
+}
+
+// `errors` are the keys which could not be resolved
+fn build_window(app: &Application, conf: config::Config, errors: Vec<AppError>) {
     // Create a window and set the title
     let window = ApplicationWindow::builder()
         .application(app)
         .title(APP_NAME)
-        .default_height(550)
-        .default_width(700)
+        .default_height(conf.h as i32)
+        .default_width(conf.w as i32)
         .build();
 
+    // Font size of the chat and the prompt
+    let css = gtk::CssProvider::new();
+    css.load_from_string(format!("textview {{ font-size: {}pt; }}", conf.font_size).as_str());
+    if let Some(display) = gtk::gdk::Display::default() {
+        gtk::style_context_add_provider_for_display(&display, &css, gtk::STYLE_PROVIDER_PRIORITY_APPLICATION);
+    }
+
     let text_view = TextView::builder()
         .editable(true)
         .cursor_visible(true)
@@ -38,15 +133,43 @@ fn build_ui(app: &Application) {
         .wrap_mode(gtk::WrapMode::Word)
         .build();
 
-
-    let result_view = TextView::builder()
-        .editable(false)
-        .cursor_visible(true)
-        .margin_top(10)
-        .wrap_mode(gtk::WrapMode::Word)
+    let (notifier, notify_rx) = notify::channel();
+    let s_notify = notify::area(notify_rx);
+    for e in errors {
+        notifier.report(e);
+    }
+    let (action_sx, action_rx) = async_channel::unbounded::<MsgAction>();
+    let chat_view = ChatView::new(action_sx, conf.providers());
+    let s_result_view = chat_view.widget().clone();
+    let (side_sx, side_rx) = async_channel::unbounded::<SideAction>();
+    let sidebar = Sidebar::new(side_sx.clone());
+    let s_sidebar = sidebar.widget().clone();
+
+    let manager = model_manager::ModelManager::new(&window, conf.ollama_url.as_str(), conf.ollama_port, notifier.clone());
+    let idc_models = Button::builder()
+        .label("Models")
+        .margin_start(5)
         .build();
+    let mm = manager.clone();
+    idc_models.connect_clicked(move |_| {
+        let mm = mm.clone();
+        glib::spawn_future_local(async move {
+            mm.present().await;
+        });
+    });
+
+    let attach_bar = attach::AttachBar::new(notifier.clone());
+    attach_bar.connect_prompt(&text_view);
+    let attachments = attach_bar.widget().clone();
+    let idc_attach = attach_bar.button();
+
+    let meter = history::ContextMeter::new();
+    let s_meter = meter.widget().clone();
+
+    let banner = banner::ErrorBanner::new();
+    let s_banner = banner.widget().clone();
 
-    let ctx = Arc::new(Context::new(&text_view.buffer(), &result_view.buffer()));
+    let ctx = Arc::new(Context::new(conf, &text_view.buffer(), chat_view, sidebar, manager, attach_bar, meter, banner.clone(), notifier.clone()));
     debug!("Context ready");
 
     let (chat_sx,chat_rx) = async_channel::unbounded::<String>();
@@ -56,7 +179,6 @@ fn build_ui(app: &Application) {
         if let Some(c) = &st.conf.eleven {
             let key = &c.key;
             //let url = &c.url;
-            let voice = &c.model;
             let client = ElevenLabsClient::new(key.as_str());
             info!("Found elevenlabs config");
             while let Ok(txt) = chat_rx.recv().await {
@@ -65,33 +187,50 @@ fn build_ui(app: &Application) {
                     continue;
                 }
                 debug!("read: {}", txt);
+                let voice = st.persona().await
+                    .and_then(|p| p.voice)
+                    .unwrap_or(c.model.clone());
                 let body = TextToSpeechBody::new(txt)
                     .with_model_id(Model::ElevenMultilingualV2);
-                let endpoint = TextToSpeech::new(voice, body);
+                let endpoint = TextToSpeech::new(voice.as_str(), body);
                 match client.hit(endpoint).await {
                     Ok(speech) => {
                         debug!("playing");
-                        crate::report_err!(play(speech));
+                        if let Err(e) = play(speech) {
+                            st.notifier.report(AppError::Speech(format!("Cannot play: {}", e.to_string())));
+                        }
                     }
                     Err(e) => {
-                        error!("tts error: {}", e.to_string());
+                        st.notifier.report(AppError::Speech(e.to_string()));
                     }
                 }
             }
         }
     });
 
+    let model_sel = std::rc::Rc::new(models::ModelSelector::new(ctx.clone()));
     let ai_sel = enum_dd!(AiChat, 0, 100);
     let st = ctx.clone();
+    let ms = model_sel.clone();
     ai_sel.connect_selected_item_notify(move |r| {
         let sel = r.selected();
         let st = st.clone();
+        let ms = ms.clone();
         let item = AiChat::ALL[sel as usize];
         glib::spawn_future_local(async move {
             let mut a = st.ai_chat.lock().await;
             *a = Some(item);
+            drop(a);
+            ms.refresh(&st, item).await;
         });
     });
+    let st = ctx.clone();
+    let ms = model_sel.clone();
+    glib::spawn_future_local(async move {
+        let ai = st.ai_chat.lock().await.unwrap_or_default();
+        ms.refresh(&st, ai).await;
+    });
+    let model_dd = model_sel.widget().clone();
 
     let st = ctx.clone();
     let language_sel = enum_dd!(Language, 5);
@@ -103,16 +242,11 @@ fn build_ui(app: &Application) {
             *a = Some(Language::ALL[sel as usize]);
         });
     });
-    
-    let s_result_view = ScrolledWindow::builder()
-        .child(&result_view)
-        .min_content_height(310)
-        .build();
 
     let idc_ask = Button::builder()
         .label("Ask")
         .build();
-    let devices = helper::device_dd();
+    let devices = helper::device_dd(&notifier);
     let st = ctx.clone();
     devices.connect_selected_item_notify(move |r| {
         let st = st.clone();
@@ -121,10 +255,17 @@ fn build_ui(app: &Application) {
         glib::spawn_future_local(async move {
             match st.set_rec_device(isel).await {
                 Ok(_) => debug!("Device set"),
-                Err(e) => error!("Error setting device: {}", e.to_string()),
+                Err(e) => st.notifier.report(AppError::Audio(format!("Cannot use the device: {}", e.to_string()))),
             }
         });
     });
+    // The device from the preferences, the first one otherwise
+    let names = devices.model().and_downcast::<gtk::StringList>();
+    let configured = ctx.conf.record_device.as_ref()
+        .and_then(|r| names.and_then(|n| (0..n.n_items()).find(|i| n.string(*i).as_deref() == Some(r.as_str()))));
+    if let Some(i) = configured.filter(|i| *i != devices.selected()) {
+        devices.set_selected(i);
+    }
 
     let idc_rec = Button::builder()
         .label("Rec")
@@ -226,15 +367,53 @@ fn build_ui(app: &Application) {
                 Ok(r) => {
                     let a = st2.text_buffer().await;
                     a.set_text(r.as_str());
+                    *st2.last_transcript.lock().await = Some(r);
                 },
                 Err(e) => {
-                    error!("Error transcribing: {}", e.to_string());
+                    st2.notifier.report(AppError::Transcription(format!("{:#}", e)));
                 },
             }
             s.send(true).await.expect("Failed to send from TR");
         });
     });
 
+    // Text chat works without voice, the controls which need it are switched off with the reason
+    let st = ctx.clone();
+    glib::spawn_future_local(glib::clone!(
+        #[weak]
+        devices,
+        #[weak]
+        idc_rec,
+        #[weak]
+        idc_tr,
+        #[weak]
+        status_label,
+        async move {
+            let no_devices = devices.model().map_or(0, |m| m.n_items()) == 0;
+            let why = if no_devices || !st.has_recorder().await {
+                Some("No microphone was found, voice input is off")
+            } else if st.conf.whisper_model.trim().is_empty() {
+                Some("Set the Whisper model in the preferences to use voice input")
+            } else {
+                None
+            };
+            if let Some(why) = why {
+                info!("{}", why);
+                devices.set_sensitive(false);
+                idc_rec.set_sensitive(false);
+                idc_tr.set_sensitive(false);
+                idc_rec.set_tooltip_text(Some(why));
+                idc_tr.set_tooltip_text(Some(why));
+                status_label.set_text("no voice input");
+                status_label.set_tooltip_text(Some(why));
+            }
+        }
+    ));
+    if ctx.conf.eleven.is_none() {
+        idc_play.set_sensitive(false);
+        idc_play.set_tooltip_text(Some("Set the ElevenLabs key in the preferences to read the answers aloud"));
+    }
+
     let ids_dev = Label::builder()
         .label("device")
         .margin_start(5)
@@ -262,45 +441,261 @@ fn build_ui(app: &Application) {
     });
 
 
-    let hbox = row!(5,[ai_sel, ids_dev, devices, status_label]);
-    let bhbox = row!(5,[idc_ask, idc_rec, language_sel, idc_tr, idc_clearq, idc_play]);
-    let vbox = column![text_view, s_result_view, hbox, bhbox];
+    let personas = gtk::StringList::new(&["No persona"]);
+    for p in ctx.conf.personas.iter() {
+        personas.append(p.name.as_str());
+    }
+    let persona_sel = DropDown::builder()
+        .model(&personas)
+        .margin_start(5)
+        .build();
+    let st = ctx.clone();
+    persona_sel.connect_selected_item_notify(glib::clone!(
+        #[weak]
+        ai_sel,
+        #[weak]
+        language_sel,
+        move |r| {
+            let sel = r.selected() as usize;
+            let persona = if sel == 0 { None } else { Some(sel - 1) };
+            if let Some(p) = persona.and_then(|i| st.conf.personas.get(i)) {
+                // Selecting in the drop downs updates the context as well
+                if let Some(i) = p.provider().and_then(|a| AiChat::ALL.iter().position(|x| *x == a)) {
+                    ai_sel.set_selected(i as u32);
+                }
+                if let Some(i) = p.language().and_then(|l| Language::ALL.iter().position(|x| *x == l)) {
+                    language_sel.set_selected(i as u32);
+                }
+            }
+            let st = st.clone();
+            glib::spawn_future_local(async move {
+                *st.persona.lock().await = persona;
+            });
+        }
+    ));
+
+    let idc_prefs = prefs::prefs_button(ctx.clone());
+    let hbox = row!(5,[ai_sel, model_dd, idc_models, idc_prefs, persona_sel, ids_dev, devices, s_meter, status_label]);
+    let idc_advanced = advanced::advanced_button(ctx.clone());
+    let idc_compare = compare::compare_button(ctx.clone());
+    let idc_templates = templates::templates_button(ctx.clone());
+    templates::connect_shortcuts(ctx.clone(), window.upcast_ref(), &idc_templates);
+    let bhbox = row!(5,[idc_ask, idc_compare, idc_templates, idc_attach, idc_rec, language_sel, idc_tr, idc_clearq, idc_play, idc_advanced]);
+    #[cfg(any(feature = "leptess", feature = "paddleocr"))]
+    bhbox.append(&ocr::ocr_button(ctx.clone()));
+    if ctx.conf.rag.folder.is_some() {
+        bhbox.append(&rag::rag_button(ctx.clone()));
+    }
+    let idc_tools = CheckButton::builder()
+        .label("Tools")
+        .active(ctx.conf.tools.enabled)
+        .tooltip_text("Let the model use the calculator, clock, allowed files and past chats")
+        .margin_start(5)
+        .build();
+    let st = ctx.clone();
+    idc_tools.connect_toggled(move |c| {
+        let on = c.is_active();
+        let st = st.clone();
+        glib::spawn_future_local(async move {
+            *st.use_tools.lock().await = on;
+        });
+    });
+    bhbox.append(&idc_tools);
+    let vbox = column![s_notify, s_result_view, s_banner, attachments, text_view, hbox, bhbox];
+
+    // The question is already in the conversation, it is only asked again
+    let st = ctx.clone();
+    let csx = chat_sx.clone();
+    banner.connect_retry(move || {
+        let st = st.clone();
+        let chat_sx = csx.clone();
+        glib::spawn_future_local(async move {
+            chat::ask(st, chat_sx).await;
+        });
+    });
 
     let st = ctx.clone();
     let st2 = ctx.clone();
 
+    let tb = text_view.buffer();
+    let csx = chat_sx.clone();
     idc_ask.connect_clicked(move |_| {
         let st = st.clone();
-        let st2 = st2.clone();
-        let chat_sx = chat_sx.clone();
+        let chat_sx = csx.clone();
+        let prompt = get_text!(tb).to_string();
+        clear_text!(tb);
         glib::spawn_future_local(async move {
-            let ai = st.ai_chat.lock().await.clone();
-            let result_buffer = st.result_buffer().await;
-            clear_text!(result_buffer);
-            match ai {
-                Some(AiChat::Ollama) => {
-                    glib::spawn_future_local(async move {
+            st.push_question(prompt.as_str()).await;
+            chat::ask(st, chat_sx).await;
+        });
+    });
+
+    let st = st2;
+    let tb = text_view.buffer();
+    glib::spawn_future_local(glib::clone!(
+        #[weak]
+        window,
+        #[weak]
+        ai_sel,
+        async move {
+            while let Ok(action) = action_rx.recv().await {
+                debug!("Message action: {:?}", action);
+                match action {
+                    MsgAction::Copy(id) => {
+                        if let Some(text) = st.message_text(id).await {
+                            window.clipboard().set_text(text.as_str());
+                        }
+                    }
+                    MsgAction::Speak(id) => {
+                        if let Some(text) = st.message_text(id).await {
+                            crate::report_err!(chat_sx.send(text).await);
+                        }
+                    }
+                    MsgAction::Edit(id) => {
+                        if let Some(text) = st.message_text(id).await {
+                            tb.set_text(text.as_str());
+                            // The edited question is sent as a sibling, the old turns stay as a variant
+                            let parent = st.parent_of(id).await;
+                            st.branch_from(parent).await;
+                        }
+                    }
+                    MsgAction::Prev(id) => st.switch_variant(id, -1).await,
+                    MsgAction::Next(id) => st.switch_variant(id, 1).await,
+                    MsgAction::Regenerate(id) | MsgAction::RegenerateWith(id, _) => {
+                        if let MsgAction::RegenerateWith(_, ai) = action {
+                            *st.ai_chat.lock().await = Some(ai);
+                            *st.model.lock().await = None;
+                            if let Some(i) = AiChat::ALL.iter().position(|x| *x == ai) {
+                                ai_sel.set_selected(i as u32);
+                            }
+                        }
+                        // The new answer becomes a variant of the old one
+                        let msg = st.conv.lock().await.get(id).map(|m| (m.role, m.parent));
+                        let question = match msg {
+                            Some((Role::User, _)) => Some(id),
+                            Some((Role::Assistant, parent)) => parent,
+                            None => continue,
+                        };
+                        st.branch_from(question).await;
+                        let st = st.clone();
                         let chat_sx = chat_sx.clone();
-                        chat::ask_ollama(st2, chat_sx).await;
-                    });
+                        glib::spawn_future_local(async move {
+                            chat::ask(st, chat_sx).await;
+                        });
+                    }
+                    MsgAction::Delete(id) => {
+                        st.remove_message(id).await;
+                    }
                 }
-                Some(AiChat::Grok) | Some(AiChat::ChatGPT) | Some(AiChat::Deepseek) => {
-                    glib::spawn_future_local(async move {
-                        let chat_sx = chat_sx.clone();
-                        if let Err(e) = chat::ask_chat(st2, chat_sx).await {
-                            error!("Error asking online chat: {}", e.to_string());
+            }
+        }
+    ));
+
+    let search_view = search_view::SearchView::new(&window, side_sx.clone());
+    let st = ctx.clone();
+    glib::spawn_future_local(glib::clone!(
+        #[weak]
+        window,
+        #[weak]
+        ai_sel,
+        #[weak]
+        language_sel,
+        async move {
+            st.refresh_sidebar().await;
+            while let Ok(action) = side_rx.recv().await {
+                debug!("Sidebar action: {:?}", action);
+                match action {
+                    SideAction::New => st.new_conversation().await,
+                    SideAction::Search => search_view.present(),
+                    SideAction::Open(_) | SideAction::Jump(_, _) => {
+                        let (id, msg) = match &action {
+                            SideAction::Jump(id, msg) => (id.clone(), Some(*msg)),
+                            SideAction::Open(id) => (id.clone(), None),
+                            _ => continue,
+                        };
+                        if st.conv.lock().await.id != id {
+                            match st.open_conversation(id.as_str()).await {
+                                Ok((ai, lang)) => {
+                                    if let Some(i) = ai.and_then(|a| AiChat::ALL.iter().position(|x| *x == a)) {
+                                        ai_sel.set_selected(i as u32);
+                                    }
+                                    if let Some(i) = lang.and_then(|l| Language::ALL.iter().position(|x| *x == l)) {
+                                        language_sel.set_selected(i as u32);
+                                    }
+                                }
+                                Err(e) => st.notifier.report(AppError::Storage(format!("Cannot open the conversation: {:#}", e))),
+                            }
                         }
-                    });
-                }
-                None => {
-                    error!("No chat selected");
+                        if let Some(msg) = msg {
+                            st.reveal(msg).await;
+                            st.ui.lock().await.chat.scroll_to(msg);
+                        }
+                    }
+                    SideAction::Rename(id, title) => {
+                        crate::report_err!(st.rename_conversation(id.as_str(), title.as_str()).await);
+                    }
+                    SideAction::Delete(id) => {
+                        crate::report_err!(st.delete_conversation(id.as_str()).await);
+                    }
+                    SideAction::Export(id, fmt) => {
+                        let c = match store::load(id.as_str()) {
+                            Ok(c) => c,
+                            Err(e) => {
+                                st.notifier.report(AppError::Storage(format!("Cannot load the conversation: {:#}", e)));
+                                continue;
+                            }
+                        };
+                        let dialog = gtk::FileDialog::builder()
+                            .title("Export conversation")
+                            .initial_name(export::file_name(&c, fmt))
+                            .build();
+                        if let Ok(Some(path)) = dialog.save_future(Some(&window)).await.map(|f| f.path()) {
+                            if let Err(e) = export::export(&c, fmt, path.as_path()) {
+                                st.notifier.report(AppError::Storage(format!("Export failed: {:#}", e)));
+                            }
+                        }
+                    }
+                    SideAction::ExportAll(fmt) => {
+                        let dialog = gtk::FileDialog::builder()
+                            .title("Export all conversations")
+                            .build();
+                        if let Ok(Some(path)) = dialog.select_folder_future(Some(&window)).await.map(|f| f.path()) {
+                            match export::export_all(path.as_path(), fmt) {
+                                Ok(n) => info!("Exported {} conversations", n),
+                                Err(e) => st.notifier.report(AppError::Storage(format!("Export failed: {:#}", e))),
+                            }
+                        }
+                    }
+                    SideAction::Import => {
+                        let filter = gtk::FileFilter::new();
+                        filter.set_name(Some("JSON export"));
+                        filter.add_suffix("json");
+                        let dialog = gtk::FileDialog::builder()
+                            .title("Import conversations")
+                            .default_filter(&filter)
+                            .build();
+                        if let Ok(Some(path)) = dialog.open_future(Some(&window)).await.map(|f| f.path()) {
+                            match tokio::task::spawn_blocking(move || import::import(path.as_path())).await {
+                                Ok(Ok(n)) => info!("Imported {} conversations", n),
+                                Ok(Err(e)) => st.notifier.report(AppError::Storage(format!("Import failed: {:#}", e))),
+                                Err(e) => error!("Import task error: {}", e.to_string()),
+                            }
+                            st.refresh_sidebar().await;
+                        }
+                    }
                 }
             }
-        });
-    });
+        }
+    ));
 
+    let paned = gtk::Paned::builder()
+        .orientation(gtk::Orientation::Horizontal)
+        .start_child(&s_sidebar)
+        .end_child(&vbox)
+        .position(230)
+        .build();
        
-    window.set_child(Some(&vbox));
+    window.set_child(Some(&paned));
     window.present();
     window.connect_close_request(move |_| {
         let st = ctx.clone();
@@ -319,19 +714,36 @@ async fn main() -> glib::ExitCode {
     tracing_subscriber::fmt()
         .with_thread_names(true)
         .with_max_level(tracing::Level::DEBUG)
+        .with_writer(secrets::Redacting)
         .init();
 
     #[cfg(not(debug_assertions))]
     tracing_subscriber::fmt()
         .with_thread_names(true)
         .with_max_level(tracing::Level::INFO)
+        .with_writer(secrets::Redacting)
         .init();
 
     let app = Application::builder()
         .application_id(APP_ID)
         .build();
     app.connect_activate(build_ui);
-    app.run()
+    // --config FILE is taken here, the rest is left to GTK
+    let mut args = vec![];
+    let mut it = std::env::args();
+    while let Some(a) = it.next() {
+        if a == "--config" {
+            match it.next() {
+                Some(p) => config::set_path(p.into()),
+                None => error!("--config needs a file"),
+            }
+        } else if let Some(p) = a.strip_prefix("--config=") {
+            config::set_path(p.into());
+        } else {
+            args.push(a);
+        }
+    }
+    app.run_with_args(&args)
 }
 
 fn connect_text_buffer_to_button<'a>(text_view: &TextView, button: &'a Button) {
@@ -0,0 +1,194 @@
+use std::process::Stdio;
+use std::time::Duration;
+use anyhow::{Result, anyhow};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Mutex;
+use crate::config::McpServer;
+use tracing::{debug, info};
+
+const PROTOCOL_VERSION: &str = "2025-03-26";
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+// Tool reported by tools/list
+#[derive(Clone, Debug)]
+pub struct McpTool {
+    pub name: String,
+    pub description: String,
+    pub input_schema: Value,
+    // Only tools marked read only can run without asking
+    pub read_only: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct McpResource {
+    pub uri: String,
+    pub name: String,
+    pub description: String,
+}
+
+struct Io {
+    stdin: ChildStdin,
+    lines: Lines<BufReader<ChildStdout>>,
+    next_id: u64,
+}
+
+// Running server, requests are sent one at a time as JSON lines
+pub struct McpClient {
+    pub name: String,
+    pub tools: Vec<McpTool>,
+    pub resources: Vec<McpResource>,
+    io: Mutex<Io>,
+    _child: Child,
+}
+
+impl Io {
+    async fn send(&mut self, msg: &Value) -> Result<()> {
+        let mut line = serde_json::to_string(msg)?;
+        line.push('\n');
+        self.stdin.write_all(line.as_bytes()).await?;
+        self.stdin.flush().await?;
+        Ok(())
+    }
+
+    // Reads until the response with the id, notifications are skipped
+    // and requests from the server are answered
+    async fn response(&mut self, id: u64) -> Result<Value> {
+        loop {
+            let line = self.lines.next_line().await?.ok_or(anyhow!("Server closed the connection"))?;
+            let msg: Value = match serde_json::from_str(line.as_str()) {
+                Ok(v) => v,
+                Err(_) => {
+                    debug!("Not JSON-RPC: {}", line);
+                    continue;
+                }
+            };
+            if msg["method"].is_string() {
+                if !msg["id"].is_null() {
+                    let reply = if msg["method"] == "ping" {
+                        json!({ "jsonrpc": "2.0", "id": msg["id"], "result": {} })
+                    } else {
+                        json!({ "jsonrpc": "2.0", "id": msg["id"], "error": { "code": -32601, "message": "Method not found" } })
+                    };
+                    self.send(&reply).await?;
+                }
+                continue;
+            }
+            if msg["id"].as_u64() != Some(id) {
+                continue;
+            }
+            if !msg["error"].is_null() {
+                return Err(anyhow!("{}", msg["error"]["message"].as_str().unwrap_or("MCP error")));
+            }
+            return Ok(msg["result"].clone());
+        }
+    }
+
+    async fn request(&mut self, method: &str, params: Value) -> Result<Value> {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.send(&json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })).await?;
+        tokio::time::timeout(TIMEOUT, self.response(id)).await
+            .map_err(|_| anyhow!("{} timed out", method))?
+    }
+
+    // All pages of a list request
+    async fn list(&mut self, method: &str, key: &str) -> Result<Vec<Value>> {
+        let mut res = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match &cursor {
+                Some(c) => json!({ "cursor": c }),
+                None => json!({}),
+            };
+            let page = self.request(method, params).await?;
+            res.extend(page[key].as_array().cloned().unwrap_or_default());
+            cursor = page["nextCursor"].as_str().map(|c| c.to_string());
+            if cursor.is_none() {
+                return Ok(res);
+            }
+        }
+    }
+}
+
+// Text parts of a tool result or a resource
+fn text_of(items: &Value) -> String {
+    items.as_array()
+        .map(|a| a.iter()
+            .map(|c| match c["text"].as_str() {
+                Some(t) => t.to_string(),
+                None => format!("[{} content]", c["type"].as_str().or(c["mimeType"].as_str()).unwrap_or("binary")),
+            })
+            .collect::<Vec<_>>()
+            .join("\n"))
+        .unwrap_or_default()
+}
+
+impl McpClient {
+    // Starts the server, initializes the session and lists what it offers
+    pub async fn start(conf: &McpServer) -> Result<McpClient> {
+        info!("Starting MCP server {}: {} {}", conf.name, conf.command, conf.args.join(" "));
+        let mut child = Command::new(conf.command.as_str())
+            .args(conf.args.iter())
+            .envs(conf.env.iter())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take().ok_or(anyhow!("No stdin"))?;
+        let stdout = child.stdout.take().ok_or(anyhow!("No stdout"))?;
+        let mut io = Io { stdin, lines: BufReader::new(stdout).lines(), next_id: 0 };
+
+        let init = io.request("initialize", json!({
+            "protocolVersion": PROTOCOL_VERSION,
+            "capabilities": {},
+            "clientInfo": { "name": "gchatter", "version": env!("CARGO_PKG_VERSION") },
+        })).await?;
+        io.send(&json!({ "jsonrpc": "2.0", "method": "notifications/initialized" })).await?;
+
+        let tools = if init["capabilities"]["tools"].is_null() {
+            vec![]
+        } else {
+            io.list("tools/list", "tools").await?
+                .iter()
+                .filter_map(|t| Some(McpTool {
+                    name: t["name"].as_str()?.to_string(),
+                    description: t["description"].as_str().unwrap_or("").to_string(),
+                    input_schema: t["inputSchema"].clone(),
+                    read_only: t["annotations"]["readOnlyHint"].as_bool().unwrap_or(false)
+                        || conf.read_only.iter().any(|r| t["name"] == r.as_str()),
+                }))
+                .collect()
+        };
+        let resources = if init["capabilities"]["resources"].is_null() {
+            vec![]
+        } else {
+            io.list("resources/list", "resources").await?
+                .iter()
+                .filter_map(|r| Some(McpResource {
+                    uri: r["uri"].as_str()?.to_string(),
+                    name: r["name"].as_str().unwrap_or("").to_string(),
+                    description: r["description"].as_str().unwrap_or("").to_string(),
+                }))
+                .collect()
+        };
+        info!("MCP server {} has {} tools and {} resources", conf.name, tools.len(), resources.len());
+        Ok(McpClient { name: conf.name.clone(), tools, resources, io: Mutex::new(io), _child: child })
+    }
+
+    pub async fn call_tool(&self, name: &str, arguments: &Value) -> Result<String> {
+        let res = self.io.lock().await.request("tools/call", json!({ "name": name, "arguments": arguments })).await?;
+        let text = text_of(&res["content"]);
+        if res["isError"].as_bool().unwrap_or(false) {
+            return Err(anyhow!("{}", text));
+        }
+        Ok(text)
+    }
+
+    pub async fn read_resource(&self, uri: &str) -> Result<String> {
+        let res = self.io.lock().await.request("resources/read", json!({ "uri": uri })).await?;
+        Ok(text_of(&res["contents"]))
+    }
+}
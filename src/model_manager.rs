@@ -0,0 +1,326 @@
+use anyhow::{Result, anyhow};
+use gtk::prelude::*;
+use gtk::{glib, Align, Box, Button, Entry, Label, ListBox, ListBoxRow, Orientation, ProgressBar, ScrolledWindow, TextView, Window};
+use ollama_rs::Ollama;
+use serde_json::Value;
+use tokio_stream::StreamExt;
+use crate::errors::AppError;
+use crate::models::{self, ollama_base};
+use crate::notify::Notifier;
+use tracing::{debug, error, info};
+
+// Ollama model manager window: pull, delete, show info and loaded models
+#[derive(Clone)]
+pub struct ModelManager {
+    window: Window,
+    list: ListBox,
+    loaded: Label,
+    info: TextView,
+    entry: Entry,
+    progress: ProgressBar,
+    status: Label,
+    url: String,
+    port: u16,
+    notifier: Notifier,
+}
+
+// Models currently loaded in memory, Ollama /api/ps
+pub async fn running(url: &str, port: u16) -> Result<Vec<String>> {
+    let v: Value = reqwest::get(format!("{}/api/ps", ollama_base(url, port)))
+        .await?
+        .error_for_status()?
+        .json().await?;
+    Ok(v["models"].as_array()
+        .ok_or(anyhow!("Invalid /api/ps response"))?
+        .iter()
+        .filter_map(|m| {
+            let name = m["name"].as_str()?;
+            Some(match m["size_vram"].as_u64() {
+                Some(v) => format!("{} ({:.1} GB VRAM)", name, v as f64 / 1e9),
+                None => name.to_string(),
+            })
+        })
+        .collect())
+}
+
+// Ollama accepts names without the ":latest" tag
+pub async fn is_pulled(ollama: &Ollama, model: &str) -> bool {
+    match ollama.list_local_models().await {
+        Ok(models) => models.iter().any(|m| {
+            m.name == model || m.name.strip_suffix(":latest") == Some(model)
+        }),
+        Err(e) => {
+            error!("Cannot list Ollama models: {}", e.to_string());
+            // Can't tell, don't offer pulling
+            true
+        }
+    }
+}
+
+impl ModelManager {
+    pub fn new(parent: &impl IsA<gtk::Window>, url: &str, port: u16, notifier: Notifier) -> Self {
+        let list = ListBox::builder()
+            .selection_mode(gtk::SelectionMode::None)
+            .build();
+        let s_list = ScrolledWindow::builder()
+            .child(&list)
+            .min_content_height(200)
+            .vexpand(true)
+            .build();
+
+        let loaded = Label::builder()
+            .halign(Align::Start)
+            .wrap(true)
+            .margin_top(5)
+            .build();
+
+        let info = TextView::builder()
+            .editable(false)
+            .monospace(true)
+            .wrap_mode(gtk::WrapMode::WordChar)
+            .build();
+        let s_info = ScrolledWindow::builder()
+            .child(&info)
+            .min_content_height(200)
+            .vexpand(true)
+            .margin_top(5)
+            .build();
+
+        let entry = Entry::builder()
+            .placeholder_text("model to pull, e.g. gemma3:4b")
+            .hexpand(true)
+            .build();
+        let idc_pull = Button::builder()
+            .label("Pull")
+            .margin_start(5)
+            .build();
+        let idc_refresh = Button::builder()
+            .icon_name("view-refresh-symbolic")
+            .tooltip_text("Refresh")
+            .margin_start(5)
+            .build();
+        let pull_row = Box::builder()
+            .orientation(Orientation::Horizontal)
+            .margin_top(5)
+            .build();
+        pull_row.append(&entry);
+        pull_row.append(&idc_pull);
+        pull_row.append(&idc_refresh);
+
+        let progress = ProgressBar::builder()
+            .show_text(true)
+            .margin_top(5)
+            .build();
+        let status = Label::builder()
+            .halign(Align::Start)
+            .build();
+
+        let root = Box::builder()
+            .orientation(Orientation::Vertical)
+            .margin_top(5)
+            .margin_start(5)
+            .margin_end(5)
+            .margin_bottom(5)
+            .build();
+        root.append(&s_list);
+        root.append(&loaded);
+        root.append(&pull_row);
+        root.append(&progress);
+        root.append(&status);
+        root.append(&s_info);
+
+        let window = Window::builder()
+            .title("Ollama models")
+            .transient_for(parent)
+            .default_width(600)
+            .default_height(650)
+            .hide_on_close(true)
+            .child(&root)
+            .build();
+
+        let mm = Self {
+            window, list, loaded, info, entry, progress, status,
+            url: url.to_string(),
+            port,
+            notifier,
+        };
+
+        let m = mm.clone();
+        idc_pull.connect_clicked(move |_| {
+            let m = m.clone();
+            let name = m.entry.text().trim().to_string();
+            if name.is_empty() {
+                return;
+            }
+            glib::spawn_future_local(async move {
+                if let Err(e) = m.pull(name.as_str()).await {
+                    m.failed(format!("Pulling {} failed", name).as_str(), e);
+                }
+            });
+        });
+        let m = mm.clone();
+        idc_refresh.connect_clicked(move |_| {
+            let m = m.clone();
+            glib::spawn_future_local(async move {
+                m.refresh().await;
+            });
+        });
+        mm
+    }
+
+    // Short note in the window, the details go to the notification area
+    fn failed(&self, what: &str, e: anyhow::Error) {
+        self.status.set_text(what);
+        self.notifier.report(AppError::request(&e.context(what.to_string())));
+    }
+
+    fn ollama(&self) -> Ollama {
+        Ollama::new(self.url.as_str(), self.port)
+    }
+
+    pub async fn present(&self) {
+        self.window.present();
+        self.refresh().await;
+    }
+
+    fn row(&self, m: &models::ModelEntry) -> ListBoxRow {
+        let label = Label::builder()
+            .label(m.label().as_str())
+            .halign(Align::Start)
+            .hexpand(true)
+            .ellipsize(gtk::pango::EllipsizeMode::End)
+            .build();
+        let idc_info = Button::builder()
+            .icon_name("dialog-information-symbolic")
+            .tooltip_text("Info and modelfile")
+            .has_frame(false)
+            .build();
+        let idc_delete = Button::builder()
+            .icon_name("user-trash-symbolic")
+            .tooltip_text("Delete")
+            .has_frame(false)
+            .build();
+
+        let mm = self.clone();
+        let name = m.name.clone();
+        idc_info.connect_clicked(move |_| {
+            let mm = mm.clone();
+            let name = name.clone();
+            glib::spawn_future_local(async move {
+                mm.show_info(name.as_str()).await;
+            });
+        });
+        let mm = self.clone();
+        let name = m.name.clone();
+        idc_delete.connect_clicked(move |_| {
+            let mm = mm.clone();
+            let name = name.clone();
+            glib::spawn_future_local(async move {
+                let dialog = gtk::AlertDialog::builder()
+                    .message(format!("Delete {}?", name))
+                    .buttons(["Cancel", "Delete"])
+                    .cancel_button(0)
+                    .default_button(0)
+                    .build();
+                if !dialog.choose_future(Some(&mm.window)).await.map(|i| i == 1).unwrap_or(false) {
+                    return;
+                }
+                match mm.ollama().delete_model(name.clone()).await {
+                    Ok(_) => info!("Deleted {}", name),
+                    Err(e) => mm.failed(format!("Cannot delete {}", name).as_str(), e.into()),
+                }
+                mm.refresh().await;
+            });
+        });
+
+        let b = Box::builder()
+            .orientation(Orientation::Horizontal)
+            .build();
+        b.append(&label);
+        b.append(&idc_info);
+        b.append(&idc_delete);
+        ListBoxRow::builder()
+            .child(&b)
+            .build()
+    }
+
+    pub async fn refresh(&self) {
+        self.list.remove_all();
+        match models::list_ollama(self.url.as_str(), self.port).await {
+            Ok(models) => {
+                for m in models.iter() {
+                    self.list.append(&self.row(m));
+                }
+            }
+            Err(e) => self.failed("Cannot reach Ollama", e),
+        }
+        match running(self.url.as_str(), self.port).await {
+            Ok(r) if r.is_empty() => self.loaded.set_text("Loaded: none"),
+            Ok(r) => self.loaded.set_text(format!("Loaded: {}", r.join(", ")).as_str()),
+            Err(e) => error!("Cannot list loaded models: {}", e.to_string()),
+        }
+    }
+
+    pub async fn show_info(&self, name: &str) {
+        let buffer = self.info.buffer();
+        match self.ollama().show_model_info(name.to_string()).await {
+            Ok(i) => {
+                let details = i.model_info.iter()
+                    .map(|(k, v)| format!("{}: {}", k, v))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                buffer.set_text(format!("# {}\n\nCapabilities: {}\n\n## Parameters\n{}\n\n## Details\n{}\n\n## Template\n{}\n\n## Modelfile\n{}\n\n## License\n{}",
+                    name, i.capabilities.join(", "), i.parameters, details, i.template, i.modelfile, i.license).as_str());
+            }
+            Err(e) => {
+                buffer.set_text(format!("Cannot get info for {}", name).as_str());
+                error!("Cannot get model info: {}", e.to_string());
+            }
+        }
+    }
+
+    // Pulls the model, the progress bar shows the current layer
+    pub async fn pull(&self, name: &str) -> Result<()> {
+        self.window.present();
+        self.entry.set_text(name);
+        self.progress.set_fraction(0.0);
+        let mut stream = self.ollama().pull_model_stream(name.to_string(), false).await?;
+        while let Some(res) = stream.next().await {
+            let s = res?;
+            debug!("Pull: {:?}", s);
+            if let (Some(total), Some(done)) = (s.total, s.completed) {
+                if total > 0 {
+                    self.progress.set_fraction(done as f64 / total as f64);
+                }
+            }
+            self.progress.set_text(Some(s.message.as_str()));
+        }
+        self.progress.set_fraction(1.0);
+        self.status.set_text(format!("Pulled {}", name).as_str());
+        info!("Pulled {}", name);
+        self.refresh().await;
+        Ok(())
+    }
+
+    // Asks the user to pull a missing model, true if it got pulled
+    pub async fn offer_pull(&self, name: &str) -> bool {
+        let dialog = gtk::AlertDialog::builder()
+            .message(format!("Model {} is not available in Ollama", name))
+            .detail("Do you want to pull it now?")
+            .buttons(["Cancel", "Pull"])
+            .cancel_button(0)
+            .default_button(1)
+            .build();
+        let parent = self.window.transient_for();
+        if !dialog.choose_future(parent.as_ref()).await.map(|i| i == 1).unwrap_or(false) {
+            return false;
+        }
+        match self.pull(name).await {
+            Ok(_) => true,
+            Err(e) => {
+                self.failed(format!("Pulling {} failed", name).as_str(), e);
+                false
+            }
+        }
+    }
+}
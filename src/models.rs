@@ -0,0 +1,173 @@
+use anyhow::{Result, anyhow};
+use gtk::prelude::*;
+use gtk::{glib, DropDown, StringList};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use crate::config::AiApi;
+use crate::context::Context;
+use crate::errors::AppError;
+use crate::AiChat;
+use tracing::debug;
+
+// Model reported by the backend, details are filled where the API reports them
+#[derive(Clone, Debug, Default)]
+pub struct ModelEntry {
+    pub name: String,
+    pub size: Option<u64>,
+    pub parameters: Option<String>,
+    pub quantization: Option<String>,
+    pub context: Option<u64>,
+}
+
+impl ModelEntry {
+    pub fn label(&self) -> String {
+        let mut details = vec![];
+        if let Some(p) = &self.parameters {
+            details.push(p.clone());
+        }
+        if let Some(s) = self.size {
+            details.push(format!("{:.1} GB", s as f64 / 1e9));
+        }
+        if let Some(q) = &self.quantization {
+            details.push(q.clone());
+        }
+        if let Some(c) = self.context {
+            details.push(format!("{}k ctx", c / 1024));
+        }
+        if details.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{} ({})", self.name, details.join(", "))
+        }
+    }
+}
+
+pub fn ollama_base(url: &str, port: u16) -> String {
+    format!("{}:{}", url.trim_end_matches('/'), port)
+}
+
+// Context length is in model_info under "<architecture>.context_length"
+pub async fn ollama_context_length(client: &reqwest::Client, base: &str, name: &str) -> Option<u64> {
+    let v: Value = client.post(format!("{}/api/show", base))
+        .json(&serde_json::json!({ "model": name }))
+        .send().await.ok()?
+        .json().await.ok()?;
+    v["model_info"].as_object()?
+        .iter()
+        .find(|(k, _)| k.ends_with(".context_length"))
+        .and_then(|(_, v)| v.as_u64())
+}
+
+// Ollama /api/tags
+pub async fn list_ollama(url: &str, port: u16) -> Result<Vec<ModelEntry>> {
+    let base = ollama_base(url, port);
+    let client = reqwest::Client::new();
+    let v: Value = client.get(format!("{}/api/tags", base))
+        .send().await?
+        .error_for_status()?
+        .json().await?;
+    let mut res = v["models"].as_array()
+        .ok_or(anyhow!("Invalid /api/tags response"))?
+        .iter()
+        .filter_map(|m| Some(ModelEntry {
+            name: m["name"].as_str()?.to_string(),
+            size: m["size"].as_u64(),
+            parameters: m["details"]["parameter_size"].as_str().map(|s| s.to_string()),
+            quantization: m["details"]["quantization_level"].as_str().map(|s| s.to_string()),
+            context: None,
+        }))
+        .collect::<Vec<_>>();
+
+    let ctx = futures::future::join_all(res.iter()
+        .map(|m| ollama_context_length(&client, base.as_str(), m.name.as_str()))).await;
+    for (m, c) in res.iter_mut().zip(ctx) {
+        m.context = c;
+    }
+    res.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(res)
+}
+
+// OpenAI compatible /models
+pub async fn list_openai(api: &AiApi) -> Result<Vec<ModelEntry>> {
+    let url = format!("{}/models", api.url.trim_end_matches('/'));
+    let v: Value = reqwest::Client::new()
+        .get(url)
+        .bearer_auth(api.key.as_str())
+        .send().await?
+        .error_for_status()?
+        .json().await?;
+    let mut res = v["data"].as_array()
+        .ok_or(anyhow!("Invalid /models response"))?
+        .iter()
+        .filter_map(|m| Some(ModelEntry {
+            name: m["id"].as_str()?.to_string(),
+            // Not part of the OpenAI API, but some compatible ones report it
+            context: m["context_length"].as_u64().or(m["context_window"].as_u64()),
+            ..Default::default()
+        }))
+        .collect::<Vec<_>>();
+    res.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(res)
+}
+
+pub async fn list(ctx: &Context, ai: AiChat) -> Result<Vec<ModelEntry>> {
+    match ai {
+        AiChat::Ollama => list_ollama(ctx.conf.ollama_url.as_str(), ctx.conf.ollama_port).await,
+        _ => match ctx.conf.api(ai) {
+            Some(api) => list_openai(api).await,
+            None => Err(anyhow!("{} is not configured", ai)),
+        },
+    }
+}
+
+// Drop down with the models of the selected chat, the first item keeps the configured model
+pub struct ModelSelector {
+    dd: DropDown,
+    options: StringList,
+    names: Rc<RefCell<Vec<String>>>,
+}
+
+impl ModelSelector {
+    pub fn new(ctx: Arc<Context>) -> Self {
+        let options = StringList::new(&["Default model"]);
+        let dd = DropDown::builder()
+            .model(&options)
+            .margin_start(5)
+            .width_request(200)
+            .build();
+        let names: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(vec![]));
+        let n = names.clone();
+        dd.connect_selected_item_notify(move |r| {
+            let sel = r.selected() as usize;
+            let model = if sel == 0 { None } else { n.borrow().get(sel - 1).cloned() };
+            debug!("Selected model: {:?}", model);
+            let ctx = ctx.clone();
+            glib::spawn_future_local(async move {
+                *ctx.model.lock().await = model;
+            });
+        });
+        Self { dd, options, names }
+    }
+
+    pub fn widget(&self) -> &DropDown {
+        &self.dd
+    }
+
+    // Lists the models of the chat, the selection goes back to the default
+    pub async fn refresh(&self, ctx: &Context, ai: AiChat) {
+        self.dd.set_selected(0);
+        self.options.splice(1, self.options.n_items() - 1, &[]);
+        self.names.borrow_mut().clear();
+        match list(ctx, ai).await {
+            Ok(models) => {
+                for m in models.iter() {
+                    self.options.append(m.label().as_str());
+                }
+                *self.names.borrow_mut() = models.into_iter().map(|m| m.name).collect();
+            }
+            Err(e) => ctx.notifier.report(AppError::request(&e.context(format!("Cannot list {} models", ai)))),
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use async_channel::{Receiver, Sender};
+use gtk::prelude::*;
+use gtk::{glib, Align, Box, Button, Label, Orientation};
+use crate::errors::AppError;
+use tracing::error;
+
+// Seconds a notification stays without being closed
+const SHOW_FOR: u32 = 15;
+
+// Reports failures to the notification area, can be used from any thread
+#[derive(Clone)]
+pub struct Notifier {
+    sx: Sender<AppError>,
+}
+
+impl Notifier {
+    pub fn report(&self, e: AppError) {
+        error!("{}", e);
+        if let Err(e) = self.sx.try_send(e) {
+            error!("Cannot show the notification: {}", e.to_string());
+        }
+    }
+}
+
+pub fn channel() -> (Notifier, Receiver<AppError>) {
+    let (sx, rx) = async_channel::unbounded();
+    (Notifier { sx }, rx)
+}
+
+fn row(e: &AppError) -> Box {
+    let label = Label::builder()
+        .halign(Align::Start)
+        .hexpand(true)
+        .wrap(true)
+        .build();
+    label.set_markup(format!("<b>{}</b>  {}",
+        glib::markup_escape_text(e.title()),
+        glib::markup_escape_text(e.hint())).as_str());
+    let idc_close = Button::builder()
+        .icon_name("window-close-symbolic")
+        .has_frame(false)
+        .valign(Align::Center)
+        .build();
+    let row = Box::builder()
+        .orientation(Orientation::Horizontal)
+        .css_classes(["error"])
+        .tooltip_text(e.detail())
+        .build();
+    row.append(&label);
+    row.append(&idc_close);
+    idc_close.connect_clicked(glib::clone!(
+        #[weak]
+        row,
+        move |_| {
+            if let Some(p) = row.parent().and_downcast::<Box>() {
+                p.remove(&row);
+            }
+        }
+    ));
+    row
+}
+
+// Area above the chat, the same failure is shown once while it is visible
+pub fn area(rx: Receiver<AppError>) -> Box {
+    let root = Box::builder()
+        .orientation(Orientation::Vertical)
+        .spacing(2)
+        .margin_start(5)
+        .margin_end(5)
+        .build();
+    let r = root.clone();
+    glib::spawn_future_local(async move {
+        while let Ok(e) = rx.recv().await {
+            let mut child = r.first_child();
+            let mut shown = false;
+            while let Some(c) = child {
+                shown |= c.tooltip_text().as_deref() == Some(e.detail());
+                child = c.next_sibling();
+            }
+            if shown {
+                continue;
+            }
+            let row = row(&e);
+            r.append(&row);
+            glib::timeout_add_seconds_local_once(SHOW_FOR, glib::clone!(
+                #[weak]
+                r,
+                #[weak]
+                row,
+                move || {
+                    if row.parent().is_some() {
+                        r.remove(&row);
+                    }
+                }
+            ));
+        }
+    });
+    root
+}
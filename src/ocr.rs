@@ -0,0 +1,227 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use anyhow::{Result, anyhow};
+use gtk::prelude::*;
+use gtk::{glib, Box, Button, MenuButton, Orientation, Popover, ScrolledWindow, TextView, Window};
+use crate::config::Config;
+use crate::context::Context;
+use crate::Language;
+use crate::errors::AppError;
+use tracing::debug;
+
+// Tesseract traineddata names
+fn tesseract_lang(lang: Language) -> &'static str {
+    match lang {
+        Language::EN => "eng",
+        Language::PL => "pol",
+        Language::CN => "chi_sim",
+        Language::DE => "deu",
+        Language::FR => "fra",
+        Language::ES => "spa",
+        Language::RU => "rus",
+        Language::TR => "tur",
+        Language::JP => "jpn",
+    }
+}
+
+#[cfg(feature = "leptess")]
+fn recognize_tesseract(conf: &Config, path: &Path, lang: Language) -> Result<String> {
+    let mut lt = leptess::LepTess::new(conf.tessdata.as_deref(), tesseract_lang(lang))?;
+    lt.set_image(path)?;
+    Ok(lt.get_utf8_text()?)
+}
+
+// PaddleOCR-json config files shipped in its models dir
+#[cfg(feature = "paddleocr")]
+fn paddle_config(lang: Language) -> &'static str {
+    match lang {
+        Language::CN => "models/config_chinese.txt",
+        Language::JP => "models/config_japan.txt",
+        Language::RU => "models/config_cyrillic.txt",
+        _ => "models/config_en.txt",
+    }
+}
+
+// Runs PaddleOCR-json, it prints {"code": 100, "data": [{"text": ...}]} for the image
+#[cfg(feature = "paddleocr")]
+fn recognize_paddle(conf: &Config, path: &Path, lang: Language) -> Result<String> {
+    let exe = PathBuf::from(conf.paddleocr_exe.as_ref().ok_or(anyhow!("paddleocr_exe is not set in the config"))?);
+    let out = std::process::Command::new(&exe)
+        .current_dir(exe.parent().unwrap_or(Path::new(".")))
+        .arg(format!("-image_path={}", path.display()))
+        .arg(format!("-config_path={}", paddle_config(lang)))
+        .output()?;
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let line = stdout.lines()
+        .find(|l| l.trim_start().starts_with('{'))
+        .ok_or(anyhow!("No output from PaddleOCR"))?;
+    let v: serde_json::Value = serde_json::from_str(line)?;
+    match v["code"].as_i64() {
+        Some(100) => Ok(v["data"].as_array()
+            .map(|a| a.iter()
+                .filter_map(|d| d["text"].as_str())
+                .collect::<Vec<_>>()
+                .join("\n"))
+            .unwrap_or_default()),
+        // No text found
+        Some(101) => Ok(String::new()),
+        _ => Err(anyhow!("PaddleOCR error: {}", v["data"])),
+    }
+}
+
+// With both backends PaddleOCR is used when its executable is configured, Tesseract otherwise
+#[cfg(all(feature = "leptess", feature = "paddleocr"))]
+pub fn recognize(conf: &Config, path: &Path, lang: Language) -> Result<String> {
+    if conf.paddleocr_exe.is_some() {
+        recognize_paddle(conf, path, lang)
+    } else {
+        recognize_tesseract(conf, path, lang)
+    }
+}
+
+#[cfg(all(feature = "leptess", not(feature = "paddleocr")))]
+pub fn recognize(conf: &Config, path: &Path, lang: Language) -> Result<String> {
+    recognize_tesseract(conf, path, lang)
+}
+
+#[cfg(all(feature = "paddleocr", not(feature = "leptess")))]
+pub fn recognize(conf: &Config, path: &Path, lang: Language) -> Result<String> {
+    recognize_paddle(conf, path, lang)
+}
+
+// Lets the user correct the text before it goes to the prompt
+fn preview(ctx: Arc<Context>, parent: Option<Window>, text: String) {
+    let view = TextView::builder()
+        .editable(true)
+        .wrap_mode(gtk::WrapMode::Word)
+        .build();
+    view.buffer().set_text(text.as_str());
+    let scroll = ScrolledWindow::builder()
+        .child(&view)
+        .vexpand(true)
+        .build();
+    let idc_insert = Button::builder()
+        .label("Insert")
+        .build();
+    let idc_cancel = Button::builder()
+        .label("Cancel")
+        .margin_start(5)
+        .build();
+    let buttons = Box::builder()
+        .orientation(Orientation::Horizontal)
+        .halign(gtk::Align::End)
+        .margin_top(5)
+        .build();
+    buttons.append(&idc_insert);
+    buttons.append(&idc_cancel);
+    let root = Box::builder()
+        .orientation(Orientation::Vertical)
+        .margin_top(5)
+        .margin_start(5)
+        .margin_end(5)
+        .margin_bottom(5)
+        .build();
+    root.append(&scroll);
+    root.append(&buttons);
+
+    let window = Window::builder()
+        .title("Recognized text")
+        .default_width(500)
+        .default_height(400)
+        .modal(true)
+        .child(&root)
+        .build();
+    window.set_transient_for(parent.as_ref());
+
+    let w = window.clone();
+    idc_insert.connect_clicked(move |_| {
+        let buffer = view.buffer();
+        let text = crate::get_text!(buffer).to_string();
+        let ctx = ctx.clone();
+        glib::spawn_future_local(async move {
+            ctx.ui.lock().await.append_text(text.as_str());
+        });
+        w.close();
+    });
+    let w = window.clone();
+    idc_cancel.connect_clicked(move |_| w.close());
+    window.present();
+}
+
+async fn run(ctx: Arc<Context>, parent: Option<Window>, path: PathBuf) {
+    let lang = ctx.language.lock().await.unwrap_or(Language::EN);
+    debug!("OCR {} ({})", path.display(), tesseract_lang(lang));
+    let conf = ctx.conf.clone();
+    let res = tokio::task::spawn_blocking(move || recognize(&conf, path.as_path(), lang)).await;
+    match res {
+        Ok(Ok(text)) => preview(ctx, parent, text),
+        Ok(Err(e)) => ctx.notifier.report(AppError::Ocr(format!("{:#}", e))),
+        Err(e) => ctx.notifier.report(AppError::Ocr(e.to_string())),
+    }
+}
+
+// OCR menu next to the prompt: image file or clipboard image
+pub fn ocr_button(ctx: Arc<Context>) -> MenuButton {
+    let idc_file = Button::builder()
+        .label("From file")
+        .has_frame(false)
+        .build();
+    let idc_clip = Button::builder()
+        .label("From clipboard")
+        .has_frame(false)
+        .build();
+    let b = Box::builder()
+        .orientation(Orientation::Vertical)
+        .build();
+    b.append(&idc_file);
+    b.append(&idc_clip);
+    let popover = Popover::builder()
+        .child(&b)
+        .build();
+
+    let st = ctx.clone();
+    let p = popover.clone();
+    idc_file.connect_clicked(move |b| {
+        p.popdown();
+        let st = st.clone();
+        let window = b.root().and_downcast::<Window>();
+        glib::spawn_future_local(async move {
+            let filter = gtk::FileFilter::new();
+            filter.set_name(Some("Images"));
+            filter.add_pixbuf_formats();
+            let dialog = gtk::FileDialog::builder()
+                .title("OCR image")
+                .default_filter(&filter)
+                .build();
+            if let Ok(Some(path)) = dialog.open_future(window.as_ref()).await.map(|f| f.path()) {
+                run(st, window, path).await;
+            }
+        });
+    });
+
+    let st = ctx.clone();
+    let p = popover.clone();
+    idc_clip.connect_clicked(move |b| {
+        p.popdown();
+        let st = st.clone();
+        let window = b.root().and_downcast::<Window>();
+        let clipboard = b.clipboard();
+        glib::spawn_future_local(async move {
+            let path = match clipboard.read_texture_future().await {
+                Ok(Some(t)) => crate::images::store_bytes(&t.save_to_png_bytes(), "png"),
+                Ok(None) => Err(anyhow!("No image in the clipboard")),
+                Err(e) => Err(e.into()),
+            };
+            match path {
+                Ok(p) => run(st, window, p).await,
+                Err(e) => st.notifier.report(AppError::Ocr(format!("Cannot read the clipboard image: {:#}", e))),
+            }
+        });
+    });
+
+    MenuButton::builder()
+        .label("OCR")
+        .popover(&popover)
+        .margin_start(5)
+        .build()
+}
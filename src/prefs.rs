@@ -0,0 +1,300 @@
+use std::sync::Arc;
+use gtk::prelude::*;
+use gtk::{glib, Align, Box, Button, DropDown, Entry, Grid, Label, Notebook, Orientation, SpinButton, StringList, Window};
+use pv_recorder::PvRecorderBuilder;
+use crate::config::{self, AiApi, Config};
+use crate::context::Context;
+use tracing::info;
+
+const DEFAULT_DEVICE: &str = "Default";
+
+// Label and widget pairs laid out in two columns
+struct Page {
+    grid: Grid,
+    rows: i32,
+}
+
+impl Page {
+    fn new() -> Self {
+        let grid = Grid::builder()
+            .row_spacing(5)
+            .column_spacing(10)
+            .margin_top(10)
+            .margin_start(10)
+            .margin_end(10)
+            .margin_bottom(10)
+            .build();
+        Self { grid, rows: 0 }
+    }
+
+    fn heading(&mut self, text: &str) {
+        let l = Label::builder()
+            .halign(Align::Start)
+            .margin_top(if self.rows == 0 { 0 } else { 10 })
+            .build();
+        l.set_markup(format!("<b>{}</b>", glib::markup_escape_text(text)).as_str());
+        self.grid.attach(&l, 0, self.rows, 2, 1);
+        self.rows += 1;
+    }
+
+    fn add(&mut self, label: &str, w: &impl IsA<gtk::Widget>) {
+        let l = Label::builder()
+            .label(label)
+            .halign(Align::Start)
+            .build();
+        w.set_hexpand(true);
+        self.grid.attach(&l, 0, self.rows, 1, 1);
+        self.grid.attach(w, 1, self.rows, 1, 1);
+        self.rows += 1;
+    }
+
+    fn entry(&mut self, label: &str, text: &str) -> Entry {
+        let e = Entry::builder()
+            .text(text)
+            .build();
+        self.add(label, &e);
+        e
+    }
+
+    fn spin(&mut self, label: &str, value: f64, min: f64, max: f64, step: f64) -> SpinButton {
+        let s = SpinButton::with_range(min, max, step);
+        s.set_value(value);
+        self.add(label, &s);
+        s
+    }
+}
+
+// Key, URL and model of an online chat, all empty removes its section
+struct ApiFields {
+    name: String,
+    key: Entry,
+    url: Entry,
+    model: Entry,
+}
+
+impl ApiFields {
+    fn new(page: &mut Page, name: &str, api: Option<&AiApi>) -> Self {
+        page.heading(name);
+        let api = api.cloned().unwrap_or_default();
+        let key = page.entry("API key", api.key.as_str());
+        key.set_visibility(false);
+        if let Some(source) = key_source(&api) {
+            key.set_placeholder_text(Some(source.as_str()));
+        }
+        Self {
+            name: name.to_string(),
+            key,
+            url: page.entry("URL", api.url.as_str()),
+            model: page.entry("Model", api.model.as_str()),
+        }
+    }
+
+    // Sampling options set in the file are kept
+    fn apply(&self, api: Option<AiApi>, shadowed: &mut Vec<String>) -> Option<AiApi> {
+        let (key, url, model) = (text(&self.key), text(&self.url), text(&self.model));
+        if key.is_empty() && url.is_empty() && model.is_empty() {
+            return None;
+        }
+        let api = api.unwrap_or_default();
+        let key = typed_key(self.name.as_str(), key, &api, shadowed);
+        Some(AiApi { key, url, model, ..api })
+    }
+}
+
+// A typed key would be written in plain text and then ignored when key_env or key_cmd
+// is set, it is dropped and the user is told where the key comes from
+fn typed_key(name: &str, key: String, api: &AiApi, shadowed: &mut Vec<String>) -> String {
+    match key_source(api) {
+        Some(source) if !key.is_empty() && key != api.key => {
+            shadowed.push(format!("{} ({})", name, source));
+            api.key.clone()
+        }
+        _ => key,
+    }
+}
+
+fn text(e: &Entry) -> String {
+    e.text().trim().to_string()
+}
+
+// The file as it is now, the running config has the keys filled in
+// and is never written back
+fn current(ctx: &Context) -> Option<Config> {
+    config::load()
+        .map_err(|e| ctx.notifier.report(e))
+        .ok()
+}
+
+// Where the key comes from when it is not in the config
+fn key_source(api: &AiApi) -> Option<String> {
+    match (&api.key_env, &api.key_cmd) {
+        (Some(var), _) => Some(format!("from ${}", var)),
+        (None, Some(cmd)) => Some(format!("from `{}`", cmd)),
+        (None, None) => None,
+    }
+}
+
+fn present(ctx: Arc<Context>, parent: Option<Window>) {
+    let conf = match current(&ctx) {
+        Some(c) => c,
+        None => return,
+    };
+
+    let mut chats = Page::new();
+    chats.heading("Ollama");
+    let ollama_url = chats.entry("URL", conf.ollama_url.as_str());
+    let ollama_port = chats.spin("Port", conf.ollama_port as f64, 1.0, 65535.0, 1.0);
+    let ollama_model = chats.entry("Model", conf.ollama_model.as_str());
+    let gpt = ApiFields::new(&mut chats, "ChatGPT", conf.gpt.as_ref());
+    let deepseek = ApiFields::new(&mut chats, "Deepseek", conf.deepseek.as_ref());
+    let grok = ApiFields::new(&mut chats, "Grok", conf.grok.as_ref());
+
+    let mut voice = Page::new();
+    voice.heading("Voice input");
+    let devices = PvRecorderBuilder::new(512)
+        .get_available_devices()
+        .unwrap_or_default();
+    let names = StringList::new(&[DEFAULT_DEVICE]);
+    for d in devices.iter() {
+        names.append(d.as_str());
+    }
+    let device = DropDown::builder()
+        .model(&names)
+        .build();
+    if let Some(i) = conf.record_device.as_ref().and_then(|r| devices.iter().position(|d| d == r)) {
+        device.set_selected(i as u32 + 1);
+    }
+    voice.add("Record device", &device);
+    let whisper = voice.entry("Whisper model", conf.whisper_model.as_str());
+    let idc_browse = Button::builder()
+        .label("Browse")
+        .build();
+    voice.grid.attach(&idc_browse, 2, voice.rows - 1, 1, 1);
+    voice.heading("Reading answers (ElevenLabs)");
+    let eleven = conf.eleven.clone().unwrap_or_default();
+    let eleven_key = voice.entry("API key", eleven.key.as_str());
+    eleven_key.set_visibility(false);
+    if let Some(source) = key_source(&eleven) {
+        eleven_key.set_placeholder_text(Some(source.as_str()));
+    }
+    let eleven_voice = voice.entry("Voice id", eleven.model.as_str());
+
+    let mut ui = Page::new();
+    ui.heading("Window");
+    let width = ui.spin("Width", conf.w as f64, 300.0, 8000.0, 10.0);
+    let height = ui.spin("Height", conf.h as f64, 200.0, 8000.0, 10.0);
+    let font_size = ui.spin("Font size", conf.font_size as f64, 6.0, 48.0, 1.0);
+    let msg_wait = ui.spin("Answer refresh (ms)", conf.chat_msg_wait as f64, 0.0, 5000.0, 50.0);
+
+    let pages = Notebook::new();
+    pages.append_page(&chats.grid, Some(&Label::new(Some("Chats"))));
+    pages.append_page(&voice.grid, Some(&Label::new(Some("Voice"))));
+    pages.append_page(&ui.grid, Some(&Label::new(Some("Interface"))));
+
+    let status = Label::builder()
+        .halign(Align::Start)
+        .hexpand(true)
+        .wrap(true)
+        .build();
+    let idc_save = Button::builder()
+        .label("Save")
+        .build();
+    let idc_close = Button::builder()
+        .label("Close")
+        .margin_start(5)
+        .build();
+    let buttons = Box::builder()
+        .orientation(Orientation::Horizontal)
+        .margin_top(10)
+        .build();
+    buttons.append(&status);
+    buttons.append(&idc_save);
+    buttons.append(&idc_close);
+    let root = Box::builder()
+        .orientation(Orientation::Vertical)
+        .margin_top(10)
+        .margin_start(10)
+        .margin_end(10)
+        .margin_bottom(10)
+        .build();
+    root.append(&pages);
+    root.append(&buttons);
+
+    let window = Window::builder()
+        .title("Preferences")
+        .default_width(550)
+        .modal(true)
+        .child(&root)
+        .build();
+    window.set_transient_for(parent.as_ref());
+
+    let w = window.clone();
+    let wh = whisper.clone();
+    idc_browse.connect_clicked(move |_| {
+        let w = w.clone();
+        let wh = wh.clone();
+        glib::spawn_future_local(async move {
+            let dialog = gtk::FileDialog::builder()
+                .title("Whisper model")
+                .build();
+            if let Ok(Some(path)) = dialog.open_future(Some(&w)).await.map(|f| f.path()) {
+                wh.set_text(path.to_string_lossy().as_ref());
+            }
+        });
+    });
+
+    idc_save.connect_clicked(move |_| {
+        // Read again so that edits made to the file meanwhile are not lost
+        let mut conf = match current(&ctx) {
+            Some(c) => c,
+            None => return,
+        };
+        conf.ollama_url = text(&ollama_url);
+        conf.ollama_port = ollama_port.value_as_int() as u16;
+        conf.ollama_model = text(&ollama_model);
+        let mut shadowed = vec![];
+        conf.gpt = gpt.apply(conf.gpt.take(), &mut shadowed);
+        conf.deepseek = deepseek.apply(conf.deepseek.take(), &mut shadowed);
+        conf.grok = grok.apply(conf.grok.take(), &mut shadowed);
+        conf.record_device = match device.selected() {
+            0 => None,
+            i => devices.get(i as usize - 1).cloned(),
+        };
+        conf.whisper_model = text(&whisper);
+        let eleven = conf.eleven.take().unwrap_or_default();
+        let key = typed_key("ElevenLabs", text(&eleven_key), &eleven, &mut shadowed);
+        conf.eleven = (!key.is_empty() || key_source(&eleven).is_some())
+            .then(|| AiApi { key, model: text(&eleven_voice), ..eleven });
+        conf.w = width.value() as f32;
+        conf.h = height.value() as f32;
+        conf.font_size = font_size.value() as f32;
+        conf.chat_msg_wait = msg_wait.value_as_int() as u64;
+        match config::save(&conf) {
+            Ok(path) => {
+                info!("Preferences saved to {}", path.display());
+                if shadowed.is_empty() {
+                    status.set_text("Saved, the changes apply after a restart");
+                } else {
+                    status.set_text(format!("Saved, the changes apply after a restart. The keys typed for {} \
+                        were not saved, the config reads them from elsewhere", shadowed.join(", ")).as_str());
+                }
+            }
+            Err(e) => ctx.notifier.report(e),
+        }
+    });
+    let w = window.clone();
+    idc_close.connect_clicked(move |_| w.close());
+    window.present();
+}
+
+pub fn prefs_button(ctx: Arc<Context>) -> Button {
+    let b = Button::builder()
+        .label("Preferences")
+        .margin_start(5)
+        .build();
+    b.connect_clicked(move |b| {
+        let parent = b.root().and_downcast::<Window>();
+        present(ctx.clone(), parent);
+    });
+    b
+}
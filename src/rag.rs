@@ -0,0 +1,401 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use anyhow::{Result, anyhow};
+use gtk::prelude::*;
+use gtk::{glib, Box, Button, CheckButton, Label, MenuButton, Orientation, Popover};
+use ollama_rs::Ollama;
+use ollama_rs::generation::embeddings::request::GenerateEmbeddingsRequest;
+use serde::{Deserialize, Serialize};
+use crate::config::RagConf;
+use crate::context::Context;
+use crate::conversation::Role;
+use crate::{documents, store};
+use tracing::{debug, error, info, warn};
+
+const INDEX_FILE: &str = "rag.json";
+// Files read from the folder, the rest is skipped
+const EXTENSIONS: [&str; 23] = [
+    "md", "txt", "rst", "org", "pdf", "docx", "html", "rs", "py", "js", "ts", "go", "c", "h",
+    "cpp", "java", "kt", "sh", "toml", "json", "yaml", "yml", "sql",
+];
+const BATCH: usize = 16;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Chunk {
+    pub text: String,
+    pub vector: Vec<f32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IndexedFile {
+    pub modified: u64,
+    pub size: u64,
+    pub chunks: Vec<Chunk>,
+}
+
+// Embedded chunks of the folder, files are keyed by the path relative to it
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Index {
+    pub model: String,
+    pub folder: PathBuf,
+    pub files: HashMap<String, IndexedFile>,
+}
+
+pub struct Hit {
+    pub file: String,
+    pub text: String,
+    pub score: f32,
+}
+
+// Excerpts for the request and the files they come from
+pub struct Retrieved {
+    pub prompt: String,
+    pub sources: Vec<String>,
+}
+
+fn index_path() -> PathBuf {
+    store::data_dir().join(INDEX_FILE)
+}
+
+impl Index {
+    pub fn load() -> Result<Index> {
+        Ok(serde_json::from_str(std::fs::read_to_string(index_path())?.as_str())?)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        std::fs::create_dir_all(store::data_dir())?;
+        std::fs::write(index_path(), serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn chunks(&self) -> usize {
+        self.files.values().map(|f| f.chunks.len()).sum()
+    }
+
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<Hit> {
+        let mut hits = self.files.iter()
+            .flat_map(|(name, f)| f.chunks.iter().map(move |c| Hit {
+                file: name.clone(),
+                text: c.text.clone(),
+                score: cosine(query, c.vector.as_slice()),
+            }))
+            .collect::<Vec<_>>();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+        hits.truncate(k);
+        hits
+    }
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    let na = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let nb = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if na == 0.0 || nb == 0.0 { 0.0 } else { dot / (na * nb) }
+}
+
+// Supported files under the folder, hidden ones are skipped. Symlinked folders are
+// followed once, `seen` holds the canonical paths so that a link cycle ends.
+// Only an unreadable top folder is an error, bad entries below it are skipped
+fn walk(dir: &Path, seen: &mut HashSet<PathBuf>, res: &mut Vec<PathBuf>) -> Result<()> {
+    if !seen.insert(dir.canonicalize()?) {
+        debug!("Already indexed {}", dir.display());
+        return Ok(());
+    }
+    for e in std::fs::read_dir(dir)? {
+        let path = match e {
+            Ok(e) => e.path(),
+            Err(e) => {
+                warn!("Skipping an entry of {}: {}", dir.display(), e);
+                continue;
+            }
+        };
+        if path.file_name().map(|n| n.to_string_lossy().starts_with('.')).unwrap_or(true) {
+            continue;
+        }
+        if path.is_dir() {
+            if let Err(e) = walk(path.as_path(), seen, res) {
+                warn!("Skipping {}: {}", path.display(), e);
+            }
+        } else if path.extension()
+            .and_then(|e| e.to_str())
+            .map(|e| EXTENSIONS.contains(&e.to_lowercase().as_str()))
+            .unwrap_or(false) {
+            res.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Windows of `size` characters ending at a line break or space where possible,
+// each one repeats the last `overlap` characters of the previous
+pub fn chunk(text: &str, size: usize, overlap: usize) -> Vec<String> {
+    let chars = text.chars().collect::<Vec<_>>();
+    let size = size.max(1);
+    let mut res = vec![];
+    let mut start = 0;
+    while start < chars.len() {
+        let mut end = (start + size).min(chars.len());
+        if end < chars.len() {
+            let from = start + size * 3 / 4;
+            let tail = &chars[from..end];
+            if let Some(i) = tail.iter().rposition(|c| *c == '\n').or(tail.iter().rposition(|c| c.is_whitespace())) {
+                end = from + i + 1;
+            }
+        }
+        let t = chars[start..end].iter().collect::<String>();
+        if !t.trim().is_empty() {
+            res.push(t.trim().to_string());
+        }
+        if end == chars.len() {
+            break;
+        }
+        start = end.saturating_sub(overlap).max(start + 1);
+    }
+    res
+}
+
+async fn embed(ollama: &Ollama, model: &str, input: Vec<String>) -> Result<Vec<Vec<f32>>> {
+    let request = GenerateEmbeddingsRequest::new(model.to_string(), input.into());
+    Ok(ollama.generate_embeddings(request).await?.embeddings)
+}
+
+fn ollama(ctx: &Context) -> Ollama {
+    Ollama::new(ctx.conf.ollama_url.as_str(), ctx.conf.ollama_port)
+}
+
+// Re-embeds new and changed files and drops deleted ones, `progress` gets (done, total)
+pub async fn update(ctx: &Context, progress: impl Fn(usize, usize)) -> Result<(usize, usize)> {
+    let conf: &RagConf = &ctx.conf.rag;
+    let folder = PathBuf::from(conf.folder.as_ref().ok_or(anyhow!("No [rag] folder in the config"))?);
+    let f = folder.clone();
+    let found = tokio::task::spawn_blocking(move || {
+        let mut files = vec![];
+        walk(f.as_path(), &mut HashSet::new(), &mut files).map(|_| files)
+    }).await??;
+
+    // Built aside, questions keep using the old index until it is swapped in at the end
+    let current = ctx.rag.lock().await.clone();
+    let mut index = match current {
+        Some(i) => i,
+        None => Index::load().unwrap_or_default(),
+    };
+    if index.model != conf.embed_model || index.folder != folder {
+        info!("Indexing {} from scratch with {}", folder.display(), conf.embed_model);
+        index = Index { model: conf.embed_model.clone(), folder: folder.clone(), files: HashMap::new() };
+    }
+    let names = found.iter()
+        .map(|p| p.strip_prefix(&folder).unwrap_or(p).to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    let keep = names.iter().collect::<HashSet<_>>();
+    index.files.retain(|k, _| keep.contains(k));
+
+    let ollama = ollama(ctx);
+    let size = conf.chunk_tokens * 4;
+    let overlap = conf.overlap_tokens * 4;
+    let mut updated = 0;
+    for (i, (path, name)) in found.iter().zip(names.iter()).enumerate() {
+        progress(i, found.len());
+        let meta = match std::fs::metadata(path) {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+        let modified = meta.modified().ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        if index.files.get(name).map(|f| f.modified == modified && f.size == meta.len()).unwrap_or(false) {
+            continue;
+        }
+        let p = path.clone();
+        let text = match tokio::task::spawn_blocking(move || documents::extract(p.as_path())).await {
+            Ok(Ok(t)) => t,
+            Ok(Err(e)) => {
+                debug!("Skipping {}: {}", name, e.to_string());
+                continue;
+            }
+            Err(e) => {
+                error!("Extract task error: {}", e.to_string());
+                continue;
+            }
+        };
+        let mut chunks = vec![];
+        for batch in chunk(text.as_str(), size, overlap).chunks(BATCH) {
+            match embed(&ollama, conf.embed_model.as_str(), batch.to_vec()).await {
+                Ok(vectors) => chunks.extend(batch.iter().zip(vectors).map(|(t, v)| Chunk { text: t.clone(), vector: v })),
+                Err(e) => {
+                    // Keep what is done so far, the rest gets indexed next time
+                    crate::report_err!(index.save());
+                    *ctx.rag.lock().await = Some(index);
+                    return Err(anyhow!("Embedding {} failed: {}", name, e.to_string()));
+                }
+            }
+        }
+        index.files.insert(name.clone(), IndexedFile { modified, size: meta.len(), chunks });
+        updated += 1;
+    }
+    progress(found.len(), found.len());
+    if let Err(e) = index.save() {
+        error!("Cannot save the index: {}", e.to_string());
+    }
+    info!("Index updated, {} of {} files changed", updated, found.len());
+    let res = (index.files.len(), index.chunks());
+    *ctx.rag.lock().await = Some(index);
+    Ok(res)
+}
+
+// Closest chunks to the last question, None when the documents are not used
+pub async fn retrieve(ctx: &Context) -> Option<Retrieved> {
+    if !*ctx.use_rag.lock().await {
+        return None;
+    }
+    let query = ctx.conv.lock().await.history()
+        .filter(|m| m.role == Role::User)
+        .last()
+        .map(|m| m.text.clone())?;
+    let vector = match embed(&ollama(ctx), ctx.conf.rag.embed_model.as_str(), vec![query]).await {
+        Ok(mut v) if !v.is_empty() => v.remove(0),
+        Ok(_) => return None,
+        Err(e) => {
+            error!("Cannot embed the question: {}", e.to_string());
+            return None;
+        }
+    };
+    let mut guard = ctx.rag.lock().await;
+    if guard.is_none() {
+        match Index::load() {
+            Ok(i) => *guard = Some(i),
+            Err(e) => {
+                error!("Documents are not indexed: {}", e.to_string());
+                return None;
+            }
+        }
+    }
+    let hits = guard.as_ref()?.search(vector.as_slice(), ctx.conf.rag.top_k);
+    drop(guard);
+    if hits.is_empty() {
+        return None;
+    }
+
+    let mut prompt = String::from("Excerpts from the user's documents are below. Use them when they are relevant \
+        and cite the file name in square brackets, e.g. [notes.md], after the facts taken from it.\n");
+    let mut sources: Vec<String> = vec![];
+    for h in hits.iter() {
+        debug!("Retrieved {} ({:.3})", h.file, h.score);
+        prompt.push_str(format!("\n[{}]\n{}\n", h.file, h.text.trim()).as_str());
+        if !sources.contains(&h.file) {
+            sources.push(h.file.clone());
+        }
+    }
+    Some(Retrieved { prompt, sources })
+}
+
+// Popover switching the documents on and updating the index
+pub fn rag_button(ctx: Arc<Context>) -> MenuButton {
+    let idc_use = CheckButton::builder()
+        .label("Use documents")
+        .build();
+    let idc_update = Button::builder()
+        .label("Update index")
+        .build();
+    let status = Label::builder()
+        .label(ctx.conf.rag.folder.as_deref().unwrap_or(""))
+        .xalign(0.0)
+        .build();
+    let b = Box::builder()
+        .orientation(Orientation::Vertical)
+        .spacing(5)
+        .build();
+    b.append(&idc_use);
+    b.append(&idc_update);
+    b.append(&status);
+
+    let run = {
+        let ctx = ctx.clone();
+        let status = status.clone();
+        let idc_update = idc_update.clone();
+        move || {
+            let ctx = ctx.clone();
+            let status = status.clone();
+            let idc_update = idc_update.clone();
+            glib::spawn_future_local(async move {
+                idc_update.set_sensitive(false);
+                let s = status.clone();
+                let res = update(&ctx, move |i, n| s.set_text(format!("Indexing {}/{}", i, n).as_str())).await;
+                match res {
+                    Ok((files, chunks)) => status.set_text(format!("{} files, {} chunks", files, chunks).as_str()),
+                    Err(e) => {
+                        error!("Indexing failed: {}", e.to_string());
+                        status.set_text("Indexing failed");
+                    }
+                }
+                idc_update.set_sensitive(true);
+            });
+        }
+    };
+    let run = std::rc::Rc::new(run);
+
+    let r = run.clone();
+    idc_update.connect_clicked(move |_| r());
+    // Changed files are picked up whenever the documents are switched on
+    let st = ctx.clone();
+    idc_use.connect_toggled(move |c| {
+        let on = c.is_active();
+        let st = st.clone();
+        glib::spawn_future_local(async move {
+            *st.use_rag.lock().await = on;
+        });
+        if on {
+            run();
+        }
+    });
+
+    let popover = Popover::builder()
+        .child(&b)
+        .build();
+    MenuButton::builder()
+        .label("Docs")
+        .popover(&popover)
+        .margin_start(5)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_text_is_one_chunk() {
+        assert_eq!(chunk("  a short note\n", 100, 10), vec!["a short note"]);
+        assert!(chunk("", 100, 10).is_empty());
+        assert!(chunk(" \n\t ", 100, 10).is_empty());
+    }
+
+    #[test]
+    fn chunks_end_at_line_breaks_then_spaces() {
+        assert_eq!(chunk("aaaa aaa\nbbbb", 10, 0), vec!["aaaa aaa", "bbbb"]);
+        assert_eq!(chunk("aaaaaaa bbbbbbb", 10, 0), vec!["aaaaaaa", "bbbbbbb"]);
+        // No break in the last quarter, cut at the size
+        assert_eq!(chunk("aaaaaaaaaaaaaa", 10, 0), vec!["aaaaaaaaaa", "aaaa"]);
+    }
+
+    #[test]
+    fn chunks_repeat_the_overlap() {
+        let res = chunk("abcdefghijklmnop", 10, 3);
+        assert_eq!(res, vec!["abcdefghij", "hijklmnop"]);
+    }
+
+    #[test]
+    fn overlap_as_long_as_the_chunk_still_moves_on() {
+        let res = chunk("abcdefgh", 4, 4);
+        assert_eq!(res, vec!["abcd", "bcde", "cdef", "defg", "efgh"]);
+        assert_eq!(chunk("abc", 0, 0), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn chunks_count_characters() {
+        let res = chunk("żółw źrebię ćma", 6, 0);
+        assert_eq!(res, vec!["żółw", "źrebię", "ćma"]);
+    }
+}
@@ -0,0 +1,250 @@
+use anyhow::Result;
+use gtk::glib;
+use regex::{Regex, RegexBuilder};
+use std::collections::HashMap;
+use crate::conversation::{Conversation, Role};
+use crate::store;
+
+const SNIPPET_LEN: usize = 160;
+
+#[derive(Clone, Debug, Default)]
+pub struct SearchFilter {
+    pub provider: Option<crate::AiChat>,
+    pub model: Option<String>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Hit {
+    pub conv_id: String,
+    pub conv_title: String,
+    pub message_id: u64,
+    pub role: Role,
+    pub time: i64,
+    pub score: f32,
+    // Pango markup with the matched terms in bold
+    pub snippet: String,
+}
+
+fn terms(query: &str) -> Vec<String> {
+    query.split_whitespace()
+        .map(|t| t.to_lowercase())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+// Parses YYYY-MM-DD as the start of that day in local time
+pub fn parse_date(s: &str) -> Option<i64> {
+    let mut it = s.trim().splitn(3, '-').map(|p| p.parse::<i32>().ok());
+    let (y, m, d) = (it.next()??, it.next()??, it.next()??);
+    glib::DateTime::from_local(y, m, d, 0, 0, 0.0)
+        .ok()
+        .map(|d| d.to_unix())
+}
+
+fn snippet(text: &str, re: &Regex) -> String {
+    let first = re.find(text).map(|m| m.start()).unwrap_or(0);
+    // Start a bit before the first match, on a char boundary
+    let start = text[..first].char_indices()
+        .rev()
+        .nth(SNIPPET_LEN / 3)
+        .map(|(i, _)| i)
+        .unwrap_or(0);
+    let end = text[start..].char_indices()
+        .nth(SNIPPET_LEN)
+        .map(|(i, _)| start + i)
+        .unwrap_or(text.len());
+    let part = text[start..end].replace('\n', " ");
+
+    let mut res = String::new();
+    if start > 0 {
+        res.push_str("…");
+    }
+    let mut last = 0;
+    for m in re.find_iter(part.as_str()) {
+        res.push_str(glib::markup_escape_text(&part[last..m.start()]).as_str());
+        res.push_str(format!("<b>{}</b>", glib::markup_escape_text(m.as_str())).as_str());
+        last = m.end();
+    }
+    res.push_str(glib::markup_escape_text(&part[last..]).as_str());
+    if end < text.len() {
+        res.push_str("…");
+    }
+    res
+}
+
+// Searches all stored messages
+pub fn search(query: &str, filter: &SearchFilter) -> Result<Vec<Hit>> {
+    if terms(query).is_empty() {
+        return Ok(vec![]);
+    }
+    rank(store::load_all()?.as_slice(), query, filter)
+}
+
+// Hits are ranked by tf-idf of the query terms
+fn rank(convs: &[Conversation], query: &str, filter: &SearchFilter) -> Result<Vec<Hit>> {
+    let terms = terms(query);
+    if terms.is_empty() {
+        return Ok(vec![]);
+    }
+    let pattern = terms.iter()
+        .map(|t| regex::escape(t))
+        .collect::<Vec<_>>()
+        .join("|");
+    let re = RegexBuilder::new(pattern.as_str())
+        .case_insensitive(true)
+        .build()?;
+
+    let provider = filter.provider.map(|p| p.to_string());
+    let model = filter.model.as_ref().map(|m| m.to_lowercase());
+
+    // Candidate messages with the term counts
+    let mut found = vec![];
+    let mut df: HashMap<&str, usize> = HashMap::new();
+    let mut total = 0;
+    for c in convs.iter() {
+        if provider.as_ref().map(|p| *p != c.provider).unwrap_or(false) {
+            continue;
+        }
+        for m in c.messages.iter() {
+            total += 1;
+            if filter.from.map(|f| m.time < f).unwrap_or(false) || filter.to.map(|t| m.time >= t).unwrap_or(false) {
+                continue;
+            }
+            if let Some(model) = &model {
+                let name = if m.role == Role::Assistant { &m.model } else { &c.model };
+                if !name.to_lowercase().contains(model.as_str()) {
+                    continue;
+                }
+            }
+            let lower = m.text.to_lowercase();
+            let counts = terms.iter()
+                .map(|t| lower.matches(t.as_str()).count())
+                .collect::<Vec<_>>();
+            if counts.iter().all(|n| *n == 0) {
+                continue;
+            }
+            for (t, n) in terms.iter().zip(counts.iter()) {
+                if *n > 0 {
+                    *df.entry(t.as_str()).or_default() += 1;
+                }
+            }
+            let phrase = terms.len() > 1 && lower.contains(terms.join(" ").as_str());
+            found.push((c, m, counts, phrase));
+        }
+    }
+
+    let mut hits = found.into_iter()
+        .map(|(c, m, counts, phrase)| {
+            let len_norm = 1.0 + (m.text.len() as f32 / 500.0).ln_1p();
+            let mut score = terms.iter().zip(counts.iter())
+                .map(|(t, n)| {
+                    let idf = ((total as f32 + 1.0) / (*df.get(t.as_str()).unwrap_or(&0) as f32 + 1.0)).ln() + 1.0;
+                    (1.0 + (*n as f32).ln_1p()) * idf * if *n > 0 { 1.0 } else { 0.0 }
+                })
+                .sum::<f32>() / len_norm;
+            if phrase {
+                score *= 2.0;
+            }
+            Hit {
+                conv_id: c.id.clone(),
+                conv_title: if c.title.is_empty() { c.default_title() } else { c.title.clone() },
+                message_id: m.id,
+                role: m.role,
+                time: m.time,
+                score,
+                snippet: snippet(m.text.as_str(), &re),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score).then(b.time.cmp(&a.time)));
+    Ok(hits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AiChat;
+
+    fn conv(id: &str, provider: AiChat, model: &str, msgs: &[(Role, &str, i64)]) -> Conversation {
+        let mut c = Conversation::new();
+        c.id = id.to_string();
+        c.provider = provider.to_string();
+        c.model = model.to_string();
+        for (role, text, time) in msgs {
+            c.push_at(*role, model, text, *time);
+        }
+        c
+    }
+
+    fn re(query: &str) -> Regex {
+        RegexBuilder::new(regex::escape(query).as_str()).case_insensitive(true).build().unwrap()
+    }
+
+    fn times(hits: &[Hit]) -> Vec<i64> {
+        let mut res = hits.iter().map(|h| h.time).collect::<Vec<_>>();
+        res.sort();
+        res
+    }
+
+    #[test]
+    fn more_matches_and_phrases_rank_higher() {
+        let convs = [conv("a", AiChat::Ollama, "llama3", &[
+            (Role::User, "I like rust", 1),
+            (Role::Assistant, "rust rust rust and more rust", 2),
+            (Role::User, "nothing here", 3),
+        ])];
+        let hits = rank(&convs, "Rust", &SearchFilter::default()).unwrap();
+        assert_eq!(hits.iter().map(|h| h.time).collect::<Vec<_>>(), vec![2, 1]);
+
+        let convs = [conv("b", AiChat::Ollama, "llama3", &[
+            (Role::User, "borrow the checker", 1),
+            (Role::Assistant, "the borrow checker", 2),
+        ])];
+        let hits = rank(&convs, "borrow checker", &SearchFilter::default()).unwrap();
+        assert_eq!(hits.iter().map(|h| h.time).collect::<Vec<_>>(), vec![2, 1]);
+        assert!(hits[0].score > hits[1].score);
+        assert!(rank(&convs, "  ", &SearchFilter::default()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn snippet_marks_the_matches() {
+        assert_eq!(snippet("a <b> tag & rust", &re("rust")), "a &lt;b&gt; tag &amp; <b>rust</b>");
+        assert_eq!(snippet("Zażółć GĘŚLĄ jaźń", &re("gęślą")), "Zażółć <b>GĘŚLĄ</b> jaźń");
+
+        let text = format!("{}needle{}", "ą ".repeat(200), " ę".repeat(200));
+        let res = snippet(text.as_str(), &re("needle"));
+        assert!(res.starts_with('…') && res.ends_with('…'), "{}", res);
+        assert!(res.contains("<b>needle</b>"), "{}", res);
+    }
+
+    #[test]
+    fn non_ascii_terms_are_found() {
+        let convs = [conv("a", AiChat::Ollama, "llama3", &[(Role::User, "Gęślą jaźń", 1)])];
+        let hits = rank(&convs, "JAŹŃ", &SearchFilter::default()).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].snippet, "Gęślą <b>jaźń</b>");
+    }
+
+    #[test]
+    fn filters_by_provider_model_and_date() {
+        let convs = [
+            conv("a", AiChat::Ollama, "llama3", &[(Role::User, "rust?", 100), (Role::Assistant, "rust!", 200)]),
+            conv("b", AiChat::ChatGPT, "gpt-4o", &[(Role::User, "rust?", 300), (Role::Assistant, "rust!", 400)]),
+        ];
+        let all = rank(&convs, "rust", &SearchFilter::default()).unwrap();
+        assert_eq!(times(&all), vec![100, 200, 300, 400]);
+
+        let filter = SearchFilter { provider: Some(AiChat::Ollama), ..Default::default() };
+        assert_eq!(times(&rank(&convs, "rust", &filter).unwrap()), vec![100, 200]);
+
+        let filter = SearchFilter { model: Some(String::from("GPT")), ..Default::default() };
+        assert_eq!(times(&rank(&convs, "rust", &filter).unwrap()), vec![300, 400]);
+
+        // `to` is the start of the day after the range
+        let filter = SearchFilter { from: Some(200), to: Some(400), ..Default::default() };
+        assert_eq!(times(&rank(&convs, "rust", &filter).unwrap()), vec![200, 300]);
+    }
+}
@@ -0,0 +1,183 @@
+use gtk::prelude::*;
+use gtk::{glib, Align, Box, DropDown, Entry, Label, ListBox, ListBoxRow, Orientation, ScrolledWindow, SearchEntry, StringList, Window};
+use async_channel::Sender;
+use std::cell::RefCell;
+use std::rc::Rc;
+use crate::search::{self, Hit, SearchFilter};
+use crate::sidebar::SideAction;
+use crate::helper::format_time;
+use crate::conversation::Role;
+use crate::AiChat;
+use tracing::error;
+
+const DAY: i64 = 24 * 60 * 60;
+
+// Search window, activating a hit sends `SideAction::Jump`
+pub struct SearchView {
+    window: Window,
+}
+
+fn hit_row(h: &Hit) -> ListBoxRow {
+    let title = Label::builder()
+        .halign(Align::Start)
+        .ellipsize(gtk::pango::EllipsizeMode::End)
+        .build();
+    title.set_markup(format!("<b>{}</b>  <small>{} {}</small>",
+        glib::markup_escape_text(h.conv_title.as_str()),
+        if h.role == Role::User { "You" } else { "AI" },
+        format_time(h.time)).as_str());
+    let snippet = Label::builder()
+        .halign(Align::Start)
+        .wrap(true)
+        .xalign(0.0)
+        .build();
+    snippet.set_markup(h.snippet.as_str());
+
+    let b = Box::builder()
+        .orientation(Orientation::Vertical)
+        .margin_top(3)
+        .margin_bottom(3)
+        .build();
+    b.append(&title);
+    b.append(&snippet);
+    ListBoxRow::builder()
+        .child(&b)
+        .build()
+}
+
+impl SearchView {
+    pub fn new(parent: &impl IsA<gtk::Window>, actions: Sender<SideAction>) -> Self {
+        let query = SearchEntry::builder()
+            .placeholder_text("Search conversations")
+            .hexpand(true)
+            .build();
+
+        let options = StringList::new(&["Any provider"]);
+        for a in AiChat::ALL {
+            options.append(a.as_str());
+        }
+        let provider = DropDown::builder()
+            .model(&options)
+            .margin_start(5)
+            .build();
+        let model = Entry::builder()
+            .placeholder_text("model")
+            .width_chars(12)
+            .margin_start(5)
+            .build();
+        let from = Entry::builder()
+            .placeholder_text("from YYYY-MM-DD")
+            .width_chars(14)
+            .margin_start(5)
+            .build();
+        let to = Entry::builder()
+            .placeholder_text("to YYYY-MM-DD")
+            .width_chars(14)
+            .margin_start(5)
+            .build();
+
+        let filters = Box::builder()
+            .orientation(Orientation::Horizontal)
+            .margin_top(5)
+            .build();
+        filters.append(&provider);
+        filters.append(&model);
+        filters.append(&from);
+        filters.append(&to);
+
+        let list = ListBox::builder()
+            .selection_mode(gtk::SelectionMode::Single)
+            .build();
+        let status = Label::builder()
+            .halign(Align::Start)
+            .margin_top(5)
+            .build();
+        let scroll = ScrolledWindow::builder()
+            .child(&list)
+            .vexpand(true)
+            .hscrollbar_policy(gtk::PolicyType::Never)
+            .build();
+
+        let root = Box::builder()
+            .orientation(Orientation::Vertical)
+            .margin_top(5)
+            .margin_start(5)
+            .margin_end(5)
+            .margin_bottom(5)
+            .build();
+        root.append(&query);
+        root.append(&filters);
+        root.append(&status);
+        root.append(&scroll);
+
+        let window = Window::builder()
+            .title("Search")
+            .transient_for(parent)
+            .default_width(600)
+            .default_height(500)
+            .hide_on_close(true)
+            .child(&root)
+            .build();
+
+        let hits: Rc<RefCell<Vec<Hit>>> = Rc::new(RefCell::new(vec![]));
+        let h = hits.clone();
+        list.connect_row_activated(move |_, row| {
+            if let Some(hit) = h.borrow().get(row.index() as usize) {
+                let a = SideAction::Jump(hit.conv_id.clone(), hit.message_id);
+                if let Err(e) = actions.try_send(a) {
+                    error!("Error sending search action: {}", e.to_string());
+                }
+            }
+        });
+
+        let run = Rc::new(glib::clone!(
+            #[weak] query, #[weak] provider, #[weak] model, #[weak] from, #[weak] to,
+            #[weak] list, #[weak] status,
+            move || {
+                let q = query.text().to_string();
+                let sel = provider.selected() as usize;
+                let m = model.text().trim().to_string();
+                let filter = SearchFilter {
+                    provider: if sel == 0 { None } else { AiChat::ALL.get(sel - 1).copied() },
+                    model: if m.is_empty() { None } else { Some(m) },
+                    from: search::parse_date(from.text().as_str()),
+                    to: search::parse_date(to.text().as_str()).map(|t| t + DAY),
+                };
+                let hits = hits.clone();
+                glib::spawn_future_local(async move {
+                    let res = tokio::task::spawn_blocking(move || search::search(q.as_str(), &filter)).await;
+                    list.remove_all();
+                    match res {
+                        Ok(Ok(found)) => {
+                            status.set_text(format!("{} results", found.len()).as_str());
+                            for h in found.iter() {
+                                list.append(&hit_row(h));
+                            }
+                            *hits.borrow_mut() = found;
+                        }
+                        Ok(Err(e)) => {
+                            status.set_text("Search failed");
+                            error!("Search error: {}", e.to_string());
+                        }
+                        Err(e) => error!("Search task error: {}", e.to_string()),
+                    }
+                });
+            }
+        ));
+
+        let r = run.clone();
+        query.connect_search_changed(move |_| r());
+        let r = run.clone();
+        provider.connect_selected_item_notify(move |_| r());
+        for e in [&model, &from, &to] {
+            let r = run.clone();
+            e.connect_activate(move |_| r());
+        }
+
+        Self { window }
+    }
+
+    pub fn present(&self) {
+        self.window.present();
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use tracing_subscriber::fmt::MakeWriter;
+use crate::config::{self, AiApi, Config};
+use crate::errors::AppError;
+use tracing::debug;
+
+const SECRETS_FILE: &str = "secrets.toml";
+const REDACTED: &str = "[redacted]";
+
+// Keys in use, taken out of everything that is logged
+static SECRETS: RwLock<Vec<String>> = RwLock::new(vec![]);
+
+// Every key however short, an empty one would match everywhere
+fn register(key: &str) {
+    if key.is_empty() {
+        return;
+    }
+    if let Ok(mut s) = SECRETS.write() {
+        if !s.iter().any(|k| k == key) {
+            s.push(key.to_string());
+        }
+    }
+}
+
+pub fn redact(text: &str) -> String {
+    let mut res = text.to_string();
+    if let Ok(s) = SECRETS.read() {
+        for k in s.iter() {
+            res = res.replace(k.as_str(), REDACTED);
+        }
+    }
+    res
+}
+
+// Log writer which redacts each event before it goes to stdout
+pub struct Redacting;
+
+pub struct EventWriter(Vec<u8>);
+
+impl<'a> MakeWriter<'a> for Redacting {
+    type Writer = EventWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        EventWriter(vec![])
+    }
+}
+
+impl Write for EventWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for EventWriter {
+    fn drop(&mut self) {
+        let text = redact(String::from_utf8_lossy(&self.0).as_ref());
+        let _ = std::io::stdout().write_all(text.as_bytes());
+    }
+}
+
+// secrets.toml next to the config, `section = "key"` lines
+fn secrets_path() -> PathBuf {
+    config::path()
+        .parent()
+        .map(|p| p.join(SECRETS_FILE))
+        .unwrap_or(PathBuf::from(SECRETS_FILE))
+}
+
+// Not read when other users could read it too
+fn load_file() -> Result<HashMap<String, String>, AppError> {
+    let path = secrets_path();
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path)
+            .map_err(|e| AppError::Config(format!("Cannot read {}: {}", path.display(), e)))?
+            .permissions()
+            .mode();
+        if mode & 0o077 != 0 {
+            return Err(AppError::Config(format!("{} can be read by other users, run chmod 600 on it", path.display())));
+        }
+    }
+    let text = std::fs::read_to_string(&path)
+        .map_err(|e| AppError::Config(format!("Cannot read {}: {}", path.display(), e)))?;
+    toml::from_str(text.as_str())
+        .map_err(|e| AppError::Config(format!("{} is not valid: {}", path.display(), e)))
+}
+
+// Adds the keys to secrets.toml, which is created readable only by the user
+pub fn save_file(keys: &[(&str, String)]) -> Result<PathBuf, AppError> {
+    let path = secrets_path();
+    let mut all = load_file()?.into_iter().collect::<BTreeMap<_, _>>();
+    for (name, key) in keys {
+        all.insert(name.to_string(), key.clone());
+    }
+    let text = toml::to_string(&all)
+        .map_err(|e| AppError::Config(format!("Cannot write {}: {}", path.display(), e)))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| AppError::Config(format!("Cannot create {}: {}", dir.display(), e)))?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&path)
+        .and_then(|mut f| f.write_all(text.as_bytes()))
+        .map_err(|e| AppError::Config(format!("Cannot write {}: {}", path.display(), e)))?;
+    Ok(path)
+}
+
+// Stdout of the command, e.g. "pass show openai"
+fn run(cmd: &str) -> Result<String, String> {
+    let out = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .output()
+        .map_err(|e| e.to_string())?;
+    if !out.status.success() {
+        return Err(format!("exited with {}: {}", out.status, String::from_utf8_lossy(&out.stderr).trim()));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+// key_env, then key_cmd, then the secrets file, then the key written in the config.
+// When the configured source fails the section has no key, a key written in the
+// config is not sent in its place
+fn resolve(name: &str, api: &mut AiApi, file: &HashMap<String, String>) -> Result<(), AppError> {
+    // Whatever happens below, it stays out of the log
+    register(api.key.as_str());
+    if api.key_env.is_some() || api.key_cmd.is_some() {
+        api.key.clear();
+    }
+    if let Some(var) = &api.key_env {
+        api.key = std::env::var(var)
+            .map_err(|_| AppError::Config(format!("The key of [{}] is read from ${}, which is not set", name, var)))?;
+        debug!("Key of [{}] from ${}", name, var);
+    } else if let Some(cmd) = &api.key_cmd {
+        api.key = run(cmd.as_str())
+            .map_err(|e| AppError::Config(format!("The key command of [{}] failed: {}", name, e)))?;
+        debug!("Key of [{}] from its command", name);
+    } else if let Some(k) = file.get(name) {
+        api.key = k.clone();
+        debug!("Key of [{}] from {}", name, SECRETS_FILE);
+    }
+    register(api.key.as_str());
+    Ok(())
+}
+
+// Fills in the keys of all sections, the ones which cannot be found are reported
+// and their sections have no key. key_cmd may wait for a
+// passphrase, so this runs before the window is built and off the main loop
+pub fn resolve_keys(conf: &mut Config) -> Vec<AppError> {
+    let mut errors = vec![];
+    let file = load_file().unwrap_or_else(|e| {
+        errors.push(e);
+        HashMap::new()
+    });
+    let sections = [
+        ("gpt", &mut conf.gpt),
+        ("deepseek", &mut conf.deepseek),
+        ("grok", &mut conf.grok),
+        ("eleven", &mut conf.eleven),
+    ];
+    for (name, api) in sections {
+        if let Some(api) = api {
+            if let Err(e) = resolve(name, api, &file) {
+                errors.push(e);
+            }
+        }
+    }
+    errors
+}
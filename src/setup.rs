@@ -0,0 +1,221 @@
+use gtk::prelude::*;
+use gtk::{glib, Align, Application, Box, Button, DropDown, Entry, Grid, Label, Orientation, StringList, Window};
+use crate::config::{self, AiApi, Config};
+use crate::secrets;
+use tracing::info;
+
+// Where the typed keys go, the first one is the default
+const KEY_STORES: [&str; 3] = ["secrets.toml (only you can read it)", "Environment variables", "The config file"];
+const SECRETS: u32 = 0;
+const ENV: u32 = 1;
+
+// Entries of the first run setup
+struct Fields {
+    keys: DropDown,
+    ollama_url: Entry,
+    ollama_model: Entry,
+    gpt_key: Entry,
+    deepseek_key: Entry,
+    whisper_model: Entry,
+    eleven_key: Entry,
+    eleven_voice: Entry,
+}
+
+impl Fields {
+    // Online chats and speech are added only when a key is given. The keys for
+    // secrets.toml are returned, they are not written into the config
+    fn config(&self) -> Result<(Config, Vec<(&'static str, String)>), String> {
+        let mut conf = Config::initial();
+        let url = self.ollama_url.text().trim().trim_end_matches('/').to_string();
+        if !url.is_empty() {
+            // "http://host:port" is split into the two fields of the config
+            match url.rsplit_once(':').filter(|(h, _)| h.contains("//")) {
+                Some((host, port)) => {
+                    conf.ollama_port = port.parse().map_err(|_| format!("Invalid port {}", port))?;
+                    conf.ollama_url = host.to_string();
+                }
+                None => conf.ollama_url = url,
+            }
+        }
+        let model = self.ollama_model.text().trim().to_string();
+        if !model.is_empty() {
+            conf.ollama_model = model;
+        }
+        let store = self.keys.selected();
+        let mut secrets = vec![];
+        let mut api = |name: &'static str, key: &Entry, url: &str, model: &str| {
+            let key = key.text().trim().to_string();
+            if key.is_empty() {
+                return None;
+            }
+            let mut api = AiApi { url: url.to_string(), model: model.to_string(), ..Default::default() };
+            match store {
+                SECRETS => secrets.push((name, key)),
+                // The entry holds the name of the variable
+                ENV => api.key_env = Some(key),
+                _ => api.key = key,
+            }
+            Some(api)
+        };
+        conf.gpt = api("gpt", &self.gpt_key, "https://api.openai.com/v1/", "gpt-4o");
+        conf.deepseek = api("deepseek", &self.deepseek_key, "https://api.deepseek.com/", "deepseek-chat");
+        conf.eleven = api("eleven", &self.eleven_key, "", self.eleven_voice.text().trim());
+        conf.whisper_model = self.whisper_model.text().trim().to_string();
+        Ok((conf, secrets))
+    }
+
+    // Variable names are asked for instead of the keys when they come from the environment
+    fn show_store(&self) {
+        let env = self.keys.selected() == ENV;
+        let entries = [
+            (&self.gpt_key, "OPENAI_API_KEY"),
+            (&self.deepseek_key, "DEEPSEEK_API_KEY"),
+            (&self.eleven_key, "ELEVENLABS_API_KEY"),
+        ];
+        for (e, var) in entries {
+            e.set_visibility(env);
+            e.set_placeholder_text(Some(if env { var } else { "optional" }));
+        }
+    }
+}
+
+fn entry(grid: &Grid, row: i32, label: &str, placeholder: &str) -> Entry {
+    let l = Label::builder()
+        .label(label)
+        .halign(Align::Start)
+        .build();
+    let e = Entry::builder()
+        .placeholder_text(placeholder)
+        .hexpand(true)
+        .build();
+    grid.attach(&l, 0, row, 1, 1);
+    grid.attach(&e, 1, row, 1, 1);
+    e
+}
+
+// Asks for the basics and writes the config, `done` builds the main window with it.
+// Closing the window quits the app
+pub fn present<F: Fn(Config) + 'static>(app: &Application, done: F) {
+    let intro = Label::builder()
+        .label(format!("No usable {} was found. Fill in what you have, everything but Ollama is optional \
+            and can be changed in the preferences later.", config::path().display()).as_str())
+        .wrap(true)
+        .halign(Align::Start)
+        .margin_bottom(10)
+        .build();
+    let grid = Grid::builder()
+        .row_spacing(5)
+        .column_spacing(10)
+        .build();
+    let keys = DropDown::builder()
+        .model(&StringList::new(&KEY_STORES))
+        .build();
+    let keys_label = Label::builder()
+        .label("Keep API keys in")
+        .halign(Align::Start)
+        .build();
+    grid.attach(&keys_label, 0, 2, 1, 1);
+    grid.attach(&keys, 1, 2, 1, 1);
+    let fields = std::rc::Rc::new(Fields {
+        keys,
+        ollama_url: entry(&grid, 0, "Ollama URL", "http://localhost:11434"),
+        ollama_model: entry(&grid, 1, "Ollama model", "llama3.2"),
+        gpt_key: entry(&grid, 3, "OpenAI API key", ""),
+        deepseek_key: entry(&grid, 4, "Deepseek API key", ""),
+        whisper_model: entry(&grid, 5, "Whisper model file", "optional, for voice input"),
+        eleven_key: entry(&grid, 6, "ElevenLabs API key", ""),
+        eleven_voice: entry(&grid, 7, "ElevenLabs voice id", "optional, for reading answers"),
+    });
+    fields.show_store();
+    let f = fields.clone();
+    fields.keys.connect_selected_notify(move |_| f.show_store());
+    let idc_browse = Button::builder()
+        .label("Browse")
+        .build();
+    grid.attach(&idc_browse, 2, 5, 1, 1);
+    let status = Label::builder()
+        .halign(Align::Start)
+        .wrap(true)
+        .css_classes(["error"])
+        .build();
+    let idc_save = Button::builder()
+        .label("Save and start")
+        .build();
+    let idc_quit = Button::builder()
+        .label("Quit")
+        .margin_start(5)
+        .build();
+    let buttons = Box::builder()
+        .orientation(Orientation::Horizontal)
+        .halign(Align::End)
+        .margin_top(10)
+        .build();
+    buttons.append(&idc_save);
+    buttons.append(&idc_quit);
+    let root = Box::builder()
+        .orientation(Orientation::Vertical)
+        .margin_top(10)
+        .margin_start(10)
+        .margin_end(10)
+        .margin_bottom(10)
+        .build();
+    root.append(&intro);
+    root.append(&grid);
+    root.append(&status);
+    root.append(&buttons);
+
+    let window = Window::builder()
+        .application(app)
+        .title("gChatter setup")
+        .default_width(550)
+        .child(&root)
+        .default_widget(&idc_save)
+        .build();
+
+    let w = window.clone();
+    let whisper = fields.whisper_model.clone();
+    idc_browse.connect_clicked(move |_| {
+        let w = w.clone();
+        let whisper = whisper.clone();
+        glib::spawn_future_local(async move {
+            let dialog = gtk::FileDialog::builder()
+                .title("Whisper model")
+                .build();
+            if let Ok(Some(path)) = dialog.open_future(Some(&w)).await.map(|f| f.path()) {
+                whisper.set_text(path.to_string_lossy().as_ref());
+            }
+        });
+    });
+
+    let w = window.clone();
+    idc_save.connect_clicked(move |_| {
+        let (conf, keys) = match fields.config() {
+            Ok(c) => c,
+            Err(e) => {
+                status.set_text(e.as_str());
+                return;
+            }
+        };
+        if !keys.is_empty() {
+            match secrets::save_file(keys.as_slice()) {
+                Ok(path) => info!("Wrote {}", path.display()),
+                Err(e) => {
+                    status.set_text(format!("{}", e).as_str());
+                    return;
+                }
+            }
+        }
+        match config::save(&conf) {
+            Ok(path) => {
+                info!("Wrote {}", path.display());
+                // The app is held until the main window is up, so it keeps running
+                done(conf);
+                w.destroy();
+            }
+            Err(e) => status.set_text(format!("{}", e).as_str()),
+        }
+    });
+    let w = window.clone();
+    idc_quit.connect_clicked(move |_| w.close());
+    window.present();
+}
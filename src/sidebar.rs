@@ -0,0 +1,251 @@
+use gtk::prelude::*;
+use gtk::{Align, Box, Button, Entry, Label, ListBox, ListBoxRow, MenuButton, Orientation, Popover, ScrolledWindow};
+use async_channel::Sender;
+use std::cell::RefCell;
+use std::rc::Rc;
+use crate::store::ConvSummary;
+use crate::helper::format_time;
+use crate::export::ExportFormat;
+use tracing::error;
+
+// Actions triggered from the history sidebar
+#[derive(Clone, Debug, PartialEq)]
+pub enum SideAction {
+    New,
+    Open(String),
+    Rename(String, String),
+    Delete(String),
+    Search,
+    // Open the conversation and scroll to the message
+    Jump(String, u64),
+    Export(String, ExportFormat),
+    ExportAll(ExportFormat),
+    Import,
+}
+
+pub struct Sidebar {
+    root: Box,
+    list: ListBox,
+    ids: Rc<RefCell<Vec<String>>>,
+    actions: Sender<SideAction>,
+}
+
+fn send(sx: &Sender<SideAction>, a: SideAction) {
+    if let Err(e) = sx.try_send(a) {
+        error!("Error sending sidebar action: {}", e.to_string());
+    }
+}
+
+// Menu button with a popover listing the export formats
+fn export_menu<F: Fn(ExportFormat) -> SideAction + Clone + 'static>(sx: &Sender<SideAction>, tip: &str, action: F) -> MenuButton {
+    let b = Box::builder()
+        .orientation(Orientation::Vertical)
+        .build();
+    let popover = Popover::builder()
+        .child(&b)
+        .build();
+    for fmt in ExportFormat::ALL {
+        let idc = Button::builder()
+            .label(fmt.as_str())
+            .has_frame(false)
+            .build();
+        let sx = sx.clone();
+        let action = action.clone();
+        let p = popover.clone();
+        idc.connect_clicked(move |_| {
+            p.popdown();
+            send(&sx, action(*fmt));
+        });
+        b.append(&idc);
+    }
+    MenuButton::builder()
+        .icon_name("document-save-as-symbolic")
+        .tooltip_text(tip)
+        .has_frame(false)
+        .popover(&popover)
+        .build()
+}
+
+impl Sidebar {
+    pub fn new(actions: Sender<SideAction>) -> Self {
+        let list = ListBox::builder()
+            .selection_mode(gtk::SelectionMode::Single)
+            .build();
+
+        let ids: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(vec![]));
+        let i = ids.clone();
+        let sx = actions.clone();
+        list.connect_row_activated(move |_, row| {
+            if let Some(id) = i.borrow().get(row.index() as usize) {
+                send(&sx, SideAction::Open(id.clone()));
+            }
+        });
+
+        let idc_new = Button::builder()
+            .label("New chat")
+            .hexpand(true)
+            .build();
+        let sx = actions.clone();
+        idc_new.connect_clicked(move |_| send(&sx, SideAction::New));
+
+        let idc_search = Button::builder()
+            .icon_name("system-search-symbolic")
+            .tooltip_text("Search conversations")
+            .margin_start(5)
+            .build();
+        let sx = actions.clone();
+        idc_search.connect_clicked(move |_| send(&sx, SideAction::Search));
+
+        let top = Box::builder()
+            .orientation(Orientation::Horizontal)
+            .margin_bottom(5)
+            .build();
+        top.append(&idc_new);
+        top.append(&idc_search);
+        top.append(&export_menu(&actions, "Export all", SideAction::ExportAll));
+
+        let idc_import = Button::builder()
+            .icon_name("document-open-symbolic")
+            .tooltip_text("Import ChatGPT or Open WebUI/Ollama history")
+            .has_frame(false)
+            .build();
+        let sx = actions.clone();
+        idc_import.connect_clicked(move |_| send(&sx, SideAction::Import));
+        top.append(&idc_import);
+
+        let scroll = ScrolledWindow::builder()
+            .child(&list)
+            .vexpand(true)
+            .hscrollbar_policy(gtk::PolicyType::Never)
+            .build();
+
+        let root = Box::builder()
+            .orientation(Orientation::Vertical)
+            .width_request(220)
+            .margin_top(5)
+            .margin_start(5)
+            .margin_end(5)
+            .build();
+        root.append(&top);
+        root.append(&scroll);
+
+        Self { root, list, ids, actions }
+    }
+
+    pub fn widget(&self) -> &Box {
+        &self.root
+    }
+
+    fn row(&self, c: &ConvSummary) -> ListBoxRow {
+        let title = Label::builder()
+            .label(c.title.as_str())
+            .halign(Align::Start)
+            .ellipsize(gtk::pango::EllipsizeMode::End)
+            .build();
+        let info = Label::builder()
+            .halign(Align::Start)
+            .build();
+        info.set_markup(format!("<small>{} {}  {}</small>",
+            gtk::glib::markup_escape_text(c.provider.as_str()),
+            gtk::glib::markup_escape_text(c.model.as_str()),
+            format_time(c.updated)).as_str());
+
+        let labels = Box::builder()
+            .orientation(Orientation::Vertical)
+            .hexpand(true)
+            .build();
+        labels.append(&title);
+        labels.append(&info);
+
+        // Rename popover
+        let entry = Entry::builder()
+            .text(c.title.as_str())
+            .build();
+        let popover = Popover::builder()
+            .child(&entry)
+            .build();
+        let sx = self.actions.clone();
+        let id = c.id.clone();
+        entry.connect_activate(popdown_after(popover.clone(), move |e| {
+            send(&sx, SideAction::Rename(id.clone(), e.text().to_string()));
+        }));
+        let idc_rename = MenuButton::builder()
+            .icon_name("document-edit-symbolic")
+            .tooltip_text("Rename")
+            .has_frame(false)
+            .popover(&popover)
+            .build();
+
+        let idc_delete = Button::builder()
+            .icon_name("user-trash-symbolic")
+            .tooltip_text("Delete")
+            .has_frame(false)
+            .build();
+        let sx = self.actions.clone();
+        let id = c.id.clone();
+        idc_delete.connect_clicked(move |_| send(&sx, SideAction::Delete(id.clone())));
+
+        let id = c.id.clone();
+        let idc_export = export_menu(&self.actions, "Export", move |f| SideAction::Export(id.clone(), f));
+
+        let b = Box::builder()
+            .orientation(Orientation::Horizontal)
+            .margin_top(3)
+            .margin_bottom(3)
+            .build();
+        b.append(&labels);
+        b.append(&idc_rename);
+        b.append(&idc_export);
+        b.append(&idc_delete);
+
+        ListBoxRow::builder()
+            .child(&b)
+            .build()
+    }
+
+    // The saved conversation moves to the top with its new title and time, selected
+    pub fn update(&self, c: &ConvSummary) {
+        let mut ids = self.ids.borrow_mut();
+        if let Some(i) = ids.iter().position(|id| *id == c.id) {
+            if let Some(row) = self.list.row_at_index(i as i32) {
+                self.list.remove(&row);
+            }
+            ids.remove(i);
+        }
+        let row = self.row(c);
+        self.list.insert(&row, 0);
+        self.list.select_row(Some(&row));
+        ids.insert(0, c.id.clone());
+    }
+
+    // A new conversation is not listed yet, nothing is selected then
+    pub fn select(&self, id: &str) {
+        let row = self.ids.borrow().iter()
+            .position(|i| i == id)
+            .and_then(|i| self.list.row_at_index(i as i32));
+        self.list.select_row(row.as_ref());
+    }
+
+    // Rebuilds the list, `current` gets selected
+    pub fn refresh(&self, items: &[ConvSummary], current: &str) {
+        self.list.remove_all();
+        let mut ids = self.ids.borrow_mut();
+        ids.clear();
+        for c in items {
+            let row = self.row(c);
+            self.list.append(&row);
+            if c.id == current {
+                self.list.select_row(Some(&row));
+            }
+            ids.push(c.id.clone());
+        }
+    }
+}
+
+// Closes the popover after the entry was activated
+fn popdown_after<F: Fn(&Entry) + 'static>(popover: Popover, f: F) -> impl Fn(&Entry) + 'static {
+    move |e| {
+        f(e);
+        popover.popdown();
+    }
+}
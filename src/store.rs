@@ -0,0 +1,112 @@
+use std::path::PathBuf;
+use anyhow::{Result, anyhow};
+use crate::conversation::Conversation;
+use tracing::{debug, error};
+
+const APP_DIR: &str = "gchatter";
+const CONV_DIR: &str = "conversations";
+
+// Short info about a stored conversation, used by the sidebar
+#[derive(Clone, Debug)]
+pub struct ConvSummary {
+    pub id: String,
+    pub title: String,
+    pub provider: String,
+    pub model: String,
+    pub updated: i64,
+}
+
+impl From<&Conversation> for ConvSummary {
+    fn from(c: &Conversation) -> Self {
+        Self {
+            id: c.id.clone(),
+            title: if c.title.is_empty() { c.default_title() } else { c.title.clone() },
+            provider: c.provider.clone(),
+            model: c.model.clone(),
+            updated: c.updated,
+        }
+    }
+}
+
+// $XDG_DATA_HOME/gchatter, falls back to ~/.local/share/gchatter
+pub fn data_dir() -> PathBuf {
+    let base = std::env::var("XDG_DATA_HOME")
+        .ok()
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            let home = std::env::var("HOME").unwrap_or(String::from("."));
+            PathBuf::from(home).join(".local").join("share")
+        });
+    base.join(APP_DIR)
+}
+
+fn conv_dir() -> Result<PathBuf> {
+    let dir = data_dir().join(CONV_DIR);
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn conv_path(id: &str) -> Result<PathBuf> {
+    if id.is_empty() || id.contains(['/', '\\', '.']) {
+        return Err(anyhow!("Invalid conversation id: {}", id));
+    }
+    Ok(conv_dir()?.join(format!("{}.json", id)))
+}
+
+pub fn save(conv: &Conversation) -> Result<()> {
+    let path = conv_path(conv.id.as_str())?;
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_string_pretty(conv)?)?;
+    std::fs::rename(&tmp, &path)?;
+    debug!("Saved conversation {}", conv.id);
+    Ok(())
+}
+
+pub fn load(id: &str) -> Result<Conversation> {
+    let path = conv_path(id)?;
+    let mut conv: Conversation = serde_json::from_str(std::fs::read_to_string(path)?.as_str())?;
+    conv.upgrade();
+    Ok(conv)
+}
+
+pub fn delete(id: &str) -> Result<()> {
+    std::fs::remove_file(conv_path(id)?)?;
+    Ok(())
+}
+
+pub fn rename(id: &str, title: &str) -> Result<()> {
+    let mut conv = load(id)?;
+    conv.title = title.to_string();
+    save(&conv)
+}
+
+// All the stored conversations, newest first
+pub fn load_all() -> Result<Vec<Conversation>> {
+    let mut res = vec![];
+    for entry in std::fs::read_dir(conv_dir()?)? {
+        let path = entry?.path();
+        if path.extension().map(|e| e != "json").unwrap_or(true) {
+            continue;
+        }
+        let conv = std::fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|s| serde_json::from_str::<Conversation>(s.as_str()).map_err(anyhow::Error::from));
+        match conv {
+            Ok(mut c) => {
+                c.upgrade();
+                res.push(c);
+            }
+            Err(e) => error!("Cannot read {}: {}", path.display(), e.to_string()),
+        }
+    }
+    res.sort_by(|a, b| b.updated.cmp(&a.updated));
+    Ok(res)
+}
+
+pub fn list() -> Result<Vec<ConvSummary>> {
+    Ok(load_all()?
+        .iter()
+        .map(ConvSummary::from)
+        .collect())
+}
@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock};
+use anyhow::Result;
+use gtk::prelude::*;
+use gtk::{gdk, glib, Align, Box, Button, Entry, Grid, Label, MenuButton, Orientation, Popover, Window};
+use regex::Regex;
+use crate::config::Template;
+use crate::context::Context;
+use crate::errors::AppError;
+use crate::store;
+use tracing::{debug, error, info};
+
+const TEMPLATES_FILE: &str = "templates.json";
+
+// {name}, letters, digits, '_' and spaces
+static PLACEHOLDER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\{([A-Za-z_][A-Za-z0-9_ ]*)\}").unwrap());
+
+fn saved_path() -> PathBuf {
+    store::data_dir().join(TEMPLATES_FILE)
+}
+
+// Templates saved from the menu
+fn load_saved() -> Vec<Template> {
+    match std::fs::read_to_string(saved_path()) {
+        Ok(s) => serde_json::from_str(s.as_str()).unwrap_or_else(|e| {
+            error!("Cannot read {}: {}", TEMPLATES_FILE, e.to_string());
+            vec![]
+        }),
+        Err(_) => vec![],
+    }
+}
+
+// A saved template with the same name is replaced
+fn save(t: Template) -> Result<()> {
+    let mut saved = load_saved();
+    saved.retain(|s| s.name != t.name);
+    saved.push(t);
+    std::fs::create_dir_all(store::data_dir())?;
+    std::fs::write(saved_path(), serde_json::to_string_pretty(&saved)?)?;
+    Ok(())
+}
+
+// The configured templates, then the saved ones
+fn all(ctx: &Context) -> Vec<Template> {
+    let mut res = ctx.conf.templates.clone();
+    res.extend(load_saved());
+    res
+}
+
+// Placeholder names in the order they appear, each once
+fn placeholders(text: &str) -> Vec<String> {
+    let mut res: Vec<String> = vec![];
+    for c in PLACEHOLDER.captures_iter(text) {
+        let name = c[1].to_string();
+        if !res.contains(&name) {
+            res.push(name);
+        }
+    }
+    res
+}
+
+// One pass over the template, braces in the values are not placeholders
+fn fill(text: &str, values: &HashMap<String, String>) -> String {
+    PLACEHOLDER.replace_all(text, |c: &regex::Captures| {
+        values.get(&c[1]).cloned().unwrap_or(c[0].to_string())
+    }).to_string()
+}
+
+// Small form with an entry for each name, None when cancelled
+async fn ask_values(parent: Option<Window>, title: &str, names: &[String]) -> Option<Vec<String>> {
+    let grid = Grid::builder()
+        .row_spacing(5)
+        .column_spacing(10)
+        .build();
+    let entries = names.iter()
+        .enumerate()
+        .map(|(i, n)| {
+            let label = Label::builder()
+                .label(n.as_str())
+                .halign(Align::Start)
+                .build();
+            let entry = Entry::builder()
+                .hexpand(true)
+                .activates_default(true)
+                .build();
+            grid.attach(&label, 0, i as i32, 1, 1);
+            grid.attach(&entry, 1, i as i32, 1, 1);
+            entry
+        })
+        .collect::<Vec<_>>();
+    let idc_ok = Button::builder()
+        .label("OK")
+        .build();
+    let idc_cancel = Button::builder()
+        .label("Cancel")
+        .margin_start(5)
+        .build();
+    let buttons = Box::builder()
+        .orientation(Orientation::Horizontal)
+        .halign(Align::End)
+        .margin_top(10)
+        .build();
+    buttons.append(&idc_ok);
+    buttons.append(&idc_cancel);
+    let root = Box::builder()
+        .orientation(Orientation::Vertical)
+        .margin_top(10)
+        .margin_start(10)
+        .margin_end(10)
+        .margin_bottom(10)
+        .build();
+    root.append(&grid);
+    root.append(&buttons);
+
+    let window = Window::builder()
+        .title(title)
+        .default_width(400)
+        .modal(true)
+        .child(&root)
+        .default_widget(&idc_ok)
+        .build();
+    window.set_transient_for(parent.as_ref());
+
+    let (sx, rx) = async_channel::unbounded::<bool>();
+    let s = sx.clone();
+    idc_ok.connect_clicked(move |_| {
+        let _ = s.try_send(true);
+    });
+    let s = sx.clone();
+    idc_cancel.connect_clicked(move |_| {
+        let _ = s.try_send(false);
+    });
+    window.connect_close_request(move |_| {
+        let _ = sx.try_send(false);
+        glib::Propagation::Proceed
+    });
+    window.present();
+    let ok = rx.recv().await.unwrap_or(false);
+    window.close();
+    ok.then(|| entries.iter().map(|e| e.text().to_string()).collect())
+}
+
+async fn read_clipboard(clipboard: gdk::Clipboard) -> String {
+    match clipboard.read_text_future().await {
+        Ok(t) => t.map(|t| t.to_string()).unwrap_or_default(),
+        Err(e) => {
+            error!("Cannot read the clipboard: {}", e.to_string());
+            String::new()
+        }
+    }
+}
+
+// Fills in the placeholders and inserts the prompt at the cursor.
+// Text selected in the prompt is replaced when the template uses {selection}
+async fn apply(ctx: Arc<Context>, parent: Option<Window>, t: Template) {
+    let names = placeholders(t.text.as_str());
+    let display = match gdk::Display::default() {
+        Some(d) => d,
+        None => return,
+    };
+    let buffer = ctx.text_buffer().await;
+    let mut values = HashMap::new();
+    let mut asked = vec![];
+    let mut replace = false;
+    for n in names {
+        let v = match n.as_str() {
+            "clipboard" => read_clipboard(display.clipboard()).await,
+            // Selected prompt text, otherwise the primary selection of the desktop
+            "selection" => match buffer.selection_bounds() {
+                Some((start, end)) => {
+                    replace = true;
+                    buffer.text(&start, &end, false).to_string()
+                }
+                None => read_clipboard(display.primary_clipboard()).await,
+            },
+            "transcript" => ctx.last_transcript.lock().await.clone().unwrap_or_default(),
+            _ => {
+                asked.push(n);
+                continue;
+            }
+        };
+        values.insert(n, v);
+    }
+    if !asked.is_empty() {
+        match ask_values(parent, t.name.as_str(), asked.as_slice()).await {
+            Some(v) => values.extend(asked.into_iter().zip(v)),
+            None => return,
+        }
+    }
+    debug!("Template {} with {:?}", t.name, values.keys().collect::<Vec<_>>());
+    if replace {
+        buffer.delete_selection(true, true);
+    }
+    buffer.insert_at_cursor(fill(t.text.as_str(), &values).as_str());
+}
+
+// Keeps the prompt for later under a name asked for
+async fn save_prompt(ctx: Arc<Context>, parent: Option<Window>) {
+    let buffer = ctx.text_buffer().await;
+    let text = crate::get_text!(buffer).to_string();
+    if text.trim().is_empty() {
+        return;
+    }
+    let name = match ask_values(parent, "Save as template", &[String::from("name")]).await {
+        Some(v) => v.into_iter().next().unwrap_or_default(),
+        None => return,
+    };
+    if name.trim().is_empty() {
+        return;
+    }
+    match save(Template { name: name.trim().to_string(), text, shortcut: None }) {
+        Ok(_) => info!("Saved template {}", name.trim()),
+        Err(e) => ctx.notifier.report(AppError::Storage(format!("Cannot save the template: {:#}", e))),
+    }
+}
+
+// Templates menu next to the prompt, the list is rebuilt every time it opens
+pub fn templates_button(ctx: Arc<Context>) -> MenuButton {
+    let list = Box::builder()
+        .orientation(Orientation::Vertical)
+        .build();
+    let popover = Popover::builder()
+        .child(&list)
+        .build();
+    popover.connect_show(move |p| {
+        while let Some(c) = list.first_child() {
+            list.remove(&c);
+        }
+        for t in all(&ctx) {
+            let item = Button::builder()
+                .label(t.name.as_str())
+                .tooltip_text(t.text.as_str())
+                .has_frame(false)
+                .build();
+            let st = ctx.clone();
+            let p = p.clone();
+            item.connect_clicked(move |b| {
+                p.popdown();
+                let parent = b.root().and_downcast::<Window>();
+                glib::spawn_future_local(apply(st.clone(), parent, t.clone()));
+            });
+            list.append(&item);
+        }
+        list.append(&gtk::Separator::new(Orientation::Horizontal));
+        let idc_save = Button::builder()
+            .label("Save prompt as template")
+            .has_frame(false)
+            .build();
+        let st = ctx.clone();
+        let p = p.clone();
+        idc_save.connect_clicked(move |b| {
+            p.popdown();
+            let parent = b.root().and_downcast::<Window>();
+            glib::spawn_future_local(save_prompt(st.clone(), parent));
+        });
+        list.append(&idc_save);
+    });
+
+    MenuButton::builder()
+        .label("Templates")
+        .tooltip_text("Insert a prompt template (Ctrl+T)")
+        .popover(&popover)
+        .margin_start(5)
+        .build()
+}
+
+// Ctrl+T opens the menu, templates with a shortcut are inserted directly
+pub fn connect_shortcuts(ctx: Arc<Context>, window: &Window, menu: &MenuButton) {
+    let controller = gtk::ShortcutController::new();
+    controller.set_scope(gtk::ShortcutScope::Global);
+    let m = menu.clone();
+    controller.add_shortcut(gtk::Shortcut::new(
+        gtk::ShortcutTrigger::parse_string("<Control>t"),
+        Some(gtk::CallbackAction::new(move |_, _| {
+            m.popup();
+            glib::Propagation::Stop
+        }))));
+    for t in ctx.conf.templates.iter() {
+        let trigger = match t.shortcut.as_deref() {
+            Some(s) => match gtk::ShortcutTrigger::parse_string(s) {
+                Some(trigger) => trigger,
+                None => {
+                    ctx.notifier.report(AppError::Config(format!("Invalid shortcut {} of template {}", s, t.name)));
+                    continue;
+                }
+            },
+            None => continue,
+        };
+        let st = ctx.clone();
+        let t = t.clone();
+        controller.add_shortcut(gtk::Shortcut::new(
+            Some(trigger),
+            Some(gtk::CallbackAction::new(move |w, _| {
+                let parent = w.root().and_downcast::<Window>();
+                glib::spawn_future_local(apply(st.clone(), parent, t.clone()));
+                glib::Propagation::Stop
+            }))));
+    }
+    window.add_controller(controller);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_in_order() {
+        assert_eq!(placeholders("{b} {a} {b} {not-one} {}"), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn values_are_not_filled_again() {
+        let values = HashMap::from([
+            (String::from("clipboard"), String::from("fn {selection}() {}")),
+            (String::from("selection"), String::from("x")),
+        ]);
+        assert_eq!(fill("Explain {clipboard} and {selection} {other}", &values),
+            "Explain fn {selection}() {} and x {other}");
+    }
+}
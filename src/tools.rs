@@ -0,0 +1,395 @@
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{Result, anyhow};
+use gtk::prelude::*;
+use gtk::glib;
+use ollama_rs::generation::tools::{ToolFunctionInfo, ToolInfo, ToolType};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use crate::context::Context;
+use crate::errors::AppError;
+use crate::mcp::McpClient;
+use crate::search::{self, SearchFilter};
+use crate::{calc, documents};
+use tracing::{debug, error, info};
+
+// Longest file content returned by read_file
+const MAX_READ: usize = 32 * 1024;
+const SEARCH_HITS: usize = 5;
+
+crate::make_enum!(Builtin, [Calculator, DateTime, ReadFile, SearchChats, Shell]);
+
+// Call made by the model, kept with the answer so the history can be replayed
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ToolUse {
+    pub id: String,
+    pub name: String,
+    pub arguments: Value,
+    pub result: String,
+    // Request round of the answer the call was made in
+    pub round: usize,
+}
+
+#[derive(Clone)]
+pub enum ToolKind {
+    Builtin(Builtin),
+    Mcp { client: Arc<McpClient>, tool: String, read_only: bool },
+    McpResource(Arc<McpClient>),
+}
+
+// Tool offered to the model, `parameters` is a JSON schema
+#[derive(Clone)]
+pub struct ToolSpec {
+    pub name: String,
+    pub description: String,
+    pub parameters: Value,
+    pub kind: ToolKind,
+}
+
+fn spec(b: Builtin) -> ToolSpec {
+    let (name, description, parameters) = match b {
+        Builtin::Calculator => ("calculator",
+            "Evaluates an arithmetic expression with + - * / % ^, parentheses, pi, e, sqrt, ln, log, sin, cos, tan, abs, round",
+            json!({
+                "type": "object",
+                "properties": { "expression": { "type": "string", "description": "Expression, e.g. (2 + 3) * sqrt(16)" } },
+                "required": ["expression"],
+            })),
+        Builtin::DateTime => ("current_datetime",
+            "Returns the current local date, time, weekday and time zone",
+            json!({ "type": "object", "properties": {} })),
+        Builtin::ReadFile => ("read_file",
+            "Reads a text, Markdown, PDF or DOCX file from the user's allowed folders",
+            json!({
+                "type": "object",
+                "properties": { "path": { "type": "string", "description": "Absolute path or a path relative to an allowed folder" } },
+                "required": ["path"],
+            })),
+        Builtin::SearchChats => ("search_conversations",
+            "Searches the user's past conversations and returns the best matching messages",
+            json!({
+                "type": "object",
+                "properties": { "query": { "type": "string", "description": "Words to look for" } },
+                "required": ["query"],
+            })),
+        Builtin::Shell => ("run_shell_command",
+            "Runs a command with sh -c on the user's computer and returns its output and exit status. The user has to allow every command",
+            json!({
+                "type": "object",
+                "properties": { "command": { "type": "string", "description": "Command line, e.g. ls -l ~/Documents" } },
+                "required": ["command"],
+            })),
+    };
+    ToolSpec {
+        name: name.to_string(),
+        description: description.to_string(),
+        parameters,
+        kind: ToolKind::Builtin(b),
+    }
+}
+
+// Function names allow only letters, digits, _ and -
+fn function_name(server: &str, name: &str) -> String {
+    format!("{}__{}", server, name).chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+        .take(64)
+        .collect()
+}
+
+fn mcp_specs(client: &Arc<McpClient>) -> Vec<ToolSpec> {
+    let mut res = client.tools.iter()
+        .map(|t| ToolSpec {
+            name: function_name(client.name.as_str(), t.name.as_str()),
+            description: t.description.clone(),
+            parameters: if t.input_schema.is_object() { t.input_schema.clone() } else { json!({ "type": "object", "properties": {} }) },
+            kind: ToolKind::Mcp { client: client.clone(), tool: t.name.clone(), read_only: t.read_only },
+        })
+        .collect::<Vec<_>>();
+    // Resources are read through one tool per server
+    if !client.resources.is_empty() {
+        let list = client.resources.iter()
+            .map(|r| format!("{} ({}) {}", r.uri, r.name, r.description).trim_end().to_string())
+            .collect::<Vec<_>>()
+            .join("; ");
+        res.push(ToolSpec {
+            name: function_name(client.name.as_str(), "read_resource"),
+            description: format!("Reads a resource of {}: {}", client.name, list),
+            parameters: json!({
+                "type": "object",
+                "properties": { "uri": { "type": "string", "description": "Resource URI" } },
+                "required": ["uri"],
+            }),
+            kind: ToolKind::McpResource(client.clone()),
+        });
+    }
+    res
+}
+
+// MCP servers of the persona, started on first use
+async fn mcp_clients(ctx: &Context) -> Vec<Arc<McpClient>> {
+    let persona = ctx.persona().await;
+    let mut running = ctx.mcp.lock().await;
+    let mut res = vec![];
+    for s in ctx.conf.mcp.iter() {
+        let used = persona.as_ref().map(|p| p.uses_mcp(s)).unwrap_or(s.enabled);
+        if !used {
+            continue;
+        }
+        if !running.contains_key(&s.name) {
+            // A server which failed to start is not tried again
+            let client = match McpClient::start(s).await {
+                Ok(c) => Some(Arc::new(c)),
+                Err(e) => {
+                    ctx.notifier.report(AppError::Tool(format!("Cannot start MCP server {}: {:#}", s.name, e)));
+                    None
+                }
+            };
+            running.insert(s.name.clone(), client);
+        }
+        if let Some(Some(c)) = running.get(&s.name) {
+            res.push(c.clone());
+        }
+    }
+    res
+}
+
+// Tools available with the current settings
+pub async fn registry(ctx: &Context) -> Vec<ToolSpec> {
+    if !*ctx.use_tools.lock().await {
+        return vec![];
+    }
+    let mut res = Builtin::ALL.iter()
+        .filter(|b| match b {
+            Builtin::ReadFile => !ctx.conf.tools.read_folders.is_empty(),
+            Builtin::Shell => ctx.conf.tools.shell,
+            _ => true,
+        })
+        .map(|b| spec(*b))
+        .collect::<Vec<_>>();
+    for c in mcp_clients(ctx).await.iter() {
+        res.extend(mcp_specs(c));
+    }
+    res
+}
+
+// Asks before a tool changes something, false when the user refused
+pub async fn confirm(ctx: &Context, message: &str, detail: &str) -> bool {
+    let parent = ctx.ui.lock().await.chat.widget().root().and_downcast::<gtk::Window>();
+    let dialog = gtk::AlertDialog::builder()
+        .message(message)
+        .detail(detail)
+        .buttons(["Deny", "Allow"])
+        .cancel_button(0)
+        .default_button(0)
+        .build();
+    dialog.choose_future(parent.as_ref()).await.map(|i| i == 1).unwrap_or(false)
+}
+
+pub fn openai_tools(specs: &[ToolSpec]) -> Value {
+    Value::Array(specs.iter()
+        .map(|s| json!({
+            "type": "function",
+            "function": {
+                "name": s.name,
+                "description": s.description,
+                "parameters": s.parameters,
+            },
+        }))
+        .collect())
+}
+
+pub fn ollama_tools(specs: &[ToolSpec]) -> Vec<ToolInfo> {
+    specs.iter()
+        .filter_map(|s| Some(ToolInfo {
+            tool_type: ToolType::Function,
+            function: ToolFunctionInfo {
+                name: s.name.clone(),
+                description: s.description.clone(),
+                parameters: serde_json::from_value(s.parameters.clone()).ok()?,
+            },
+        }))
+        .collect()
+}
+
+fn arg<'a>(args: &'a Value, name: &str) -> Result<&'a str> {
+    args[name].as_str().ok_or(anyhow!("Missing argument: {}", name))
+}
+
+fn datetime() -> Result<String> {
+    let now = glib::DateTime::now_local()?;
+    Ok(now.format("%Y-%m-%d %H:%M:%S %Z, %A")?.to_string())
+}
+
+// Only files inside the allowed folders, links are resolved first
+fn read_file(folders: &[String], path: &str) -> Result<String> {
+    let roots = folders.iter()
+        .filter_map(|f| std::fs::canonicalize(f).ok())
+        .collect::<Vec<_>>();
+    let p = PathBuf::from(path);
+    let candidates = if p.is_absolute() {
+        vec![p]
+    } else {
+        roots.iter().map(|r| r.join(&p)).collect()
+    };
+    let file = candidates.iter()
+        .filter_map(|c| std::fs::canonicalize(c).ok())
+        .find(|c| roots.iter().any(|r| c.starts_with(r)))
+        .ok_or(anyhow!("{} is not in an allowed folder", path))?;
+    let text = documents::extract(file.as_path())?;
+    Ok(match text.char_indices().nth(MAX_READ) {
+        Some((i, _)) => format!("{}\n[truncated]", &text[..i]),
+        None => text,
+    })
+}
+
+fn search_chats(query: &str) -> Result<String> {
+    let tags = Regex::new(r"<[^>]+>")?;
+    let hits = search::search(query, &SearchFilter::default())?;
+    if hits.is_empty() {
+        return Ok(String::from("No matching messages"));
+    }
+    Ok(hits.iter()
+        .take(SEARCH_HITS)
+        .map(|h| format!("{} ({}): {}", h.conv_title, crate::helper::format_time(h.time),
+            documents::unescape(tags.replace_all(h.snippet.as_str(), "").as_ref())))
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+// Process group of a shell command. `kill_on_drop` only reaches `sh`, the group
+// takes what it started too, also when the answer is cancelled
+struct Group(Option<u32>);
+
+impl Group {
+    fn kill(&self) {
+        #[cfg(unix)]
+        if let Some(pid) = self.0 {
+            // Fails only when the group is gone already
+            unsafe {
+                libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+impl Drop for Group {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+// Output of the command is streamed into the tool row of the answer `id`,
+// stderr goes into the same pipe to keep the order
+async fn run_shell(ctx: &Context, id: u64, call_id: &str, command: &str) -> Result<String> {
+    info!("Running {}", command);
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(format!("exec 2>&1\n{}", command))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true);
+    #[cfg(unix)]
+    cmd.process_group(0);
+    let mut child = cmd.spawn()?;
+    let group = Group(child.id());
+    let mut reader = BufReader::new(child.stdout.take().ok_or(anyhow!("No stdout"))?);
+    let timeout = Duration::from_secs(ctx.conf.tools.shell_timeout);
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut output = String::new();
+    let mut line = vec![];
+    let mut timed_out = false;
+    loop {
+        line.clear();
+        match tokio::time::timeout_at(deadline, reader.read_until(b'\n', &mut line)).await {
+            Ok(Ok(0)) => break,
+            Ok(Ok(_)) => {
+                let text = String::from_utf8_lossy(line.as_slice());
+                ctx.tool_output(id, call_id, text.as_ref()).await;
+                output.push_str(text.as_ref());
+            }
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => {
+                timed_out = true;
+                break;
+            }
+        }
+    }
+    let status = match tokio::time::timeout_at(deadline, child.wait()).await {
+        Ok(s) if !timed_out => match s?.code() {
+            Some(c) => format!("Exit status: {}", c),
+            None => String::from("Killed by a signal"),
+        },
+        _ => {
+            group.kill();
+            child.wait().await?;
+            format!("Killed after {} seconds", timeout.as_secs())
+        }
+    };
+    ctx.tool_output(id, call_id, format!("[{}]", status).as_str()).await;
+    // The end of the output has the errors, the start is dropped
+    if let Some((i, _)) = output.char_indices().rev().nth(MAX_READ) {
+        output = format!("[truncated]\n{}", &output[i..]);
+    }
+    Ok(format!("{}\n{}", status, output.trim_end()))
+}
+
+async fn call_builtin(ctx: &Context, id: u64, t: &ToolUse, b: Builtin) -> Result<String> {
+    let args = &t.arguments;
+    match b {
+        Builtin::Calculator => Ok(calc::eval(arg(args, "expression")?)?.to_string()),
+        Builtin::DateTime => datetime(),
+        Builtin::ReadFile => {
+            let folders = ctx.conf.tools.read_folders.clone();
+            let path = arg(args, "path")?.to_string();
+            tokio::task::spawn_blocking(move || read_file(folders.as_slice(), path.as_str())).await?
+        }
+        Builtin::SearchChats => {
+            let query = arg(args, "query")?.to_string();
+            tokio::task::spawn_blocking(move || search_chats(query.as_str())).await?
+        }
+        Builtin::Shell => {
+            let command = arg(args, "command")?;
+            let cwd = std::env::current_dir().map(|d| d.display().to_string()).unwrap_or_default();
+            let detail = format!("{}\n\nin {}", command, cwd);
+            if !confirm(ctx, "Run this shell command?", detail.as_str()).await {
+                info!("Command refused: {}", command);
+                return Ok(String::from("The user refused to run this command."));
+            }
+            run_shell(ctx, id, t.id.as_str(), command).await
+        }
+    }
+}
+
+// Runs the call made in the answer `id`, errors go back to the model as the result
+pub async fn call(ctx: &Context, specs: &[ToolSpec], id: u64, t: &ToolUse) -> String {
+    let (name, args) = (t.name.as_str(), &t.arguments);
+    debug!("Tool call {} {}", name, args);
+    let res = match specs.iter().find(|s| s.name == name).map(|s| &s.kind) {
+        Some(ToolKind::Builtin(b)) => call_builtin(ctx, id, t, *b).await,
+        Some(ToolKind::Mcp { client, tool, read_only }) => {
+            let detail = serde_json::to_string_pretty(args).unwrap_or_default();
+            if !*read_only && !confirm(ctx, format!("Allow {} to run {}?", client.name, tool).as_str(), detail.as_str()).await {
+                info!("Tool call {} denied", name);
+                return String::from("The user denied this tool call.");
+            }
+            client.call_tool(tool.as_str(), args).await
+        }
+        Some(ToolKind::McpResource(client)) => match arg(args, "uri") {
+            Ok(uri) => client.read_resource(uri).await,
+            Err(e) => Err(e),
+        },
+        None => Err(anyhow!("Unknown tool: {}", name)),
+    };
+    match res {
+        Ok(r) => r,
+        Err(e) => {
+            error!("Tool {} failed: {}", name, e.to_string());
+            format!("Error: {}", e)
+        }
+    }
+}
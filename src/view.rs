@@ -0,0 +1,342 @@
+use gtk::prelude::*;
+use gtk::{Align, Box, Button, Label, MenuButton, Orientation, Popover, ScrolledWindow, TextBuffer, TextView};
+use async_channel::Sender;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use crate::conversation::{Message, Role};
+use crate::helper::{convert_text, format_time};
+use crate::tools::ToolUse;
+use crate::AiChat;
+use tracing::error;
+
+// Actions triggered from the buttons under each chat bubble
+#[derive(Clone, Debug, Copy, PartialEq)]
+pub enum MsgAction {
+    Copy(u64),
+    Speak(u64),
+    Edit(u64),
+    Regenerate(u64),
+    // Regenerate with another chat and its default model
+    RegenerateWith(u64, AiChat),
+    // Previous and next variant of the message
+    Prev(u64),
+    Next(u64),
+    Delete(u64),
+}
+
+struct Bubble {
+    root: Box,
+    header: Label,
+    buffer: TextBuffer,
+    sources: Label,
+    tools: Box,
+    // Call id with the row and its result label
+    tool_rows: Vec<(String, gtk::Expander, Label)>,
+}
+
+pub struct ChatView {
+    list: Box,
+    scroll: ScrolledWindow,
+    viewport: gtk::Viewport,
+    follow: Rc<Cell<bool>>,
+    bubbles: HashMap<u64, Bubble>,
+    actions: Sender<MsgAction>,
+    // Configured chats offered for regenerating
+    providers: Vec<AiChat>,
+}
+
+macro_rules! action_button {
+    ($icon:expr, $tip:expr, $sx:ident, $action:expr) => {
+        {
+            let b = Button::builder()
+                .icon_name($icon)
+                .tooltip_text($tip)
+                .has_frame(false)
+                .build();
+            let sx = $sx.clone();
+            b.connect_clicked(move |_| {
+                if let Err(e) = sx.try_send($action) {
+                    error!("Error sending action: {}", e.to_string());
+                }
+            });
+            b
+        }
+    };
+}
+
+impl ChatView {
+    pub fn new(actions: Sender<MsgAction>, providers: Vec<AiChat>) -> Self {
+        let list = Box::builder()
+            .orientation(Orientation::Vertical)
+            .spacing(8)
+            .margin_top(10)
+            .margin_bottom(10)
+            .build();
+
+        let viewport = gtk::Viewport::builder()
+            .child(&list)
+            .build();
+        let scroll = ScrolledWindow::builder()
+            .child(&viewport)
+            .min_content_height(310)
+            .vexpand(true)
+            .build();
+
+        // Keep the newest message in view while the answer is streaming,
+        // unless the user scrolled up
+        let follow = Rc::new(Cell::new(true));
+        let f = follow.clone();
+        scroll.vadjustment().connect_upper_notify(move |a| {
+            if f.get() {
+                a.set_value(a.upper() - a.page_size());
+            }
+        });
+        let f = follow.clone();
+        scroll.vadjustment().connect_value_changed(move |a| {
+            f.set(a.value() + a.page_size() >= a.upper() - 30.0);
+        });
+
+        Self { list, scroll, viewport, follow, bubbles: HashMap::new(), actions, providers }
+    }
+
+    pub fn widget(&self) -> &ScrolledWindow {
+        &self.scroll
+    }
+
+    // Menu of the chats the answer can be regenerated with
+    fn regenerate_menu(&self, id: u64) -> MenuButton {
+        let b = Box::builder()
+            .orientation(Orientation::Vertical)
+            .build();
+        let popover = Popover::builder()
+            .child(&b)
+            .build();
+        for ai in self.providers.iter() {
+            let item = Button::builder()
+                .label(format!("With {}", ai).as_str())
+                .has_frame(false)
+                .build();
+            let sx = self.actions.clone();
+            let p = popover.clone();
+            let ai = *ai;
+            item.connect_clicked(move |_| {
+                p.popdown();
+                if let Err(e) = sx.try_send(MsgAction::RegenerateWith(id, ai)) {
+                    error!("Error sending action: {}", e.to_string());
+                }
+            });
+            b.append(&item);
+        }
+        MenuButton::builder()
+            .icon_name("pan-down-symbolic")
+            .tooltip_text("Regenerate with")
+            .has_frame(false)
+            .popover(&popover)
+            .build()
+    }
+
+    // Adds a bubble for the message, returns the buffer the answer can be streamed into.
+    // `variants` is the position of the message among its variants and their count
+    pub fn push(&mut self, msg: &Message, variants: (usize, usize)) -> TextBuffer {
+        let user = msg.role == Role::User;
+        let who = if user { "You" } else { msg.model.as_str() };
+
+        let header = Label::builder()
+            .halign(Align::Start)
+            .build();
+        header.set_markup(format!("<b>{}</b>  <small>{}</small>",
+            gtk::glib::markup_escape_text(who),
+            format_time(msg.time)).as_str());
+
+        let body = TextView::builder()
+            .editable(false)
+            .cursor_visible(false)
+            .wrap_mode(gtk::WrapMode::Word)
+            .build();
+        let buffer = body.buffer();
+
+        let sx = self.actions.clone();
+        let id = msg.id;
+        let actions = Box::builder()
+            .orientation(Orientation::Horizontal)
+            .halign(Align::End)
+            .build();
+        if variants.1 > 1 {
+            let prev = action_button!("go-previous-symbolic", "Previous variant", sx, MsgAction::Prev(id));
+            prev.set_sensitive(variants.0 > 1);
+            let next = action_button!("go-next-symbolic", "Next variant", sx, MsgAction::Next(id));
+            next.set_sensitive(variants.0 < variants.1);
+            actions.append(&prev);
+            actions.append(&Label::new(Some(format!("{}/{}", variants.0, variants.1).as_str())));
+            actions.append(&next);
+        }
+        actions.append(&action_button!("edit-copy-symbolic", "Copy", sx, MsgAction::Copy(id)));
+        actions.append(&action_button!("audio-speakers-symbolic", "Speak", sx, MsgAction::Speak(id)));
+        if user {
+            actions.append(&action_button!("document-edit-symbolic", "Edit and resend as a new branch", sx, MsgAction::Edit(id)));
+        }
+        actions.append(&action_button!("view-refresh-symbolic", "Regenerate", sx, MsgAction::Regenerate(id)));
+        if self.providers.len() > 1 {
+            actions.append(&self.regenerate_menu(id));
+        }
+        actions.append(&action_button!("user-trash-symbolic", "Delete", sx, MsgAction::Delete(id)));
+
+        let root = Box::builder()
+            .orientation(Orientation::Vertical)
+            .halign(if user { Align::End } else { Align::Fill })
+            .margin_start(if user { 80 } else { 5 })
+            .margin_end(if user { 5 } else { 40 })
+            .build();
+        root.append(&header);
+        if !msg.images.is_empty() {
+            let thumbs = Box::builder()
+                .orientation(Orientation::Horizontal)
+                .spacing(5)
+                .build();
+            for p in msg.images.iter() {
+                thumbs.append(&crate::attach::thumbnail(p));
+            }
+            root.append(&thumbs);
+        }
+        for d in msg.documents.iter() {
+            let note = if d.truncated { ", truncated" } else { "" };
+            let doc = Label::builder()
+                .halign(Align::Start)
+                .tooltip_text(d.path.display().to_string())
+                .build();
+            doc.set_markup(format!("<small>📄 {} (≈ {} tokens{})</small>",
+                gtk::glib::markup_escape_text(d.name.as_str()), d.tokens, note).as_str());
+            root.append(&doc);
+        }
+        let tools = Box::builder()
+            .orientation(Orientation::Vertical)
+            .build();
+        let mut tool_rows = vec![];
+        for t in msg.tools.iter() {
+            let (row, result) = Self::tool_row(t);
+            tools.append(&row);
+            tool_rows.push((t.id.clone(), row, result));
+        }
+        let sources = Label::builder()
+            .halign(Align::Start)
+            .wrap(true)
+            .build();
+        Self::render_sources(&sources, msg.sources.as_slice());
+        root.append(&tools);
+        root.append(&body);
+        root.append(&sources);
+        root.append(&actions);
+        self.list.append(&root);
+
+        if !msg.text.is_empty() {
+            Self::render(&buffer, msg.role, msg.text.as_str());
+        }
+
+        self.bubbles.insert(id, Bubble { root, header, buffer: buffer.clone(), sources, tools, tool_rows });
+        self.follow.set(true);
+        buffer
+    }
+
+    // Scrolls to the message and selects its text
+    pub fn scroll_to(&self, id: u64) {
+        if let Some(b) = self.bubbles.get(&id) {
+            self.follow.set(false);
+            let (start, end) = b.buffer.bounds();
+            b.buffer.select_range(&start, &end);
+            let viewport = self.viewport.clone();
+            let root = b.root.clone();
+            // Wait for the bubbles to get allocated
+            gtk::glib::idle_add_local_once(move || {
+                viewport.scroll_to(&root, None);
+            });
+        }
+    }
+
+    fn render(buffer: &TextBuffer, role: Role, text: &str) {
+        buffer.set_text("");
+        let mut end_iter = buffer.end_iter();
+        match role {
+            Role::User => buffer.insert(&mut end_iter, text),
+            Role::Assistant => buffer.insert_markup(&mut end_iter, convert_text(text).as_str()),
+        }
+    }
+
+    // Replaces the streamed plain text with the final markup
+    pub fn set_text(&self, id: u64, role: Role, text: &str) {
+        if let Some(b) = self.bubbles.get(&id) {
+            Self::render(&b.buffer, role, text);
+        }
+    }
+
+    fn render_sources(label: &Label, sources: &[String]) {
+        label.set_visible(!sources.is_empty());
+        label.set_markup(format!("<small>Sources: {}</small>",
+            gtk::glib::markup_escape_text(sources.join(", ").as_str())).as_str());
+    }
+
+    pub fn set_sources(&self, id: u64, sources: &[String]) {
+        if let Some(b) = self.bubbles.get(&id) {
+            Self::render_sources(&b.sources, sources);
+        }
+    }
+
+    // Collapsed call, the result is shown when expanded
+    fn tool_row(t: &ToolUse) -> (gtk::Expander, Label) {
+        let args = t.arguments.to_string();
+        let title = Label::new(None);
+        title.set_markup(format!("<small>🔧 {}({})</small>",
+            gtk::glib::markup_escape_text(t.name.as_str()),
+            gtk::glib::markup_escape_text(args.as_str())).as_str());
+        let result = Label::builder()
+            .label(t.result.as_str())
+            .halign(Align::Start)
+            .xalign(0.0)
+            .wrap(true)
+            .selectable(true)
+            .margin_start(20)
+            .build();
+        let row = gtk::Expander::builder()
+            .label_widget(&title)
+            .child(&result)
+            .build();
+        (row, result)
+    }
+
+    pub fn add_tool_use(&mut self, id: u64, t: &ToolUse) {
+        if let Some(b) = self.bubbles.get_mut(&id) {
+            let (row, result) = Self::tool_row(t);
+            b.tools.append(&row);
+            b.tool_rows.push((t.id.clone(), row, result));
+        }
+    }
+
+    fn result_label(&self, id: u64, call_id: &str) -> Option<(&gtk::Expander, &Label)> {
+        self.bubbles.get(&id)?
+            .tool_rows.iter()
+            .find(|(c, _, _)| c == call_id)
+            .map(|(_, row, result)| (row, result))
+    }
+
+    // Output of a running command, the row is opened to show it
+    pub fn append_tool_output(&self, id: u64, call_id: &str, text: &str) {
+        if let Some((row, result)) = self.result_label(id, call_id) {
+            row.set_expanded(true);
+            let mut s = result.text().to_string();
+            s.push_str(text);
+            result.set_text(s.as_str());
+        }
+    }
+
+    pub fn set_tool_result(&self, id: u64, call_id: &str, text: &str) {
+        if let Some((_, result)) = self.result_label(id, call_id) {
+            result.set_text(text);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for (_, b) in self.bubbles.drain() {
+            self.list.remove(&b.root);
+        }
+    }
+}